@@ -0,0 +1,249 @@
+//! Pixel-wise image arithmetic
+
+use num::{ NumCast, ToPrimitive };
+
+use buffer::{ ImageBuffer, GrayFloatImage };
+use image::error::{ ImageError, ImageResult };
+use image::other::GrayF32;
+use mask::{ PixelMask, is_masked };
+use traits::{ Pixel, Primitive, GenericImageView };
+
+
+/// How a result that does not fit the subpixel type is stored.
+///
+/// Only integer subpixels can overflow; float results are stored as computed.
+/// Non-integer results are rounded to the nearest integer first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Clamp to the smallest or largest representable value, so `10 - 20` is 0
+    Saturate,
+    /// Wrap around modulo the range of the type, so `10 - 20` is 65526 for u16
+    Wrap,
+}
+
+/// An element-wise operation between two pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+impl Operation {
+    #[inline(always)]
+    fn apply(&self, a: f64, b: f64) -> f64 {
+        match *self {
+            Operation::Add => a + b,
+            Operation::Subtract => a - b,
+            Operation::Multiply => a * b,
+            Operation::Divide => a / b,
+        }
+    }
+}
+
+
+/// Returns true if `T` can hold fractions
+fn is_float<T: Primitive>() -> bool {
+    let half: Option<T> = NumCast::from(0.5f64);
+    half.and_then(|h| h.to_f64()).map_or(false, |h| h != 0.0)
+}
+
+/// Stores `value` in `T` according to `policy`.
+///
+/// For integer types NaN becomes zero and infinities are clamped under either policy.
+/// Float types keep NaN and infinities; a value beyond the range of f32 becomes infinite.
+pub fn fit<T: Primitive>(value: f64, policy: OverflowPolicy) -> T {
+    if is_float::<T>() {
+        // Casts to a narrower float reject NaN, infinities and out of range values
+        return NumCast::from(value).or_else(|| NumCast::from(value as f32)).unwrap()
+    }
+    if value.is_nan() {
+        return NumCast::from(0).unwrap()
+    }
+
+    let min = T::min_value().to_f64().unwrap();
+    let max = T::max_value().to_f64().unwrap();
+    let value = value.round();
+    let value = match policy {
+        OverflowPolicy::Wrap if value.is_finite() && (value < min || value > max) => {
+            let span = max - min + 1.0;
+            ((value - min) % span + span) % span + min
+        },
+        _ => value.max(min).min(max),
+    };
+    NumCast::from(value).unwrap()
+}
+
+#[inline(always)]
+fn to_f64<T: ToPrimitive>(value: T) -> f64 {
+    value.to_f64().unwrap()
+}
+
+fn check_dimensions<I: GenericImageView, J: GenericImageView>(a: &I, b: &J) -> ImageResult<()> {
+    if a.dimensions() == b.dimensions() {
+        Ok(())
+    } else {
+        Err(ImageError::DimensionMismatch(a.dimensions(), b.dimensions()))
+    }
+}
+
+
+/// Returns an error if `mask` is given and differs in size from `image`
+fn check_mask<I: GenericImageView>(image: &I, mask: Option<&PixelMask>) -> ImageResult<()> {
+    match mask {
+        Some(mask) => mask.check_dimensions(image.dimensions()),
+        None => Ok(()),
+    }
+}
+
+
+/// Applies `op` to every pair of pixels of `a` and `b`, keeping the pixel type of the inputs.
+/// Pixels set in `mask` keep their value in `a`.
+/// Returns an error if the images or the mask differ in size.
+pub fn combine<I, J>(a: &I, b: &J, op: Operation, policy: OverflowPolicy, mask: Option<&PixelMask>)
+                     -> ImageResult<ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>>
+where I: GenericImageView, J: GenericImageView<Pixel=I::Pixel>, I::Pixel: 'static {
+    try!(check_dimensions(a, b));
+    try!(check_mask(a, mask));
+    let (width, height) = a.dimensions();
+    Ok(ImageBuffer::from_fn(width, height, |x, y| {
+        if is_masked(mask, x, y) {
+            return a.get_pixel(x, y)
+        }
+        a.get_pixel(x, y).map2(&b.get_pixel(x, y), |p, q| {
+            fit(op.apply(to_f64(p), to_f64(q)), policy)
+        })
+    }))
+}
+
+/// Applies `op` to every pair of pixels of `a` and `b`, promoting the result to f32
+/// so nothing is clipped. Pixels set in `mask` become NaN.
+/// Returns an error if the images or the mask differ in size.
+pub fn combine_to_float<I, J>(a: &I, b: &J, op: Operation, mask: Option<&PixelMask>) -> ImageResult<GrayFloatImage>
+where I: GenericImageView, J: GenericImageView {
+    try!(check_dimensions(a, b));
+    try!(check_mask(a, mask));
+    let (width, height) = a.dimensions();
+    Ok(ImageBuffer::from_fn(width, height, |x, y| {
+        if is_masked(mask, x, y) {
+            return GrayF32(::std::f32::NAN)
+        }
+        let p = to_f64(*a.get_pixel(x, y).value());
+        let q = to_f64(*b.get_pixel(x, y).value());
+        GrayF32(op.apply(p, q) as f32)
+    }))
+}
+
+/// Returns `a + b`
+pub fn add<I, J>(a: &I, b: &J, policy: OverflowPolicy)
+                 -> ImageResult<ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>>
+where I: GenericImageView, J: GenericImageView<Pixel=I::Pixel>, I::Pixel: 'static {
+    combine(a, b, Operation::Add, policy, None)
+}
+
+/// Returns `a - b`, e.g. a light frame minus a dark frame. Pixels set in `mask` keep their value in `a`.
+pub fn subtract<I, J>(a: &I, b: &J, policy: OverflowPolicy, mask: Option<&PixelMask>)
+                      -> ImageResult<ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>>
+where I: GenericImageView, J: GenericImageView<Pixel=I::Pixel>, I::Pixel: 'static {
+    combine(a, b, Operation::Subtract, policy, mask)
+}
+
+/// Returns `a * b`
+pub fn multiply<I, J>(a: &I, b: &J, policy: OverflowPolicy)
+                      -> ImageResult<ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>>
+where I: GenericImageView, J: GenericImageView<Pixel=I::Pixel>, I::Pixel: 'static {
+    combine(a, b, Operation::Multiply, policy, None)
+}
+
+/// Returns `a / b`. Integer division by zero saturates, `0 / 0` becomes 0.
+pub fn divide<I, J>(a: &I, b: &J, policy: OverflowPolicy)
+                    -> ImageResult<ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>>
+where I: GenericImageView, J: GenericImageView<Pixel=I::Pixel>, I::Pixel: 'static {
+    combine(a, b, Operation::Divide, policy, None)
+}
+
+
+/// Returns `image * factor`
+pub fn scale<I>(image: &I, factor: f64, policy: OverflowPolicy)
+                -> ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>
+where I: GenericImageView, I::Pixel: 'static {
+    let (width, height) = image.dimensions();
+    ImageBuffer::from_fn(width, height, |x, y| {
+        image.get_pixel(x, y).map(|p| fit(to_f64(p) * factor, policy))
+    })
+}
+
+/// Returns `image + delta`
+pub fn offset<I>(image: &I, delta: f64, policy: OverflowPolicy)
+                 -> ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>
+where I: GenericImageView, I::Pixel: 'static {
+    let (width, height) = image.dimensions();
+    ImageBuffer::from_fn(width, height, |x, y| {
+        image.get_pixel(x, y).map(|p| fit(to_f64(p) + delta, policy))
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{ subtract, divide, combine_to_float, scale, fit, Operation, OverflowPolicy };
+    use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+    use image::error::ImageError;
+    use image::other::{ GrayU16, GrayF32 };
+    use mask::PixelMask;
+
+    #[test]
+    fn dark_subtraction_does_not_underflow() {
+        let light: Gray16Image = ImageBuffer::from_pixel(2, 2, GrayU16(10));
+        let dark: Gray16Image = ImageBuffer::from_pixel(2, 2, GrayU16(20));
+        assert_eq!(subtract(&light, &dark, OverflowPolicy::Saturate, None).unwrap().get_pixel(0, 0).data, 0);
+        assert_eq!(subtract(&light, &dark, OverflowPolicy::Wrap, None).unwrap().get_pixel(1, 1).data, 65526);
+        assert_eq!(combine_to_float(&light, &dark, Operation::Subtract, None).unwrap().get_pixel(1, 0).data, -10.0);
+    }
+
+    #[test]
+    fn integer_division_and_scaling() {
+        let a: Gray16Image = ImageBuffer::from_pixel(1, 1, GrayU16(7));
+        let zero: Gray16Image = ImageBuffer::from_pixel(1, 1, GrayU16(0));
+        assert_eq!(divide(&a, &zero, OverflowPolicy::Wrap).unwrap().get_pixel(0, 0).data, 65535);
+        assert_eq!(divide(&zero, &zero, OverflowPolicy::Saturate).unwrap().get_pixel(0, 0).data, 0);
+        assert_eq!(scale(&a, 0.5, OverflowPolicy::Saturate).get_pixel(0, 0).data, 4);
+    }
+
+    #[test]
+    fn float_division_by_zero() {
+        let a: GrayFloatImage = ImageBuffer::from_fn(3, 1, |x, _| GrayF32(x as f32 - 1.0));
+        let zero: GrayFloatImage = ImageBuffer::new(3, 1);
+        let quotient = divide(&a, &zero, OverflowPolicy::Saturate).unwrap();
+        assert_eq!(quotient.get_pixel(0, 0).data, ::std::f32::NEG_INFINITY);
+        assert!(quotient.get_pixel(1, 0).data.is_nan());
+        assert_eq!(quotient.get_pixel(2, 0).data, ::std::f32::INFINITY);
+        assert_eq!(fit::<f32>(1e300, OverflowPolicy::Saturate), ::std::f32::INFINITY);
+    }
+
+    #[test]
+    fn size_mismatch_is_an_error() {
+        let a: Gray16Image = ImageBuffer::new(2, 2);
+        let b: Gray16Image = ImageBuffer::new(2, 3);
+        match subtract(&a, &b, OverflowPolicy::Saturate, None) {
+            Err(ImageError::DimensionMismatch((2, 2), (2, 3))) => {},
+            _ => panic!("expected a dimension mismatch"),
+        }
+    }
+
+    #[test]
+    fn masked_pixels_keep_the_first_value_or_become_nan() {
+        let light: Gray16Image = ImageBuffer::from_fn(2, 1, |x, _| GrayU16(100 + x as u16));
+        let dark: Gray16Image = ImageBuffer::from_pixel(2, 1, GrayU16(10));
+        let mask = PixelMask::from_fn(2, 1, |x, _| x == 1);
+        let difference = subtract(&light, &dark, OverflowPolicy::Saturate, Some(&mask)).unwrap();
+        assert_eq!(&*difference, &[90, 101]);
+        let difference = combine_to_float(&light, &dark, Operation::Subtract, Some(&mask)).unwrap();
+        assert_eq!(difference.get_pixel(0, 0).data, 90.0);
+        assert!(difference.get_pixel(1, 0).data.is_nan());
+
+        let small = PixelMask::from_fn(1, 1, |_, _| false);
+        assert!(subtract(&light, &dark, OverflowPolicy::Saturate, Some(&small)).is_err());
+    }
+}
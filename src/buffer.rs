@@ -3,23 +3,16 @@ use std::ops::{ Deref, DerefMut, Index, IndexMut };
 use std::marker::PhantomData;
 use std::iter::repeat;
 use std::path::Path;
-use std::io;
 use num::Zero;
 
-use std::io::{BufReader, BufWriter};
-use std::fs::File;
-use stream::{
-    ByteOrder,
-    EndianWriter,
-    SmartWriter,
-    // SmartReader
-};
+use format;
+use image::error::ImageResult;
+use correction::{ self, Correction };
+use mask::PixelMask;
 
-use traits::{ Pixel, Primitive, GenericImage }; // , ImageDecoder };
+use traits::{ Pixel, Primitive, GenericImage, GenericImageView }; // , ImageDecoder };
 //use color::{ Rgb, Rgba, Luma, LumaA, FromColor, ColorType };
 use image::other::{
-    PixelType,
-    Pixels,
     GrayU16,
     GrayF32
 }; 
@@ -141,9 +134,9 @@ pub struct ImageBuffer<P: Pixel, Container> {
 }
 
 
-impl<P, Container> GenericImage for ImageBuffer<P, Container>
+impl<P, Container> GenericImageView for ImageBuffer<P, Container>
 where P: Pixel + 'static,
-      Container: Deref<Target=[f32]> + DerefMut,
+      Container: Deref<Target=[P::Subpixel]>,
       P::Subpixel: Primitive + 'static {
 
     type Pixel = P;
@@ -157,8 +150,14 @@ where P: Pixel + 'static,
     }
 
     fn get_pixel(&self, x: u32, y: u32) -> P {
-        self.get_pixel(x, y)
+        *self.get_pixel(x, y)
     }
+}
+
+impl<P, Container> GenericImage for ImageBuffer<P, Container>
+where P: Pixel + 'static,
+      Container: Deref<Target=[P::Subpixel]> + DerefMut,
+      P::Subpixel: Primitive + 'static {
 
     fn get_pixel_mut(&mut self, x: u32, y: u32) -> &mut P {
         self.get_pixel_mut(x, y)
@@ -167,13 +166,6 @@ where P: Pixel + 'static,
     fn put_pixel(&mut self, x: u32, y: u32, pixel: P) {
         *self.get_pixel_mut(x, y) = pixel
     }
-
-
-    fn pixels(&self) -> Pixels<Self> {
-        let (width, height) = self.dimensions();
-        Pixels::new ( self, 0, 0, width, height )
-    }
-
 }
 
 
@@ -254,6 +246,17 @@ where P: Pixel + 'static,
             &self.data[index .. index + no_channels]
         )
     }
+
+    /// Returns the subpixels of row `y` as a slice
+    ///
+    /// # Panics
+    ///
+    /// Panics if `y` is out of bounds.
+    pub fn row(&self, y: u32) -> &[P::Subpixel] {
+        assert!(y < self.height, "row {} out of bounds", y);
+        let start = y as usize * self.width as usize;
+        &self.data[start .. start + self.width as usize]
+    }
 }
 
 impl<P, Container> ImageBuffer<P, Container>
@@ -295,6 +298,17 @@ where P: Pixel + 'static,
         )
     }
 
+    /// Returns the subpixels of row `y` as a mutable slice
+    ///
+    /// # Panics
+    ///
+    /// Panics if `y` is out of bounds.
+    pub fn row_mut(&mut self, y: u32) -> &mut [P::Subpixel] {
+        assert!(y < self.height, "row {} out of bounds", y);
+        let start = y as usize * self.width as usize;
+        &mut self.data[start .. start + self.width as usize]
+    }
+
     /// Puts a pixel at location `(x, y)`
     ///
     /// # Panics
@@ -308,53 +322,29 @@ where P: Pixel + 'static,
 
 impl<P, Container> ImageBuffer<P, Container>
 where P: Pixel + 'static,
-      Container: Deref<Target=[f32]> + DerefMut,
+      Container: Deref<Target=[P::Subpixel]> + DerefMut,
       P::Subpixel: Primitive + 'static {
-          
-          
-      
-// where P: Pixel + 'static , P::Subpixel: Primitive + 'static
-//where P: Pixel<Subpixel=f32> + 'static,
-     // Container: Deref<Target=[f32]> 
-     // {
+
+    /// Replaces the pixels set in `mask` by values interpolated from their valid neighbors,
+    /// see `correction::correct`. Returns the number of corrected pixels.
+    pub fn correct(&mut self, mask: &PixelMask, correction: Correction) -> ImageResult<usize> {
+        correction::correct(self, mask, correction)
+    }
+}
+
+
+impl<P, Container> ImageBuffer<P, Container>
+where P: Pixel + 'static,
+      Container: Deref<Target=[P::Subpixel]>,
+      P::Subpixel: Primitive + 'static {
+
    /// Saves the buffer to a file at the path specified.
    ///
-   /// The image format is derived from the file extension.
-   /// Currently only jpeg and png files are supported.
-   pub fn save<Q>(&self, output_path: Q) -> io::Result<()> where Q: AsRef<Path> {
-       let f = match File::create( output_path ) {
-            Ok( file ) => file,
-            Err( msg ) => { println!("{}", msg); panic!( "room" ); }
-        };
-    
-        let w = BufWriter::new( &f );
-        let mut wtr = SmartWriter::wrap(w, ByteOrder::LittleEndian);
-    
-        let pixel_type = <P as Pixel>::pixel_type();
-        let fmt1 = 0u32;
-        
-        let fmt2 = match pixel_type {
-            PixelType::Short16 => 0u32,
-            PixelType::Float32 => 2u32 
-        };
-        
-       wtr.write_u32(fmt1);
-       wtr.write_u32(fmt2);
-       wtr.write_u32( self.width );
-       wtr.write_u32( self.height );
-       
-       Ok( match pixel_type {
-            PixelType::Short16 => {
-                for (x,y,p ) in self.pixels() {
-                    try!(wtr.write_u16( p.value() ));
-                }
-            },
-            PixelType::Float32 => {
-                for (x,y,p) in self.pixels() {
-                    try!(wtr.write_f32( ( p.value() as f32 ) ));
-                }
-            }
-        })
+   /// The format is given by the extension of the path, IDP if it names none, see `format::save`.
+   /// The pixel type is taken from `P::pixel_type()`.
+   /// Subpixels are converted to u16 or f32; a value that does not fit is an error.
+   pub fn save<Q>(&self, output_path: Q) -> ImageResult<()> where Q: AsRef<Path> {
+       format::save( self, output_path )
    }
 }
 
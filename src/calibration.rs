@@ -0,0 +1,231 @@
+//! Dark (offset) and flat-field (gain) correction
+
+use std::path::Path;
+
+use num::ToPrimitive;
+
+use buffer::{ ImageBuffer, GrayFloatImage };
+use dynimage::DynamicIdpImage;
+use image::error::{ ImageError, ImageResult };
+use image::other::GrayF32;
+use mask::{ PixelMask, is_masked };
+use stack::ImageStack;
+use traits::{ Pixel, GenericImageView };
+
+
+/// Pixels whose relative gain falls outside these limits are reported as bad by default
+pub const DEFAULT_GAIN_LIMITS: (f64, f64) = (0.5, 2.0);
+
+
+/// Combines a stack of calibration frames into a master frame.
+///
+/// The per-pixel median is used so single outliers such as cosmic ray hits do not leak into the master.
+/// Returns an error if the stack is empty.
+pub fn master_frame(stack: &ImageStack) -> ImageResult<GrayFloatImage> {
+    if stack.is_empty() {
+        return Err(ImageError::FormatError("cannot build a master frame from an empty stack".to_string()))
+    }
+    Ok(stack.median())
+}
+
+
+/// A master dark and master flat, applied to raw frames as
+/// `(raw - dark) / (flat - dark) * mean(flat - dark)`.
+///
+/// Pixels set in the dead pixel mask, and pixels whose relative gain `(flat - dark) / mean(flat - dark)`
+/// is outside the gain limits, cannot be corrected and are NaN in calibrated frames.
+#[derive(Clone)]
+pub struct Calibration {
+    dark: GrayFloatImage,
+    flat: GrayFloatImage,
+    mask: Option<PixelMask>,
+    gain_limits: (f64, f64),
+    /// Mean of `flat - dark` over the pixels that are not dead
+    mean_response: f64,
+}
+
+impl Calibration {
+    /// Creates a calibration from a master dark and a master flat of the same size
+    pub fn new(dark: GrayFloatImage, flat: GrayFloatImage) -> ImageResult<Calibration> {
+        if dark.dimensions() != flat.dimensions() {
+            return Err(ImageError::DimensionMismatch(dark.dimensions(), flat.dimensions()))
+        }
+        let mut calibration = Calibration {
+            dark: dark,
+            flat: flat,
+            mask: None,
+            gain_limits: DEFAULT_GAIN_LIMITS,
+            mean_response: 0.0,
+        };
+        calibration.mean_response = calibration.compute_mean_response();
+        Ok(calibration)
+    }
+
+    /// Builds the master dark and master flat from stacks of dark and flat frames, see `master_frame`
+    pub fn from_stacks(darks: &ImageStack, flats: &ImageStack) -> ImageResult<Calibration> {
+        Calibration::new(try!(master_frame(darks)), try!(master_frame(flats)))
+    }
+
+    /// Reads a master dark and a master flat saved by `save`
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(dark: P, flat: Q) -> ImageResult<Calibration> {
+        let dark = try!(DynamicIdpImage::open(dark)).to_f32();
+        let flat = try!(DynamicIdpImage::open(flat)).to_f32();
+        Calibration::new(dark, flat)
+    }
+
+    /// Saves the master dark and the master flat as f32 images, in the format given by
+    /// the extension of each path, IDP if it names none. See `format::save`.
+    pub fn save<P: AsRef<Path>, Q: AsRef<Path>>(&self, dark: P, flat: Q) -> ImageResult<()> {
+        try!(self.dark.save(dark));
+        self.flat.save(flat)
+    }
+
+    /// Marks the pixels set in `mask` as dead. Returns an error if the mask differs in size.
+    pub fn with_mask(mut self, mask: PixelMask) -> ImageResult<Calibration> {
+        try!(mask.check_dimensions(self.dark.dimensions()));
+        self.mask = Some(mask);
+        self.mean_response = self.compute_mean_response();
+        Ok(self)
+    }
+
+    /// Sets the range of relative gains that are considered good, both limits included
+    pub fn with_gain_limits(mut self, low: f64, high: f64) -> Calibration {
+        self.gain_limits = (low, high);
+        self
+    }
+
+    /// The master dark
+    pub fn dark(&self) -> &GrayFloatImage {
+        &self.dark
+    }
+
+    /// The master flat
+    pub fn flat(&self) -> &GrayFloatImage {
+        &self.flat
+    }
+
+    /// The dead pixel mask, if any
+    pub fn mask(&self) -> Option<&PixelMask> {
+        self.mask.as_ref()
+    }
+
+    /// Mean of `flat - dark` over the pixels that are not dead; calibrated frames are scaled to it
+    pub fn mean_response(&self) -> f64 {
+        self.mean_response
+    }
+
+    fn compute_mean_response(&self) -> f64 {
+        let (width, height) = self.dark.dimensions();
+        let mut mean = 0.0;
+        let mut n = 0;
+        for y in 0..height {
+            for x in 0..width {
+                let response = self.response(x, y);
+                if is_masked(self.mask.as_ref(), x, y) || !response.is_finite() {
+                    continue
+                }
+                n += 1;
+                mean += (response - mean) / n as f64;
+            }
+        }
+        mean
+    }
+
+    #[inline(always)]
+    fn response(&self, x: u32, y: u32) -> f64 {
+        self.flat.get_pixel(x, y).data as f64 - self.dark.get_pixel(x, y).data as f64
+    }
+
+    #[inline(always)]
+    fn gain_at(&self, x: u32, y: u32) -> f64 {
+        self.response(x, y) / self.mean_response
+    }
+
+    #[inline(always)]
+    fn gain_ok(&self, gain: f64) -> bool {
+        gain >= self.gain_limits.0 && gain <= self.gain_limits.1
+    }
+
+    /// The relative gain `(flat - dark) / mean(flat - dark)` of every pixel
+    pub fn gain(&self) -> GrayFloatImage {
+        let (width, height) = self.dark.dimensions();
+        ImageBuffer::from_fn(width, height, |x, y| GrayF32(self.gain_at(x, y) as f32))
+    }
+
+    /// Returns a mask of the pixels that are not dead but whose gain is outside the gain limits
+    pub fn bad_gain(&self) -> PixelMask {
+        let (width, height) = self.dark.dimensions();
+        PixelMask::from_fn(width, height, |x, y| {
+            !is_masked(self.mask.as_ref(), x, y) && !self.gain_ok(self.gain_at(x, y))
+        })
+    }
+
+    /// Corrects `raw`, returning an f32 frame. Dead pixels and pixels with a bad gain are NaN.
+    /// Returns an error if `raw` differs in size from the masters.
+    pub fn apply<I: GenericImageView>(&self, raw: &I) -> ImageResult<GrayFloatImage> {
+        if raw.dimensions() != self.dark.dimensions() {
+            return Err(ImageError::DimensionMismatch(self.dark.dimensions(), raw.dimensions()))
+        }
+        let (width, height) = raw.dimensions();
+        Ok(ImageBuffer::from_fn(width, height, |x, y| {
+            let gain = self.gain_at(x, y);
+            if is_masked(self.mask.as_ref(), x, y) || !self.gain_ok(gain) {
+                return GrayF32(::std::f32::NAN)
+            }
+            let raw = raw.get_pixel(x, y).value().to_f64().unwrap();
+            let dark = self.dark.get_pixel(x, y).data as f64;
+            GrayF32(((raw - dark) / gain) as f32)
+        }))
+    }
+
+    /// Corrects a frame of either pixel type, see `apply`
+    pub fn apply_dynamic(&self, raw: &DynamicIdpImage) -> ImageResult<GrayFloatImage> {
+        match *raw {
+            DynamicIdpImage::U16(ref image) => self.apply(image),
+            DynamicIdpImage::F32(ref image) => self.apply(image),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+    use image::other::{ GrayU16, GrayF32 };
+    use mask::PixelMask;
+    use stack::ImageStack;
+    use super::Calibration;
+
+    #[test]
+    fn flat_field_correction() {
+        // Pixel 0 is twice as sensitive as pixel 1, pixel 2 is nearly blind
+        let dark: GrayFloatImage = ImageBuffer::from_pixel(4, 1, GrayF32(100.0));
+        let flat: GrayFloatImage = ImageBuffer::from_fn(4, 1, |x, _| GrayF32([300.0, 200.0, 101.0, 200.0][x as usize]));
+        let mask = PixelMask::from_fn(4, 1, |x, _| x == 3);
+        let calibration = Calibration::new(dark, flat).unwrap().with_mask(mask).unwrap();
+        assert!((calibration.mean_response() - 301.0 / 3.0).abs() < 1e-9);
+
+        let raw: Gray16Image = ImageBuffer::from_fn(4, 1, |x, _| GrayU16([500, 300, 150, 300][x as usize]));
+        let corrected = calibration.apply(&raw).unwrap();
+        let expected = 2.0 * calibration.mean_response();
+        assert!((corrected.get_pixel(0, 0).data as f64 - expected).abs() < 1e-3);
+        assert!((corrected.get_pixel(1, 0).data as f64 - expected).abs() < 1e-3);
+        assert!(corrected.get_pixel(2, 0).data.is_nan());
+        assert!(corrected.get_pixel(3, 0).data.is_nan());
+
+        let bad = calibration.bad_gain();
+        assert_eq!(bad.count(), 1);
+        assert!(bad.is_masked(2, 0));
+    }
+
+    #[test]
+    fn masters_from_stacks() {
+        let darks: Vec<Gray16Image> = [10u16, 12, 500].iter().map(|&v| ImageBuffer::from_pixel(2, 2, GrayU16(v))).collect();
+        let flats: Vec<Gray16Image> = [1000u16, 1010].iter().map(|&v| ImageBuffer::from_pixel(2, 2, GrayU16(v))).collect();
+        let calibration = Calibration::from_stacks(&ImageStack::from_images(&darks).unwrap(),
+                                                   &ImageStack::from_images(&flats).unwrap()).unwrap();
+        assert_eq!(calibration.dark().get_pixel(1, 1).data, 12.0);
+        assert_eq!(calibration.flat().get_pixel(0, 1).data, 1005.0);
+        assert!(Calibration::from_stacks(&ImageStack::new(2, 2), &ImageStack::new(2, 2)).is_err());
+    }
+}
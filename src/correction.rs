@@ -0,0 +1,227 @@
+//! Repair of masked pixels by interpolation from their valid neighbors
+
+use num::ToPrimitive;
+
+use arithmetic::{ fit, OverflowPolicy };
+use image::error::ImageResult;
+use mask::PixelMask;
+use stats::{ sort, percentile };
+use traits::{ Pixel, GenericImage, GenericImageView };
+
+
+/// The direction along which `Correction::Interpolate` reads valid pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Axis {
+    /// Along the row, which repairs dead columns
+    Horizontal,
+    /// Along the column, which repairs dead rows
+    Vertical,
+}
+
+/// How a masked pixel is given a new value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Correction {
+    /// The mean of the closest valid pixels, searching rings of growing size around the pixel
+    NeighborAverage,
+    /// The median of the valid pixels in the square of `2 * radius + 1` pixels around the pixel,
+    /// so 1 for 3x3 and 2 for 5x5
+    Median(u32),
+    /// Linear interpolation between the closest valid pixels on either side along `Axis`,
+    /// or a copy of the one valid side at the image border
+    Interpolate(Axis),
+    /// Fills each cluster of masked pixels from its border inwards, every pixel taking the mean
+    /// of its valid or already filled 8 neighbors, so large clusters get smooth content
+    Cluster,
+}
+
+
+/// Returns the value of the pixel at (x, y) widened to f64
+#[inline(always)]
+fn value_at<I: GenericImageView>(image: &I, x: u32, y: u32) -> f64 {
+    image.get_pixel(x, y).value().to_f64().unwrap()
+}
+
+/// Stores `value` in the pixel at (x, y), rounding and clamping it for integer pixels
+fn store<I: GenericImage>(image: &mut I, x: u32, y: u32, value: f64) {
+    let pixel = image.get_pixel_mut(x, y);
+    *pixel = pixel.map(|_| fit(value, OverflowPolicy::Saturate));
+}
+
+/// Returns the values of the valid pixels `(nx, ny)` with `|nx - x| <= radius` and `|ny - y| <= radius`
+/// for which `ring` is false or which lie on the edge of that square.
+/// A pixel is valid if it is not masked and its value is finite.
+fn valid_neighbors<I: GenericImageView>(image: &I, mask: &PixelMask, x: u32, y: u32, radius: u32, ring: bool) -> Vec<f64> {
+    let (width, height) = image.dimensions();
+    let mut values = Vec::new();
+    let (x0, x1) = (x.saturating_sub(radius), ::std::cmp::min(x + radius, width - 1));
+    let (y0, y1) = (y.saturating_sub(radius), ::std::cmp::min(y + radius, height - 1));
+    for ny in y0..y1 + 1 {
+        for nx in x0..x1 + 1 {
+            let on_ring = x.max(nx) - x.min(nx) == radius || y.max(ny) - y.min(ny) == radius;
+            if (!ring || on_ring) && !mask.is_masked(nx, ny) {
+                let value = value_at(image, nx, ny);
+                if value.is_finite() {
+                    values.push(value);
+                }
+            }
+        }
+    }
+    values
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().fold(0.0, |acc, v| acc + v) / values.len() as f64
+}
+
+/// Interpolates between the closest valid pixels before and after `i` in a line of `len` pixels
+fn interpolate<F, V>(i: u32, len: u32, is_valid: F, value: V) -> Option<f64>
+where F: Fn(u32) -> bool, V: Fn(u32) -> f64 {
+    let before = (0..i).rev().find(|&j| is_valid(j));
+    let after = (i + 1..len).find(|&j| is_valid(j));
+    match (before, after) {
+        (Some(a), Some(b)) => {
+            let t = (i - a) as f64 / (b - a) as f64;
+            Some(value(a) + (value(b) - value(a)) * t)
+        },
+        (Some(a), None) => Some(value(a)),
+        (None, Some(b)) => Some(value(b)),
+        (None, None) => None,
+    }
+}
+
+
+/// Replaces the pixels set in `mask` according to `correction`, leaving all other pixels untouched.
+///
+/// New values are computed in f64 and rounded and clamped for integer pixels.
+/// Unmasked pixels that are NaN or infinite are not used as neighbors.
+/// Pixels without a valid pixel to take a value from keep their value.
+/// Returns the number of corrected pixels, or an error if the mask differs in size from `image`.
+pub fn correct<I: GenericImage>(image: &mut I, mask: &PixelMask, correction: Correction) -> ImageResult<usize> {
+    try!(mask.check_dimensions(image.dimensions()));
+    let (width, height) = image.dimensions();
+    // Only unmasked pixels are read, except for Cluster, so the image can be written in place
+    let mut corrected = 0;
+    match correction {
+        Correction::NeighborAverage => for (x, y) in mask.masked_pixels() {
+            let max_radius = ::std::cmp::max(width, height);
+            let found = (1..max_radius).map(|r| valid_neighbors(image, mask, x, y, r, true))
+                                       .find(|values| !values.is_empty());
+            if let Some(values) = found {
+                store(image, x, y, mean(&values));
+                corrected += 1;
+            }
+        },
+        Correction::Median(radius) => for (x, y) in mask.masked_pixels() {
+            let mut values = valid_neighbors(image, mask, x, y, radius, false);
+            if !values.is_empty() {
+                sort(&mut values);
+                let median = percentile(&values, 50.0);
+                store(image, x, y, median);
+                corrected += 1;
+            }
+        },
+        Correction::Interpolate(axis) => for (x, y) in mask.masked_pixels() {
+            let value = match axis {
+                Axis::Horizontal => interpolate(x, width, |i| !mask.is_masked(i, y) && value_at(image, i, y).is_finite(),
+                                                |i| value_at(image, i, y)),
+                Axis::Vertical => interpolate(y, height, |i| !mask.is_masked(x, i) && value_at(image, x, i).is_finite(),
+                                              |i| value_at(image, x, i)),
+            };
+            if let Some(value) = value {
+                store(image, x, y, value);
+                corrected += 1;
+            }
+        },
+        Correction::Cluster => {
+            // Pixels that are neither valid nor filled yet
+            let mut pending = mask.clone();
+            for cluster in mask.clusters() {
+                let mut remaining = cluster;
+                loop {
+                    // Peel one layer: every remaining pixel that touches a valid or filled pixel
+                    let layer: Vec<(u32, u32, f64)> = remaining.iter().filter_map(|&(x, y)| {
+                        let values = valid_neighbors(image, &pending, x, y, 1, true);
+                        if values.is_empty() { None } else { Some((x, y, mean(&values))) }
+                    }).collect();
+                    if layer.is_empty() {
+                        break
+                    }
+                    for &(x, y, value) in &layer {
+                        store(image, x, y, value);
+                        pending.set(x, y, false);
+                    }
+                    corrected += layer.len();
+                    remaining.retain(|&(x, y)| pending.is_masked(x, y));
+                }
+            }
+        },
+    }
+    Ok(corrected)
+}
+
+
+#[cfg(test)]
+mod test {
+    use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+    use image::other::{ GrayU16, GrayF32 };
+    use mask::PixelMask;
+    use super::{ Axis, Correction };
+
+    /// A horizontal ramp, 10 per column
+    fn ramp() -> Gray16Image {
+        ImageBuffer::from_fn(6, 5, |x, _| GrayU16(x as u16 * 10))
+    }
+
+    #[test]
+    fn dead_column_is_interpolated_along_rows() {
+        let mut image = ramp();
+        let mask = PixelMask::from_fn(6, 5, |x, _| x == 2 || x == 3);
+        for y in 0..5 {
+            image.put_pixel(2, y, GrayU16(9999));
+            image.put_pixel(3, y, GrayU16(9999));
+        }
+        assert_eq!(image.correct(&mask, Correction::Interpolate(Axis::Horizontal)).unwrap(), 10);
+        assert_eq!(&*image, &*ramp());
+    }
+
+    #[test]
+    fn unmasked_pixels_are_untouched() {
+        let mask = PixelMask::from_fn(6, 5, |x, y| (x, y) == (3, 2) || (x, y) == (0, 0));
+        for &correction in &[Correction::NeighborAverage, Correction::Median(1), Correction::Median(2),
+                             Correction::Interpolate(Axis::Vertical), Correction::Cluster] {
+            let mut image = ramp();
+            image.put_pixel(3, 2, GrayU16(60000));
+            assert_eq!(image.correct(&mask, correction).unwrap(), 2);
+            assert_eq!(image.get_pixel(3, 2).data, 30);
+            for (x, y) in PixelMask::from_fn(6, 5, |_, _| true).masked_pixels() {
+                if !mask.is_masked(x, y) {
+                    assert_eq!(image.get_pixel(x, y).data, x as u16 * 10);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clusters_are_filled_from_the_border() {
+        let mut image: Gray16Image = ImageBuffer::from_pixel(7, 7, GrayU16(100));
+        let mask = PixelMask::from_fn(7, 7, |x, y| x >= 1 && x <= 5 && y >= 1 && y <= 5);
+        assert_eq!(mask.clusters().len(), 1);
+        assert_eq!(image.correct(&mask, Correction::Cluster).unwrap(), 25);
+        assert!(image.enumerate_pixels().all(|(_, _, p)| p.data == 100));
+    }
+
+    #[test]
+    fn nan_neighbors_are_skipped() {
+        // A NaN pixel left of the dead one at (2, 1), the other neighbors are 4
+        let mut image: GrayFloatImage = ImageBuffer::from_pixel(5, 3, GrayF32(4.0));
+        image.put_pixel(1, 1, GrayF32(::std::f32::NAN));
+        let mask = PixelMask::from_fn(5, 3, |x, y| (x, y) == (2, 1));
+        for &correction in &[Correction::NeighborAverage, Correction::Median(1),
+                             Correction::Interpolate(Axis::Horizontal), Correction::Cluster] {
+            let mut corrected = image.clone();
+            corrected.put_pixel(2, 1, GrayF32(-1.0));
+            assert_eq!(corrected.correct(&mask, correction).unwrap(), 1);
+            assert_eq!(corrected.get_pixel(2, 1).data, 4.0);
+        }
+    }
+}
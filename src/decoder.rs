@@ -2,7 +2,7 @@
 // use std::io;
 // use std::marker::PhantomData;
 // use std::result::Result;
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, SeekFrom};
 // use std::error::Error;
 // use byteorder;
 // use std::path::Path;
@@ -10,6 +10,7 @@ use std::io::{Read, Seek};
 
 use image::error::{
     ImageError,
+    HeaderError,
     ImageResult
 };
 
@@ -19,6 +20,8 @@ use image::other::{
     DecodingResult
 };
 
+use dynimage::DynamicIdpImage;
+
 use super::stream::{
     ByteOrder,
     EndianReader,
@@ -45,7 +48,22 @@ pub trait ImageDecoder: Sized {
 
 
 
+/// Size of the IDP header in bytes
+pub const HEADER_BYTES: u64 = 16;
+
+/// Returns the number of bytes a single pixel of `pixel_type` occupies on disk
+pub fn bytes_per_pixel(pixel_type: PixelType) -> u64 {
+    match pixel_type {
+        PixelType::Short16 => 2,
+        PixelType::Float32 => 4,
+    }
+}
+
 
+/// Decodes IDP streams.
+///
+/// A stream may hold several frames back to back, each with its own header.
+/// The decoder is always positioned on one frame, starting with the first.
 #[derive(Debug)]
 pub struct IDPDecoder<R> where R: Read + Seek {
     reader: SmartReader<R>,
@@ -53,11 +71,19 @@ pub struct IDPDecoder<R> where R: Read + Seek {
     width: u32,
     height: u32,
     pixel_type: PixelType,
+    /// Length of the stream in bytes
+    stream_len: u64,
+    /// Offsets of the frames whose position is known so far, starting with frame 0
+    frame_offsets: Vec<u64>,
+    /// Index of the current frame
+    frame: usize,
 }
 
 
 impl<R: Read + Seek> IDPDecoder<R> {  
     /// Create a new decoder that decodes from the stream ```r```
+    ///
+    /// The first frame starts at the current position of ```r```.
     pub fn new(r: R) -> ImageResult<IDPDecoder<R>> {
         IDPDecoder {
             reader: SmartReader::wrap(r, ByteOrder::LittleEndian),
@@ -65,35 +91,151 @@ impl<R: Read + Seek> IDPDecoder<R> {
             width: 0,
             height: 0,
             pixel_type: PixelType::Short16,
+            stream_len: 0,
+            frame_offsets: Vec::new(),
+            frame: 0,
         }.init()
     }
 
     fn read_header(&mut self) -> ImageResult<()> {
+        let offset = try!(self.reader.seek(SeekFrom::Current(0)));
+        if self.stream_len - offset < HEADER_BYTES {
+            return Err(ImageError::HeaderError(
+                HeaderError::Truncated { actual: self.stream_len - offset, offset: offset }
+            ))
+        }
         let fmt1 = try!(self.reader.read_u32() );
         let fmt2 = try!(self.reader.read_u32() );
-        self.pixel_type = match ( fmt1, fmt2 ) {
-            ( 0, 0 ) => PixelType::Short16,
-            ( 0, 2 ) => PixelType::Float32,
-            _ => panic!("Invalid IDP header found" )
+        if fmt1 != 0 {
+            return Err(ImageError::HeaderError(
+                HeaderError::NonzeroMagic { value: fmt1, offset: offset }
+            ))
+        }
+        self.pixel_type = match fmt2 {
+            0 => PixelType::Short16,
+            2 => PixelType::Float32,
+            code => return Err(ImageError::HeaderError(
+                HeaderError::UnsupportedPixelCode { code: code, offset: offset }
+            ))
         };
         self.width  = try!(self.reader.read_u32() );
         self.height = try!(self.reader.read_u32() );
-        
+
+        let pixel_bytes = (self.width as u64)
+            .checked_mul(self.height as u64)
+            .and_then(|n| n.checked_mul(bytes_per_pixel(self.pixel_type)))
+            .and_then(|n| n.checked_add(HEADER_BYTES));
+        let expected = match pixel_bytes {
+            Some(n) if self.width > 0 && self.height > 0 && n <= ::std::usize::MAX as u64 => n,
+            _ => return Err(ImageError::HeaderError(
+                HeaderError::InvalidDimensions { width: self.width, height: self.height, offset: offset }
+            ))
+        };
+
+        // Check the stream holds the whole frame before anything gets allocated for it.
+        // Bytes beyond the frame belong to the next one.
+        let actual = self.stream_len - offset;
+        if actual < expected {
+            return Err(ImageError::HeaderError(
+                HeaderError::LengthMismatch { expected: expected, actual: actual, offset: offset }
+            ))
+        }
+
         Ok(())
     }
 
+    /// Number of bytes the current frame occupies, header included
+    fn frame_len(&self) -> u64 {
+        HEADER_BYTES + self.width as u64 * self.height as u64 * bytes_per_pixel(self.pixel_type)
+    }
+
     /// Initializes the decoder.
-    pub fn init(self) -> ImageResult<IDPDecoder<R>> {
-        self.next_image()
+    pub fn init(mut self) -> ImageResult<IDPDecoder<R>> {
+        let start = try!(self.reader.seek(SeekFrom::Current(0)));
+        self.stream_len = try!(self.reader.seek(SeekFrom::End(0)));
+        self.frame_offsets = vec![start];
+        try!(self.seek_frame(0));
+        Ok(self)
+    }
+
+    /// Returns true if the stream holds another frame after the current one.
+    /// Trailing bytes too short for a header are ignored, like a partial frame of a raw file.
+    pub fn more_images(&self) -> bool {
+        self.stream_len - (self.frame_offsets[self.frame] + self.frame_len()) >= HEADER_BYTES
     }
 
     /// Reads in the next image.
     /// To determine whether there are more images call `IDPDecoder::more_images` instead.
+    /// Returns `ImageError::ImageEnd` if the current frame is the last one.
     pub fn next_image(mut self) -> ImageResult<IDPDecoder<R>> {
-        try!(self.read_header());
+        let next = self.frame + 1;
+        try!(self.seek_frame(next));
         Ok(self)
     }
 
+    /// Index of the current frame, starting at 0
+    pub fn current_frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Moves the decoder to frame `n`, reading the headers of all frames before it
+    /// that have not been visited yet.
+    ///
+    /// Returns `ImageError::ImageEnd` if the stream holds `n` frames or fewer,
+    /// in which case the decoder stays on the current frame.
+    pub fn seek_frame(&mut self, n: usize) -> ImageResult<()> {
+        let current = self.frame;
+        let mut k = ::std::cmp::min(n, self.frame_offsets.len() - 1);
+        loop {
+            let offset = self.frame_offsets[k];
+            try!(self.reader.seek(SeekFrom::Start(offset)));
+            if let Err(e) = self.read_header() {
+                if k != current {
+                    let back = self.frame_offsets[current];
+                    try!(self.reader.seek(SeekFrom::Start(back)));
+                    try!(self.read_header());
+                }
+                return Err(e)
+            }
+            self.frame = k;
+            if k == n {
+                return Ok(())
+            }
+            if !self.more_images() {
+                return self.seek_frame(current).and(Err(ImageError::ImageEnd))
+            }
+            if self.frame_offsets.len() == k + 1 {
+                let next = offset + self.frame_len();
+                self.frame_offsets.push(next);
+            }
+            k += 1;
+        }
+    }
+
+    /// Counts the frames in the stream. The decoder stays on the current frame.
+    pub fn frame_count(&mut self) -> ImageResult<usize> {
+        let current = self.frame;
+        let mut n = self.frame_offsets.len() - 1;
+        loop {
+            match self.seek_frame(n + 1) {
+                Ok(()) => n += 1,
+                Err(ImageError::ImageEnd) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        try!(self.seek_frame(current));
+        Ok(n + 1)
+    }
+
+    /// Returns an iterator over the current frame and all frames after it
+    pub fn frames(self) -> Frames<R> {
+        Frames {
+            decoder: self,
+            started: false,
+            finished: false,
+        }
+    }
+
     /// Decompresses the strip into the supplied buffer.
     /// Returns the number of bytes read.
     fn expand_strip<'a>(&mut self, decode_buffer: DecodingBuffer<'a> ) -> ImageResult<()> {
@@ -138,6 +280,8 @@ impl<R: Read + Seek> ImageDecoder for IDPDecoder<R> {
     }
 
     fn read_image(&mut self) -> ImageResult<DecodingResult> {
+        let data_start = self.frame_offsets[self.frame] + HEADER_BYTES;
+        try!(self.reader.seek(SeekFrom::Start(data_start)));
         let number_of_pixels =
               self.width  as usize
             * self.height as usize;
@@ -178,3 +322,152 @@ impl<R: Read + Seek> ImageDecoder for IDPDecoder<R> {
     
     }
 }
+
+
+/// Iterator over the frames of an IDP stream, see `IDPDecoder::frames`
+pub struct Frames<R> where R: Read + Seek {
+    decoder: IDPDecoder<R>,
+    started: bool,
+    finished: bool,
+}
+
+impl<R: Read + Seek> Iterator for Frames<R> {
+    type Item = ImageResult<DynamicIdpImage>;
+
+    fn next(&mut self) -> Option<ImageResult<DynamicIdpImage>> {
+        if self.finished {
+            return None
+        }
+        if self.started {
+            if !self.decoder.more_images() {
+                self.finished = true;
+                return None
+            }
+            let next = self.decoder.current_frame() + 1;
+            if let Err(e) = self.decoder.seek_frame(next) {
+                self.finished = true;
+                return Some(Err(e))
+            }
+        }
+        self.started = true;
+        let frame = DynamicIdpImage::from_decoder(&mut self.decoder);
+        if frame.is_err() {
+            self.finished = true;
+        }
+        Some(frame)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+    use encoder::{ IDPEncoder, ImageEncoder };
+    use image::error::{ ImageError, HeaderError };
+    use image::other::{ PixelType, GrayU16, GrayF32 };
+    use super::{ IDPDecoder, ImageDecoder };
+
+    /// A little endian header followed by `data_bytes` zero bytes
+    fn frame(magic: u32, code: u32, width: u32, height: u32, data_bytes: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for &word in &[magic, code, width, height] {
+            bytes.extend_from_slice(&[word as u8, (word >> 8) as u8, (word >> 16) as u8, (word >> 24) as u8]);
+        }
+        bytes.extend(::std::iter::repeat(0).take(data_bytes));
+        bytes
+    }
+
+    fn header_error(bytes: Vec<u8>) -> HeaderError {
+        match IDPDecoder::new(Cursor::new(bytes)) {
+            Err(ImageError::HeaderError(e)) => e,
+            Err(e) => panic!("expected a header error, got {}", e),
+            Ok(_) => panic!("expected a header error"),
+        }
+    }
+
+    #[test]
+    fn nonzero_magic() {
+        assert_eq!(header_error(frame(7, 0, 1, 1, 2)), HeaderError::NonzeroMagic { value: 7, offset: 0 });
+    }
+
+    #[test]
+    fn unsupported_pixel_code() {
+        assert_eq!(header_error(frame(0, 1, 1, 1, 2)), HeaderError::UnsupportedPixelCode { code: 1, offset: 0 });
+    }
+
+    #[test]
+    fn invalid_dimensions() {
+        assert_eq!(header_error(frame(0, 0, 0, 3, 0)), HeaderError::InvalidDimensions { width: 0, height: 3, offset: 0 });
+    }
+
+    #[test]
+    fn length_mismatch() {
+        assert_eq!(header_error(frame(0, 2, 2, 2, 15)), HeaderError::LengthMismatch { expected: 32, actual: 31, offset: 0 });
+    }
+
+    #[test]
+    fn truncated_header() {
+        let mut bytes = frame(0, 0, 2, 2, 0);
+        bytes.truncate(10);
+        assert_eq!(header_error(bytes), HeaderError::Truncated { actual: 10, offset: 0 });
+    }
+
+    /// Three frames back to back: 2x2 u16, 3x1 f32 and 2x2 u16
+    fn sequence() -> Vec<u8> {
+        let a: Gray16Image = ImageBuffer::from_pixel(2, 2, GrayU16(1));
+        let b: GrayFloatImage = ImageBuffer::from_pixel(3, 1, GrayF32(2.5));
+        let c: Gray16Image = ImageBuffer::from_pixel(2, 2, GrayU16(3));
+        let mut encoder = IDPEncoder::new(Cursor::new(Vec::new()));
+        encoder.encode_buffer(&a).unwrap();
+        encoder.encode_buffer(&b).unwrap();
+        encoder.encode_buffer(&c).unwrap();
+        encoder.into_inner().into_inner().into_inner()
+    }
+
+    #[test]
+    fn iterates_concatenated_frames() {
+        let decoder = IDPDecoder::new(Cursor::new(sequence())).unwrap();
+        let frames: Vec<_> = decoder.frames().map(|f| f.unwrap()).collect();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1].dimensions(), (3, 1));
+        assert_eq!(frames[1].to_f32().get_pixel(2, 0).data, 2.5);
+        assert_eq!(frames[2].to_u16().get_pixel(1, 1).data, 3);
+    }
+
+    #[test]
+    fn ignores_trailing_partial_header() {
+        let mut bytes = sequence();
+        bytes.extend_from_slice(&[0; 15]);
+        let mut decoder = IDPDecoder::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(decoder.frame_count().unwrap(), 3);
+        decoder.seek_frame(2).unwrap();
+        assert!(!decoder.more_images());
+        assert_eq!(IDPDecoder::new(Cursor::new(bytes)).unwrap().frames().map(|f| f.unwrap()).count(), 3);
+    }
+
+    #[test]
+    fn seeks_and_counts_frames() {
+        let mut decoder = IDPDecoder::new(Cursor::new(sequence())).unwrap();
+        assert!(decoder.more_images());
+        assert_eq!(decoder.frame_count().unwrap(), 3);
+        assert_eq!(decoder.current_frame(), 0);
+
+        decoder.seek_frame(2).unwrap();
+        assert!(!decoder.more_images());
+        match decoder.seek_frame(3) {
+            Err(ImageError::ImageEnd) => {},
+            _ => panic!("expected the end of the sequence"),
+        }
+        assert_eq!(decoder.current_frame(), 2);
+
+        let decoder = decoder.next_image();
+        assert!(decoder.is_err());
+
+        let mut decoder = IDPDecoder::new(Cursor::new(sequence())).unwrap().next_image().unwrap();
+        assert_eq!(decoder.pixel_type().unwrap(), PixelType::Float32);
+        decoder.seek_frame(0).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (2, 2));
+    }
+}
@@ -0,0 +1,294 @@
+//! Persistent defect maps.
+//!
+//! A defect map is stored in two parts that hold the same information:
+//!
+//! * an IDP image with u16 pixels holding the classification code of every pixel,
+//!   readable by any IDP tool and usable as a dead pixel mask,
+//! * a text list of the defects, one entry per line:
+//!
+//! ```text
+//! # idp defect list 1
+//! size <width> <height>
+//! row <y> <code>
+//! column <x> <code>
+//! pixel <x> <y> <code>
+//! cluster <code> <x>,<y> <x>,<y> ...
+//! ```
+//!
+//! A row or column entry adds its code to a whole line of pixels, a cluster entry to an
+//! 8-connected group of pixels. Codes are the bitwise or of `DefectKind::code`, and the code
+//! of a pixel is the bitwise or of all entries covering it.
+//! Empty lines and lines starting with `#` are ignored.
+
+use std::fs::File;
+use std::io::{ self, Read, Write, Seek, BufReader, BufWriter };
+use std::path::{ Path, PathBuf };
+
+use decoder::IDPDecoder;
+use defects::DefectMap;
+use dynimage::DynamicIdpImage;
+use encoder::{ IDPEncoder, ImageEncoder };
+use image::error::{ ImageError, ImageResult };
+use mask::PixelMask;
+use stream::{ ByteOrder, SmartReader, SmartWriter };
+
+
+/// First line of a defect list
+const LIST_MAGIC: &'static str = "# idp defect list 1";
+
+/// Largest number of pixels the size line of a defect list may give, so a corrupt list
+/// cannot make the reader allocate an arbitrarily large map
+pub const MAX_LIST_PIXELS: u64 = 1 << 28;
+
+
+/// Writes the classification codes of `map` as a u16 IDP image
+pub fn write_defect_image<W: Write + Seek>(w: W, map: &DefectMap) -> ImageResult<()> {
+    IDPEncoder::new(w).encode_buffer(&map.to_image())
+}
+
+/// Reads a defect map from a u16 IDP image of classification codes
+pub fn read_defect_image<R: Read + Seek>(r: R) -> ImageResult<DefectMap> {
+    let mut decoder = try!(IDPDecoder::new(r));
+    let image = try!(DynamicIdpImage::from_decoder(&mut decoder));
+    Ok(DefectMap::from_image(&image.to_u16()))
+}
+
+
+/// Writes the defects of `map` as a text list
+pub fn write_defect_list<W: Write + Seek>(w: W, map: &DefectMap) -> ImageResult<()> {
+    let mut w = SmartWriter::wrap(w, ByteOrder::LittleEndian);
+    let (width, height) = map.dimensions();
+    try!(writeln!(w, "{}", LIST_MAGIC));
+    try!(writeln!(w, "size {} {}", width, height));
+    if width == 0 || height == 0 {
+        try!(w.flush());
+        return Ok(())
+    }
+
+    // Codes shared by whole rows and then by whole columns of what the rows left are written
+    // once per line, the bits they cover are cleared from the codes left for single pixels and clusters
+    let mut rest: Vec<u16> = (0..height).flat_map(|y| (0..width).map(move |x| (x, y)))
+                                        .map(|(x, y)| map.code(x, y)).collect();
+    for y in 0..height {
+        let code = (0..width).fold(0xffff, |acc, x| acc & map.code(x, y));
+        if code != 0 {
+            try!(writeln!(w, "row {} {}", y, code));
+            for x in 0..width {
+                rest[y as usize * width as usize + x as usize] &= !code;
+            }
+        }
+    }
+    for x in 0..width {
+        let code = (0..height).fold(0xffff, |acc, y| acc & rest[y as usize * width as usize + x as usize]);
+        if code != 0 {
+            try!(writeln!(w, "column {} {}", x, code));
+            for y in 0..height {
+                rest[y as usize * width as usize + x as usize] &= !code;
+            }
+        }
+    }
+
+    // Group the remaining pixels by code, then into clusters
+    let rest_code = |x: u32, y: u32| rest[y as usize * width as usize + x as usize];
+    let mut codes: Vec<u16> = rest.iter().cloned().filter(|&c| c != 0).collect();
+    codes.sort();
+    codes.dedup();
+    let mut entries = Vec::new();
+    for &code in &codes {
+        let same = PixelMask::from_fn(width, height, |x, y| rest_code(x, y) == code);
+        for cluster in same.clusters() {
+            entries.push((code, cluster));
+        }
+    }
+    entries.sort_by(|a, b| ((a.1)[0].1, (a.1)[0].0).cmp(&((b.1)[0].1, (b.1)[0].0)));
+    for &(code, ref cluster) in &entries {
+        if cluster.len() == 1 {
+            try!(writeln!(w, "pixel {} {} {}", cluster[0].0, cluster[0].1, code));
+        } else {
+            let pixels: Vec<String> = cluster.iter().map(|&(x, y)| format!("{},{}", x, y)).collect();
+            try!(writeln!(w, "cluster {} {}", code, pixels.join(" ")));
+        }
+    }
+    try!(w.flush());
+    Ok(())
+}
+
+/// Reads a text list of defects
+pub fn read_defect_list<R: Read + Seek>(r: R) -> ImageResult<DefectMap> {
+    let mut text = String::new();
+    try!(SmartReader::wrap(r, ByteOrder::LittleEndian).read_to_string(&mut text));
+
+    let mut map: Option<DefectMap> = None;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let error = |msg: &str| ImageError::FormatError(format!("defect list line {}: {}", i + 1, msg));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields[0] == "size" {
+            if map.is_some() {
+                return Err(error("size given twice"))
+            }
+            let width = try!(parse_field(&fields, 1).ok_or(error("expected `size <width> <height>`")));
+            let height = try!(parse_field(&fields, 2).ok_or(error("expected `size <width> <height>`")));
+            if width as u64 * height as u64 > MAX_LIST_PIXELS {
+                return Err(error(&format!("size {}x{} exceeds {} pixels", width, height, MAX_LIST_PIXELS)))
+            }
+            map = Some(DefectMap::new(width, height));
+            continue
+        }
+        let map = match map {
+            Some(ref mut map) => map,
+            None => return Err(error("defect before the size line"))
+        };
+        let (width, height) = map.dimensions();
+        match fields[0] {
+            "row" => match (parse_field(&fields, 1), parse_field(&fields, 2)) {
+                (Some(y), Some(code)) if y < height && fields.len() == 3 => for x in 0..width {
+                    map.add_code(x, y, code);
+                },
+                _ => return Err(error("expected `row <y> <code>` inside the map")),
+            },
+            "column" => match (parse_field(&fields, 1), parse_field(&fields, 2)) {
+                (Some(x), Some(code)) if x < width && fields.len() == 3 => for y in 0..height {
+                    map.add_code(x, y, code);
+                },
+                _ => return Err(error("expected `column <x> <code>` inside the map")),
+            },
+            "pixel" => match (parse_field(&fields, 1), parse_field(&fields, 2), parse_field(&fields, 3)) {
+                (Some(x), Some(y), Some(code)) if x < width && y < height && fields.len() == 4 => {
+                    map.add_code(x, y, code);
+                },
+                _ => return Err(error("expected `pixel <x> <y> <code>` inside the map")),
+            },
+            "cluster" => {
+                let code = try!(parse_field(&fields, 1).ok_or(error("expected `cluster <code> <x>,<y> ...`")));
+                if fields.len() < 3 {
+                    return Err(error("a cluster needs at least one pixel"))
+                }
+                for pixel in &fields[2..] {
+                    let xy: Vec<Option<u32>> = pixel.split(',').map(|v| v.parse().ok()).collect();
+                    match (xy.len(), xy.get(0).cloned(), xy.get(1).cloned()) {
+                        (2, Some(Some(x)), Some(Some(y))) if x < width && y < height => map.add_code(x, y, code),
+                        _ => return Err(error(&format!("invalid cluster pixel `{}`", pixel))),
+                    }
+                }
+            },
+            other => return Err(error(&format!("unknown entry `{}`", other))),
+        }
+    }
+    map.ok_or(ImageError::FormatError("defect list without a size line".to_string()))
+}
+
+fn parse_field<T: ::std::str::FromStr>(fields: &[&str], i: usize) -> Option<T> {
+    fields.get(i).and_then(|f| f.parse().ok())
+}
+
+
+/// Path of the text list stored next to the defect image at `path`
+pub fn list_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    path.as_ref().with_extension("txt")
+}
+
+/// Returns an error if the defect image at `path` would share its path with the text list
+fn check_image_path(path: &Path) -> ImageResult<()> {
+    if list_path(path) == path {
+        return Err(ImageError::FormatError(
+            format!("the defect image {} cannot have the extension of its text list, .txt", path.display())
+        ))
+    }
+    Ok(())
+}
+
+/// Saves `map` as the IDP image `path` and the text list `list_path(path)`.
+/// Returns an error if `path` itself ends in `.txt`.
+pub fn save_defect_map<P: AsRef<Path>>(path: P, map: &DefectMap) -> ImageResult<()> {
+    try!(check_image_path(path.as_ref()));
+    try!(write_defect_image(BufWriter::new(try!(File::create(path.as_ref()))), map));
+    write_defect_list(BufWriter::new(try!(File::create(list_path(path)))), map)
+}
+
+/// Reads the IDP image `path` and merges the text list `list_path(path)` into it if it exists.
+/// Returns an error if the two differ in size or if `path` itself ends in `.txt`.
+pub fn open_defect_map<P: AsRef<Path>>(path: P) -> ImageResult<DefectMap> {
+    try!(check_image_path(path.as_ref()));
+    let mut map = try!(read_defect_image(BufReader::new(try!(File::open(path.as_ref())))));
+    match File::open(list_path(path)) {
+        Ok(f) => try!(map.merge(&try!(read_defect_list(BufReader::new(f))))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(ImageError::IoError(e)),
+    }
+    Ok(map)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use defects::{ DefectMap, DefectKind };
+    use super::{ write_defect_list, read_defect_list, write_defect_image, read_defect_image, save_defect_map };
+
+    fn map() -> DefectMap {
+        let mut map = DefectMap::new(6, 5);
+        for x in 0..6 {
+            map.add(x, 4, DefectKind::Dead);
+        }
+        for y in 0..5 {
+            map.add(1, y, DefectKind::Noisy);
+        }
+        map.add(4, 1, DefectKind::Hot);
+        map.add(3, 2, DefectKind::Hot);
+        map.add(5, 0, DefectKind::Unstable);
+        map.add(5, 0, DefectKind::Hot);
+        map
+    }
+
+    #[test]
+    fn list_roundtrip() {
+        let mut cursor = Cursor::new(Vec::new());
+        write_defect_list(&mut cursor, &map()).unwrap();
+        let text = String::from_utf8(cursor.get_ref().clone()).unwrap();
+        assert_eq!(text, "# idp defect list 1\nsize 6 5\nrow 4 2\ncolumn 1 4\n\
+                          pixel 5 0 9\ncluster 1 4,1 3,2\n");
+
+        cursor.set_position(0);
+        assert_eq!(read_defect_list(cursor).unwrap(), map());
+    }
+
+    #[test]
+    fn crossing_row_and_column_are_listed_once() {
+        let mut map = DefectMap::new(4, 3);
+        for x in 0..4 {
+            map.add(x, 1, DefectKind::Hot);
+        }
+        for y in 0..3 {
+            map.add(2, y, DefectKind::Hot);
+        }
+        let mut cursor = Cursor::new(Vec::new());
+        write_defect_list(&mut cursor, &map).unwrap();
+        let text = String::from_utf8(cursor.get_ref().clone()).unwrap();
+        assert_eq!(text, "# idp defect list 1\nsize 4 3\nrow 1 1\npixel 2 0 1\npixel 2 2 1\n");
+        cursor.set_position(0);
+        assert_eq!(read_defect_list(cursor).unwrap(), map);
+
+        let mut cursor = Cursor::new(Vec::new());
+        write_defect_list(&mut cursor, &DefectMap::new(0, 0)).unwrap();
+        assert_eq!(cursor.into_inner(), b"# idp defect list 1\nsize 0 0\n".to_vec());
+    }
+
+    #[test]
+    fn image_roundtrip_and_errors() {
+        let mut cursor = Cursor::new(Vec::new());
+        write_defect_image(&mut cursor, &map()).unwrap();
+        cursor.set_position(0);
+        assert_eq!(read_defect_image(cursor).unwrap(), map());
+
+        for text in &["pixel 1 1 1\n", "size 2 2\npixel 2 0 1\n", "size 2 2\nline 0 1\n", "size 2 2\ncluster 1 0;1\n",
+                      "size 4000000000 4000000000\n"] {
+            assert!(read_defect_list(Cursor::new(text.as_bytes())).is_err());
+        }
+        assert!(save_defect_map("map.txt", &map()).is_err());
+    }
+}
@@ -0,0 +1,478 @@
+//! Detection and classification of defective pixels
+
+use std::path::Path;
+
+use num::ToPrimitive;
+
+use arithmetic::{ self, Operation };
+use buffer::{ ImageBuffer, Gray16Image };
+use calibration::master_frame;
+use defect_file;
+use image::error::{ ImageError, ImageResult };
+use image::other::GrayU16;
+use mask::PixelMask;
+use stack::{ ImageStack, RunningStatistics };
+use stats::{ sort, percentile };
+use traits::{ Pixel, GenericImageView };
+
+
+/// Scales the median absolute deviation to the standard deviation of normally distributed values
+const MAD_TO_SIGMA: f64 = 1.4826;
+
+/// The noise of rounding to whole counts, 1 / sqrt(12), a `Detector::min_sigma` for u16 frames.
+/// Quantized frames whose residuals are mostly exactly 0 have a robust sigma of 0, which would
+/// otherwise flag every pixel that differs from its neighborhood by a single count.
+pub const QUANTIZATION_SIGMA: f64 = 0.28867513459481287;
+
+
+/// The ways a pixel can be defective
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DefectKind {
+    /// High signal without illumination
+    Hot,
+    /// Little or no response to illumination
+    Dead,
+    /// High temporal noise
+    Noisy,
+    /// Signal that drifts or jumps between frames
+    Unstable,
+}
+
+impl DefectKind {
+    /// Every kind, in the order of their codes
+    pub fn all() -> [DefectKind; 4] {
+        [DefectKind::Hot, DefectKind::Dead, DefectKind::Noisy, DefectKind::Unstable]
+    }
+
+    /// The bit that marks this kind in a classification code
+    pub fn code(&self) -> u16 {
+        match *self {
+            DefectKind::Hot => 1,
+            DefectKind::Dead => 2,
+            DefectKind::Noisy => 4,
+            DefectKind::Unstable => 8,
+        }
+    }
+}
+
+
+/// The bitwise or of the codes of all kinds
+fn known_codes() -> u16 {
+    DefectKind::all().iter().fold(0, |acc, k| acc | k.code())
+}
+
+
+/// How far a pixel may deviate from the median of its neighborhood
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    /// A multiple of the robust standard deviation of all deviations in the frame,
+    /// estimated from their median absolute deviation and at least `Detector::min_sigma`
+    Sigma(f64),
+    /// A fixed difference in pixel values
+    Absolute(f64),
+}
+
+/// Which deviations from the neighborhood count as defects
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Above,
+    Below,
+    Either,
+}
+
+/// Finds pixels that deviate from the median of their neighborhood
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Detector {
+    /// The allowed deviation
+    pub limit: Limit,
+    /// The neighborhood is the square of `2 * radius + 1` pixels around a pixel, without the pixel itself
+    pub radius: u32,
+    /// Smallest robust standard deviation a `Limit::Sigma` is scaled from, 0 by default
+    pub min_sigma: f64,
+}
+
+impl Detector {
+    /// Creates a detector with a sigma-clipping limit
+    pub fn sigma(k: f64, radius: u32) -> Detector {
+        Detector { limit: Limit::Sigma(k), radius: radius, min_sigma: 0.0 }
+    }
+
+    /// Creates a detector with an absolute limit
+    pub fn absolute(limit: f64, radius: u32) -> Detector {
+        Detector { limit: Limit::Absolute(limit), radius: radius, min_sigma: 0.0 }
+    }
+
+    /// Sets the smallest robust standard deviation, e.g. `QUANTIZATION_SIGMA` for frames of whole counts
+    pub fn with_min_sigma(mut self, min_sigma: f64) -> Detector {
+        self.min_sigma = min_sigma;
+        self
+    }
+
+    /// Returns a mask of the pixels that deviate from their local median by more than the limit
+    /// in `direction`. Pixels that are NaN or infinite are always selected.
+    pub fn detect<I: GenericImageView>(&self, image: &I, direction: Direction) -> PixelMask {
+        let (width, height) = image.dimensions();
+        let residuals = local_residuals(image, self.radius);
+        let limit = match self.limit {
+            Limit::Absolute(limit) => limit,
+            Limit::Sigma(k) => k * robust_sigma(&residuals).max(self.min_sigma),
+        };
+        PixelMask::from_fn(width, height, |x, y| {
+            let r = residuals[y as usize * width as usize + x as usize];
+            !r.is_finite() || match direction {
+                Direction::Above => r > limit,
+                Direction::Below => -r > limit,
+                Direction::Either => r.abs() > limit,
+            }
+        })
+    }
+}
+
+
+/// Returns the value of every pixel minus the median of its neighborhood, in row major order
+fn local_residuals<I: GenericImageView>(image: &I, radius: u32) -> Vec<f64> {
+    let (width, height) = image.dimensions();
+    let value_at = |x: u32, y: u32| image.get_pixel(x, y).value().to_f64().unwrap();
+    let mut neighbors = Vec::new();
+    let mut residuals = Vec::with_capacity(width as usize * height as usize);
+    for y in 0..height {
+        for x in 0..width {
+            neighbors.clear();
+            let (x0, x1) = (x.saturating_sub(radius), ::std::cmp::min(x + radius, width - 1));
+            let (y0, y1) = (y.saturating_sub(radius), ::std::cmp::min(y + radius, height - 1));
+            for ny in y0..y1 + 1 {
+                for nx in x0..x1 + 1 {
+                    let v = value_at(nx, ny);
+                    if (nx, ny) != (x, y) && !v.is_nan() {
+                        neighbors.push(v);
+                    }
+                }
+            }
+            residuals.push(if neighbors.is_empty() {
+                0.0
+            } else {
+                sort(&mut neighbors);
+                value_at(x, y) - percentile(&neighbors, 50.0)
+            });
+        }
+    }
+    residuals
+}
+
+/// Estimates the standard deviation of `residuals` from their median absolute deviation,
+/// which is not inflated by the defects themselves
+fn robust_sigma(residuals: &[f64]) -> f64 {
+    let mut values: Vec<f64> = residuals.iter().cloned().filter(|r| r.is_finite()).collect();
+    if values.is_empty() {
+        return 0.0
+    }
+    sort(&mut values);
+    let median = percentile(&values, 50.0);
+    let mut deviations: Vec<f64> = values.iter().map(|v| (v - median).abs()).collect();
+    sort(&mut deviations);
+    MAD_TO_SIGMA * percentile(&deviations, 50.0)
+}
+
+
+/// Hot pixels: dark signal above the neighborhood
+pub fn hot_pixels<I: GenericImageView>(dark: &I, detector: &Detector) -> PixelMask {
+    detector.detect(dark, Direction::Above)
+}
+
+/// Dead pixels: response `flat - dark` below the neighborhood.
+/// Returns an error if the frames differ in size.
+pub fn dead_pixels<I, J>(dark: &I, flat: &J, detector: &Detector) -> ImageResult<PixelMask>
+where I: GenericImageView, J: GenericImageView {
+    let response = try!(arithmetic::combine_to_float(flat, dark, Operation::Subtract, None));
+    Ok(detector.detect(&response, Direction::Below))
+}
+
+/// Noisy pixels: temporal standard deviation over `stack` above the neighborhood
+pub fn noisy_pixels(stack: &ImageStack, detector: &Detector) -> ImageResult<PixelMask> {
+    try!(check_frames(stack, 2));
+    Ok(detector.detect(&stack.std_dev(), Direction::Above))
+}
+
+/// Unstable pixels: the mean of the first half of `stack` differs from the mean of the
+/// second half by more than the neighborhood does, in either direction
+pub fn unstable_pixels(stack: &ImageStack, detector: &Detector) -> ImageResult<PixelMask> {
+    try!(check_frames(stack, 2));
+    let (width, height) = stack.dimensions();
+    let half = stack.len() / 2;
+    let mut first = RunningStatistics::new(width, height);
+    let mut second = RunningStatistics::new(width, height);
+    for (i, frame) in stack.frames().iter().enumerate() {
+        try!(if i < half { first.add(frame) } else { second.add(frame) });
+    }
+    let drift = try!(arithmetic::combine_to_float(&second.mean(), &first.mean(), Operation::Subtract, None));
+    Ok(detector.detect(&drift, Direction::Either))
+}
+
+fn check_frames(stack: &ImageStack, n: usize) -> ImageResult<()> {
+    if stack.len() >= n {
+        Ok(())
+    } else {
+        Err(ImageError::FormatError(format!("need at least {} frames, the stack holds {}", n, stack.len())))
+    }
+}
+
+
+/// The detectors run by `DefectMap::detect`; a kind whose detector is None is not searched for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DetectionConfig {
+    pub hot: Option<Detector>,
+    pub dead: Option<Detector>,
+    pub noisy: Option<Detector>,
+    pub unstable: Option<Detector>,
+}
+
+impl Default for DetectionConfig {
+    /// Five sigma from the median of the 5x5 neighborhood for every kind
+    fn default() -> DetectionConfig {
+        let detector = Detector::sigma(5.0, 2);
+        DetectionConfig {
+            hot: Some(detector),
+            dead: Some(detector),
+            noisy: Some(detector),
+            unstable: Some(detector),
+        }
+    }
+}
+
+
+/// The classification of every pixel of a detector.
+///
+/// A pixel can be defective in several ways at once; its code is the bitwise or of the
+/// `DefectKind::code` of each kind, and 0 for a good pixel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DefectMap {
+    width: u32,
+    height: u32,
+    codes: Vec<u16>,
+}
+
+impl DefectMap {
+    /// Creates a map of the given size without defects
+    pub fn new(width: u32, height: u32) -> DefectMap {
+        DefectMap {
+            width: width,
+            height: height,
+            codes: vec![0; width as usize * height as usize],
+        }
+    }
+
+    /// Runs the detectors of `config` on stacks of dark and flat frames.
+    ///
+    /// Hot and dead pixels are found in the master frames, noisy and unstable pixels
+    /// in the dark stack, which then needs at least two frames.
+    pub fn detect(darks: &ImageStack, flats: &ImageStack, config: &DetectionConfig) -> ImageResult<DefectMap> {
+        let (width, height) = darks.dimensions();
+        let mut map = DefectMap::new(width, height);
+        let dark = try!(master_frame(darks));
+        if let Some(ref detector) = config.hot {
+            try!(map.mark(&hot_pixels(&dark, detector), DefectKind::Hot));
+        }
+        if let Some(ref detector) = config.dead {
+            let flat = try!(master_frame(flats));
+            try!(map.mark(&try!(dead_pixels(&dark, &flat, detector)), DefectKind::Dead));
+        }
+        if let Some(ref detector) = config.noisy {
+            try!(map.mark(&try!(noisy_pixels(darks, detector)), DefectKind::Noisy));
+        }
+        if let Some(ref detector) = config.unstable {
+            try!(map.mark(&try!(unstable_pixels(darks, detector)), DefectKind::Unstable));
+        }
+        Ok(map)
+    }
+
+    /// Reads a map saved by `save`, see `defect_file::open_defect_map`
+    pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<DefectMap> {
+        defect_file::open_defect_map(path)
+    }
+
+    /// Saves the map as a u16 IDP image of classification codes and a text list of the defects,
+    /// see `defect_file::save_defect_map`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> ImageResult<()> {
+        defect_file::save_defect_map(path, self)
+    }
+
+    /// Creates a map with every pixel set in `mask` classified as `kind`
+    pub fn from_mask(mask: &PixelMask, kind: DefectKind) -> DefectMap {
+        let (width, height) = mask.dimensions();
+        let mut map = DefectMap::new(width, height);
+        map.mark(mask, kind).unwrap();
+        map
+    }
+
+    /// Creates a map from an image of classification codes. Unknown bits are dropped.
+    pub fn from_image<I: GenericImageView>(image: &I) -> DefectMap {
+        let (width, height) = image.dimensions();
+        let known = known_codes();
+        DefectMap {
+            width: width,
+            height: height,
+            codes: image.pixels().map(|(_, _, p)| p.value().to_u16().unwrap_or(0) & known).collect(),
+        }
+    }
+
+    /// Converts the map to an image of classification codes
+    pub fn to_image(&self) -> Gray16Image {
+        ImageBuffer::from_fn(self.width, self.height, |x, y| GrayU16(self.code(x, y)))
+    }
+
+    /// The width and height of this map.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    #[inline(always)]
+    fn index(&self, x: u32, y: u32) -> usize {
+        assert!(x < self.width && y < self.height,
+                "({}, {}) is outside the {}x{} defect map", x, y, self.width, self.height);
+        y as usize * self.width as usize + x as usize
+    }
+
+    /// The classification code of the pixel at (x, y)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `(x, y)` is out of bounds.
+    pub fn code(&self, x: u32, y: u32) -> u16 {
+        self.codes[self.index(x, y)]
+    }
+
+    /// Returns true if the pixel at (x, y) is defective in the way `kind` describes
+    pub fn is(&self, x: u32, y: u32, kind: DefectKind) -> bool {
+        self.code(x, y) & kind.code() != 0
+    }
+
+    /// The kinds of defect of the pixel at (x, y), empty for a good pixel
+    pub fn kinds(&self, x: u32, y: u32) -> Vec<DefectKind> {
+        DefectKind::all().iter().cloned().filter(|&k| self.is(x, y, k)).collect()
+    }
+
+    /// Classifies the pixel at (x, y) as `kind`, in addition to any earlier classification
+    pub fn add(&mut self, x: u32, y: u32, kind: DefectKind) {
+        let i = self.index(x, y);
+        self.codes[i] |= kind.code();
+    }
+
+    /// Adds the kinds in `code` to the classification of the pixel at (x, y). Unknown bits are dropped.
+    pub fn add_code(&mut self, x: u32, y: u32, code: u16) {
+        let i = self.index(x, y);
+        self.codes[i] |= code & known_codes();
+    }
+
+    /// Adds the classifications of `other` to this map. Returns an error if the maps differ in size.
+    pub fn merge(&mut self, other: &DefectMap) -> ImageResult<()> {
+        if self.dimensions() != other.dimensions() {
+            return Err(ImageError::DimensionMismatch(self.dimensions(), other.dimensions()))
+        }
+        for (code, other) in self.codes.iter_mut().zip(other.codes.iter()) {
+            *code |= *other;
+        }
+        Ok(())
+    }
+
+    /// Classifies every pixel set in `mask` as `kind`. Returns an error if the mask differs in size.
+    pub fn mark(&mut self, mask: &PixelMask, kind: DefectKind) -> ImageResult<()> {
+        try!(mask.check_dimensions(self.dimensions()));
+        for (x, y) in mask.masked_pixels() {
+            self.add(x, y, kind);
+        }
+        Ok(())
+    }
+
+    /// Number of pixels classified as `kind`
+    pub fn count(&self, kind: DefectKind) -> usize {
+        self.codes.iter().filter(|&&c| c & kind.code() != 0).count()
+    }
+
+    /// Number of pixels with any defect
+    pub fn defective(&self) -> usize {
+        self.codes.iter().filter(|&&c| c != 0).count()
+    }
+
+    /// Returns a mask of the pixels classified as `kind`
+    pub fn mask_of(&self, kind: DefectKind) -> PixelMask {
+        PixelMask::from_fn(self.width, self.height, |x, y| self.is(x, y, kind))
+    }
+
+    /// Returns a mask of every defective pixel, to skip them in statistics
+    pub fn to_mask(&self) -> PixelMask {
+        PixelMask::from_fn(self.width, self.height, |x, y| self.code(x, y) != 0)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+    use image::other::{ GrayU16, GrayF32 };
+    use stack::ImageStack;
+    use super::{ DefectMap, DefectKind, Detector, DetectionConfig, hot_pixels, QUANTIZATION_SIGMA };
+
+    #[test]
+    fn sigma_limit_has_a_floor_for_quantized_frames() {
+        // Nearly all residuals are exactly 0, a few pixels are one count up, one is hot
+        let dark: Gray16Image = ImageBuffer::from_fn(8, 8, |x, y| GrayU16(match (x, y) {
+            (5, 5) => 110,
+            (1, 2) | (6, 1) | (2, 6) => 101,
+            _ => 100,
+        }));
+        let hot = hot_pixels(&dark, &Detector::sigma(5.0, 1).with_min_sigma(QUANTIZATION_SIGMA));
+        assert_eq!(hot.masked_pixels(), vec![(5, 5)]);
+        assert_eq!(hot_pixels(&dark, &Detector::sigma(5.0, 1)).masked_pixels().len(), 4);
+    }
+
+    #[test]
+    fn sigma_limit_follows_small_float_noise() {
+        // Noise of about 0.001 around 0.5, one pixel 0.05 above
+        let dark: GrayFloatImage = ImageBuffer::from_fn(8, 8, |x, y| GrayF32(match (x, y) {
+            (3, 4) => 0.55,
+            _ => 0.5 + ((x * 7 + y * 13) % 5) as f32 * 0.0005,
+        }));
+        assert_eq!(hot_pixels(&dark, &Detector::sigma(5.0, 1)).masked_pixels(), vec![(3, 4)]);
+    }
+
+    #[test]
+    fn finds_hot_pixels_relative_to_neighbors() {
+        // A gradient, so only a local reference tells the hot pixel from the bright corner
+        let dark: GrayFloatImage = ImageBuffer::from_fn(6, 6, |x, y| {
+            GrayF32(if (x, y) == (1, 1) { 60.0 } else { (x + y) as f32 * 10.0 })
+        });
+        let hot = hot_pixels(&dark, &Detector::absolute(25.0, 1));
+        assert_eq!(hot.masked_pixels(), vec![(1, 1)]);
+    }
+
+    /// Deterministic noise of a few counts
+    fn noise(x: u32, y: u32, i: u32) -> f32 {
+        ((x * 7 + y * 13 + i * 5) % 5) as f32
+    }
+
+    #[test]
+    fn classifies_defects_from_stacks() {
+        // (0, 0) is hot, (3, 1) is dead, (4, 4) is noisy and (2, 4) drifts halfway through
+        let darks: Vec<GrayFloatImage> = (0..4).map(|i| ImageBuffer::from_fn(5, 5, |x, y| GrayF32(match (x, y) {
+            (0, 0) => 500.0,
+            (4, 4) => if i % 2 == 0 { 0.0 } else { 200.0 },
+            (2, 4) => if i < 2 { 75.0 } else { 125.0 },
+            _ => 100.0,
+        } + noise(x, y, i)))).collect();
+        let flats: Vec<GrayFloatImage> = (0..2).map(|i| ImageBuffer::from_fn(5, 5, |x, y| GrayF32(match (x, y) {
+            (0, 0) => 1400.0,
+            (3, 1) => 100.0,
+            _ => 1000.0,
+        } + noise(x, y, i)))).collect();
+
+        let map = DefectMap::detect(&ImageStack::from_images(&darks).unwrap(),
+                                    &ImageStack::from_images(&flats).unwrap(),
+                                    &DetectionConfig::default()).unwrap();
+        assert_eq!(map.kinds(0, 0), vec![DefectKind::Hot]);
+        assert_eq!(map.kinds(3, 1), vec![DefectKind::Dead]);
+        assert!(map.is(4, 4, DefectKind::Noisy));
+        assert!(map.is(2, 4, DefectKind::Unstable));
+        assert_eq!(map.to_mask().count(), map.defective());
+        assert_eq!(DefectMap::from_image(&map.to_image()), map);
+    }
+}
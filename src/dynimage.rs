@@ -0,0 +1,286 @@
+use std::io::BufWriter;
+use std::fs::File;
+use std::path::Path;
+
+use arithmetic::{ self, Operation, OverflowPolicy, fit };
+use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+use correction::Correction;
+use decoder::ImageDecoder;
+use encoder::ImageEncoder;
+use format;
+use image::error::{ ImageError, ImageResult };
+use image::other::{ PixelType, DecodingResult, GrayU16, GrayF32 };
+use histogram::{ self, Binning, Histogram };
+use mask::PixelMask;
+use png::PngEncoder;
+use preview::Preview;
+use roi::{ self, Roi, RoiStatistics };
+use stats::{ self, Statistics };
+use threshold::{ self, Threshold };
+
+
+/// An IDP image whose pixel type is only known at runtime
+#[derive(Clone)]
+pub enum DynamicIdpImage {
+    /// Each pixel is an unsigned 16 bit gray value
+    U16(Gray16Image),
+    /// Each pixel is a 32 bit float gray value
+    F32(GrayFloatImage),
+}
+
+/// Evaluates `$action` with `$image` bound to whichever buffer `$dynimage` holds
+macro_rules! dynamic_map {
+    ($dynimage: expr, ref $image: ident => $action: expr) => (
+        match $dynimage {
+            DynamicIdpImage::U16(ref $image) => $action,
+            DynamicIdpImage::F32(ref $image) => $action,
+        }
+    );
+}
+
+impl DynamicIdpImage {
+    /// Opens the image file at `path` in any readable format and decodes its first frame,
+    /// see `format::open`
+    pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<DynamicIdpImage> {
+        format::open(path)
+    }
+
+    /// Decodes the current image of `decoder`
+    pub fn from_decoder<D: ImageDecoder>(decoder: &mut D) -> ImageResult<DynamicIdpImage> {
+        let (width, height) = try!(decoder.dimensions());
+        let data = try!(decoder.read_image());
+        DynamicIdpImage::from_decoding_result(width, height, data)
+    }
+
+    /// Wraps the result of a decoder. Returns an error if it holds fewer than `width * height` pixels.
+    pub fn from_decoding_result(width: u32, height: u32, data: DecodingResult) -> ImageResult<DynamicIdpImage> {
+        let image = match data {
+            DecodingResult::U16(buffer) => ImageBuffer::from_raw(width, height, buffer).map(DynamicIdpImage::U16),
+            DecodingResult::F32(buffer) => ImageBuffer::from_raw(width, height, buffer).map(DynamicIdpImage::F32),
+        };
+        match image {
+            Some(image) => Ok(image),
+            None => Err(ImageError::FormatError(
+                format!("not enough pixels for a {}x{} image", width, height)
+            ))
+        }
+    }
+
+    /// Saves the image at `path` in the format given by its extension, keeping its pixel type.
+    /// See `format::save`.
+    pub fn save<Q: AsRef<Path>>(&self, path: Q) -> ImageResult<()> {
+        dynamic_map!(*self, ref image => format::save(image, path))
+    }
+
+    /// Saves the image losslessly as a 16 bit grayscale PNG file at `path`.
+    /// Returns an error for f32 images, which only have a preview.
+    pub fn save_png<Q: AsRef<Path>>(&self, path: Q) -> ImageResult<()> {
+        let f = try!(File::create(path));
+        let mut encoder = PngEncoder::new(BufWriter::new(f));
+        dynamic_map!(*self, ref image => encoder.encode_image(image))
+    }
+
+    /// Saves an 8 bit rendering of the image as a PNG file at `path`, see `PngEncoder::encode_preview`
+    pub fn save_preview<Q: AsRef<Path>>(&self, path: Q, mask: Option<&PixelMask>, preview: &Preview) -> ImageResult<()> {
+        let f = try!(File::create(path));
+        let mut encoder = PngEncoder::new(BufWriter::new(f));
+        dynamic_map!(*self, ref image => encoder.encode_preview(image, mask, preview))
+    }
+
+    /// The width and height of this image.
+    pub fn dimensions(&self) -> (u32, u32) {
+        dynamic_map!(*self, ref image => image.dimensions())
+    }
+
+    /// The pixel type of this image.
+    pub fn pixel_type(&self) -> PixelType {
+        match *self {
+            DynamicIdpImage::U16(_) => PixelType::Short16,
+            DynamicIdpImage::F32(_) => PixelType::Float32,
+        }
+    }
+
+    /// Returns the image as `Gray16Image`.
+    /// Float pixels are rounded and clamped to 0..65535, NaN becomes 0.
+    pub fn to_u16(&self) -> Gray16Image {
+        match *self {
+            DynamicIdpImage::U16(ref image) => image.clone(),
+            DynamicIdpImage::F32(ref image) => {
+                let (width, height) = image.dimensions();
+                ImageBuffer::from_fn(width, height, |x, y| {
+                    GrayU16(fit(image.get_pixel(x, y).data as f64, OverflowPolicy::Saturate))
+                })
+            }
+        }
+    }
+
+    /// Returns the image as `GrayFloatImage`. This is lossless for both pixel types.
+    pub fn to_f32(&self) -> GrayFloatImage {
+        match *self {
+            DynamicIdpImage::U16(ref image) => {
+                let (width, height) = image.dimensions();
+                ImageBuffer::from_fn(width, height, |x, y| GrayF32(image.get_pixel(x, y).data as f32))
+            },
+            DynamicIdpImage::F32(ref image) => image.clone(),
+        }
+    }
+
+    /// Returns a copy of the image with `pixel_type` pixels, see `to_u16` and `to_f32`
+    pub fn convert(&self, pixel_type: PixelType) -> DynamicIdpImage {
+        match pixel_type {
+            PixelType::Short16 => DynamicIdpImage::U16(self.to_u16()),
+            PixelType::Float32 => DynamicIdpImage::F32(self.to_f32()),
+        }
+    }
+
+    /// See `stats::frame_statistics`
+    pub fn frame_statistics(&self, mask: Option<&PixelMask>) -> Option<Statistics> {
+        dynamic_map!(*self, ref image => stats::frame_statistics(image, mask))
+    }
+
+    /// See `stats::row_statistics`
+    pub fn row_statistics(&self, mask: Option<&PixelMask>) -> Vec<Option<Statistics>> {
+        dynamic_map!(*self, ref image => stats::row_statistics(image, mask))
+    }
+
+    /// See `stats::column_statistics`
+    pub fn column_statistics(&self, mask: Option<&PixelMask>) -> Vec<Option<Statistics>> {
+        dynamic_map!(*self, ref image => stats::column_statistics(image, mask))
+    }
+
+    /// See `stats::frame_percentiles`
+    pub fn frame_percentiles(&self, mask: Option<&PixelMask>, percentiles: &[f64]) -> Vec<f64> {
+        dynamic_map!(*self, ref image => stats::frame_percentiles(image, mask, percentiles))
+    }
+
+    /// Computes the histogram with `binning`, or the exact u16 histogram if `binning` is None
+    /// and the pixels are u16. Float pixels default to 256 bins over their range.
+    /// See `histogram::histogram` and `histogram::gray16_histogram`.
+    pub fn histogram(&self, mask: Option<&PixelMask>, binning: Option<Binning>) -> Histogram {
+        match (self, binning) {
+            (&DynamicIdpImage::U16(ref image), None) => histogram::gray16_histogram(image, mask),
+            (_, binning) => {
+                let binning = binning.unwrap_or(Binning::auto(histogram::DEFAULT_BINS));
+                dynamic_map!(*self, ref image => histogram::histogram(image, mask, binning))
+            }
+        }
+    }
+
+    /// See `roi::roi_statistics`
+    pub fn roi_statistics(&self, rois: &[Roi], mask: Option<&PixelMask>) -> Vec<RoiStatistics> {
+        dynamic_map!(*self, ref image => roi::roi_statistics(image, rois, mask))
+    }
+
+    /// See `threshold::count`
+    pub fn count(&self, t: Threshold, mask: Option<&PixelMask>) -> usize {
+        dynamic_map!(*self, ref image => threshold::count(image, t, mask))
+    }
+
+    /// See `threshold::count_per_row`
+    pub fn count_per_row(&self, t: Threshold, mask: Option<&PixelMask>) -> Vec<usize> {
+        dynamic_map!(*self, ref image => threshold::count_per_row(image, t, mask))
+    }
+
+    /// See `threshold::count_per_column`
+    pub fn count_per_column(&self, t: Threshold, mask: Option<&PixelMask>) -> Vec<usize> {
+        dynamic_map!(*self, ref image => threshold::count_per_column(image, t, mask))
+    }
+
+    /// See `threshold::binary_map`
+    pub fn binary_map(&self, t: Threshold, mask: Option<&PixelMask>) -> PixelMask {
+        dynamic_map!(*self, ref image => threshold::binary_map(image, t, mask))
+    }
+
+    /// See `correction::correct`
+    pub fn correct(&mut self, mask: &PixelMask, correction: Correction) -> ImageResult<usize> {
+        match *self {
+            DynamicIdpImage::U16(ref mut image) => image.correct(mask, correction),
+            DynamicIdpImage::F32(ref mut image) => image.correct(mask, correction),
+        }
+    }
+
+    /// Applies `op` to every pair of pixels of `self` and `other`.
+    ///
+    /// If both images have the same pixel type and a `policy` is given the result keeps that type
+    /// and pixels set in `mask` keep their value in `self`. Otherwise the result is promoted to f32
+    /// and pixels set in `mask` become NaN. See `arithmetic::combine` and `arithmetic::combine_to_float`.
+    pub fn combine(&self, other: &DynamicIdpImage, op: Operation, policy: Option<OverflowPolicy>,
+                   mask: Option<&PixelMask>) -> ImageResult<DynamicIdpImage> {
+        match (self, other, policy) {
+            (&DynamicIdpImage::U16(ref a), &DynamicIdpImage::U16(ref b), Some(policy)) =>
+                arithmetic::combine(a, b, op, policy, mask).map(DynamicIdpImage::U16),
+            (&DynamicIdpImage::F32(ref a), &DynamicIdpImage::F32(ref b), Some(policy)) =>
+                arithmetic::combine(a, b, op, policy, mask).map(DynamicIdpImage::F32),
+            _ => dynamic_map!(*self, ref a => dynamic_map!(*other, ref b => {
+                arithmetic::combine_to_float(a, b, op, mask).map(DynamicIdpImage::F32)
+            }))
+        }
+    }
+
+    /// Returns `self - other`, see `combine`
+    pub fn subtract(&self, other: &DynamicIdpImage, policy: Option<OverflowPolicy>, mask: Option<&PixelMask>)
+                    -> ImageResult<DynamicIdpImage> {
+        self.combine(other, Operation::Subtract, policy, mask)
+    }
+
+    /// Returns `self * factor`, see `arithmetic::scale`
+    pub fn scale(&self, factor: f64, policy: OverflowPolicy) -> DynamicIdpImage {
+        match *self {
+            DynamicIdpImage::U16(ref image) => DynamicIdpImage::U16(arithmetic::scale(image, factor, policy)),
+            DynamicIdpImage::F32(ref image) => DynamicIdpImage::F32(arithmetic::scale(image, factor, policy)),
+        }
+    }
+
+    /// Returns `self + delta`, see `arithmetic::offset`
+    pub fn offset(&self, delta: f64, policy: OverflowPolicy) -> DynamicIdpImage {
+        match *self {
+            DynamicIdpImage::U16(ref image) => DynamicIdpImage::U16(arithmetic::offset(image, delta, policy)),
+            DynamicIdpImage::F32(ref image) => DynamicIdpImage::F32(arithmetic::offset(image, delta, policy)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use arithmetic::OverflowPolicy;
+    use buffer::ImageBuffer;
+    use image::other::{ PixelType, GrayU16, GrayF32 };
+    use super::DynamicIdpImage;
+
+    fn floats(values: &[f32]) -> DynamicIdpImage {
+        let values = values.to_vec();
+        DynamicIdpImage::F32(ImageBuffer::from_fn(values.len() as u32, 1, |x, _| GrayF32(values[x as usize])))
+    }
+
+    fn shorts(values: &[u16]) -> DynamicIdpImage {
+        let values = values.to_vec();
+        DynamicIdpImage::U16(ImageBuffer::from_fn(values.len() as u32, 1, |x, _| GrayU16(values[x as usize])))
+    }
+
+    #[test]
+    fn float_to_u16_rounds_clamps_and_zeroes_nan() {
+        let image = floats(&[2.4, 2.5, -5.0, 70000.0, ::std::f32::NAN, ::std::f32::INFINITY]);
+        assert_eq!(&*image.to_u16(), &[2, 3, 0, 65535, 0, 65535]);
+        assert_eq!(image.convert(PixelType::Short16).pixel_type(), PixelType::Short16);
+        assert_eq!(&*shorts(&[0, 65535]).to_f32(), &[0.0, 65535.0]);
+    }
+
+    #[test]
+    fn combine_promotes_to_f32_unless_both_types_match_and_a_policy_is_given() {
+        let (a, b) = (shorts(&[3]), shorts(&[5]));
+        let (c, d) = (floats(&[3.0]), floats(&[5.5]));
+        let saturate = Some(OverflowPolicy::Saturate);
+        let cases = [(&a, &b, saturate, PixelType::Short16, 0.0),
+                     (&a, &b, None, PixelType::Float32, -2.0),
+                     (&c, &d, saturate, PixelType::Float32, -2.5),
+                     (&c, &d, None, PixelType::Float32, -2.5),
+                     (&a, &d, saturate, PixelType::Float32, -2.5),
+                     (&c, &b, None, PixelType::Float32, -2.0)];
+        for &(x, y, policy, pixel_type, value) in &cases {
+            let difference = x.subtract(y, policy, None).unwrap();
+            assert_eq!(difference.pixel_type(), pixel_type);
+            assert_eq!(difference.to_f32().get_pixel(0, 0).data, value);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use std::io::{Write, Seek, SeekFrom};
+use std::ops::Deref;
+
+use num::NumCast;
+
+use image::error::{
+    ImageError,
+    ImageResult,
+    HeaderError
+};
+
+use image::other::{
+    PixelType,
+    DecodingResult
+};
+
+use buffer::ImageBuffer;
+
+use traits::{
+    Pixel,
+    GenericImageView
+};
+
+use super::stream::{
+    ByteOrder,
+    EndianWriter,
+    SmartWriter
+};
+
+
+
+/// The trait that all encoders implement
+pub trait ImageEncoder: Sized {
+    /// Encodes a decoded image of the given width and height
+    fn encode(&mut self, width: u32, height: u32, image: &DecodingResult) -> ImageResult<()>;
+
+    /// Encodes any image, using the pixel type of its pixels
+    fn encode_image<I: GenericImageView>(&mut self, image: &I) -> ImageResult<()>;
+
+    /// Encodes an image buffer with any container, using the pixel type of its pixels
+    fn encode_buffer<P, C>(&mut self, image: &ImageBuffer<P, C>) -> ImageResult<()>
+    where P: Pixel + 'static, P::Subpixel: 'static, C: Deref<Target=[P::Subpixel]>;
+}
+
+
+
+
+#[derive(Debug)]
+pub struct IDPEncoder<W> where W: Write + Seek {
+    writer: SmartWriter<W>,
+}
+
+
+impl<W: Write + Seek> IDPEncoder<W> {
+    /// Create a new encoder that writes to the stream ```w```
+    pub fn new(w: W) -> IDPEncoder<W> {
+        IDPEncoder {
+            writer: SmartWriter::wrap(w, ByteOrder::LittleEndian),
+        }
+    }
+
+    /// Returns the wrapped writer
+    pub fn into_inner(self) -> SmartWriter<W> {
+        self.writer
+    }
+
+    fn write_header(&mut self, width: u32, height: u32, pixel_type: PixelType) -> ImageResult<()> {
+        let fmt2 = match pixel_type {
+            PixelType::Short16 => 0u32,
+            PixelType::Float32 => 2u32,
+        };
+        try!(self.writer.write_u32(0u32));
+        try!(self.writer.write_u32(fmt2));
+        try!(self.writer.write_u32(width));
+        try!(self.writer.write_u32(height));
+        Ok(())
+    }
+
+    /// Writes a header for `P` followed by `pixels`, which must be in row major order
+    fn write_pixels<P, It>(&mut self, width: u32, height: u32, pixels: It) -> ImageResult<()>
+    where P: Pixel, It: Iterator<Item=(u32, u32, P)> {
+        let pixel_type = <P as Pixel>::pixel_type();
+        try!(self.write_header(width, height, pixel_type));
+        match pixel_type {
+            PixelType::Short16 => {
+                for (x, y, p) in pixels {
+                    try!(self.writer.write_u16(try!(cast(*p.value(), x, y))));
+                }
+            },
+            PixelType::Float32 => {
+                for (x, y, p) in pixels {
+                    try!(self.writer.write_f32(try!(cast(*p.value(), x, y))));
+                }
+            },
+        }
+        try!(self.writer.flush());
+        Ok(())
+    }
+}
+
+
+/// Casts a subpixel to the on-disk type, failing if the value does not fit
+fn cast<S: NumCast, T: NumCast>(value: S, x: u32, y: u32) -> ImageResult<T> {
+    match NumCast::from(value) {
+        Some(v) => Ok(v),
+        None => Err(ImageError::FormatError(
+            format!("pixel ({}, {}) cannot be represented in the output pixel type", x, y)
+        ))
+    }
+}
+
+
+impl<W: Write + Seek> ImageEncoder for IDPEncoder<W> {
+    fn encode(&mut self, width: u32, height: u32, image: &DecodingResult) -> ImageResult<()> {
+        // The decoder refuses empty frames, so they are not written either
+        if width == 0 || height == 0 {
+            let offset = try!(self.writer.seek(SeekFrom::Current(0)));
+            return Err(ImageError::HeaderError(
+                HeaderError::InvalidDimensions { width: width, height: height, offset: offset }
+            ))
+        }
+        let number_of_pixels = width as usize * height as usize;
+        let (pixel_type, len) = match *image {
+            DecodingResult::U16(ref buffer) => (PixelType::Short16, buffer.len()),
+            DecodingResult::F32(ref buffer) => (PixelType::Float32, buffer.len()),
+        };
+        if len != number_of_pixels {
+            return Err(ImageError::FormatError(
+                format!("{}x{} image needs {} pixels, got {}", width, height, number_of_pixels, len)
+            ))
+        }
+
+        try!(self.write_header(width, height, pixel_type));
+        match *image {
+            DecodingResult::U16(ref buffer) => {
+                for &datum in buffer {
+                    try!(self.writer.write_u16(datum));
+                }
+            },
+            DecodingResult::F32(ref buffer) => {
+                for &datum in buffer {
+                    try!(self.writer.write_f32(datum));
+                }
+            },
+        }
+        try!(self.writer.flush());
+        Ok(())
+    }
+
+    fn encode_image<I: GenericImageView>(&mut self, image: &I) -> ImageResult<()> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.pixels())
+    }
+
+    fn encode_buffer<P, C>(&mut self, image: &ImageBuffer<P, C>) -> ImageResult<()>
+    where P: Pixel + 'static, P::Subpixel: 'static, C: Deref<Target=[P::Subpixel]> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.enumerate_pixels().map(|(x, y, p)| (x, y, *p)))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+    use decoder::{ IDPDecoder, ImageDecoder };
+    use dynimage::DynamicIdpImage;
+    use image::error::{ ImageError, HeaderError };
+    use image::other::{ PixelType, DecodingResult, GrayU16, GrayF32 };
+    use super::{ IDPEncoder, ImageEncoder };
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("idp_roundtrip_{}_{}.idp", name, ::std::process::id()))
+    }
+
+    #[test]
+    fn gray16_image_roundtrip() {
+        let image: Gray16Image = ImageBuffer::from_fn(7, 5, |x, y| GrayU16((x * 9000 + y) as u16 + 10000));
+        let path = temp_path("u16");
+        image.save(&path).unwrap();
+        let bytes = fs::metadata(&path).unwrap().len();
+        let decoded = DynamicIdpImage::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes, 16 + 7 * 5 * 2);
+        match decoded {
+            DynamicIdpImage::U16(decoded) => assert_eq!(&*decoded, &*image),
+            _ => panic!("expected a u16 image"),
+        }
+    }
+
+    #[test]
+    fn gray_float_image_roundtrip() {
+        let image: GrayFloatImage = ImageBuffer::from_fn(3, 4, |x, y| GrayF32(x as f32 * -1.5 + y as f32 * 1e6));
+        let path = temp_path("f32");
+        image.save(&path).unwrap();
+        let decoded = DynamicIdpImage::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(decoded.pixel_type(), PixelType::Float32);
+        assert_eq!(&*decoded.to_f32(), &*image);
+    }
+
+    #[test]
+    fn slice_backed_buffer_roundtrip() {
+        let data = [0u16, 1, 65535, 42, 7, 8];
+        let image: ImageBuffer<GrayU16<u16>, &[u16]> = ImageBuffer::from_raw(3, 2, &data[..]).unwrap();
+        let mut encoder = IDPEncoder::new(Cursor::new(Vec::new()));
+        encoder.encode_buffer(&image).unwrap();
+
+        let bytes = encoder.into_inner().into_inner().into_inner();
+        let mut decoder = IDPDecoder::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (3, 2));
+        match decoder.read_image().unwrap() {
+            DecodingResult::U16(decoded) => assert_eq!(&decoded[..], &data[..]),
+            _ => panic!("expected u16 pixels"),
+        }
+    }
+
+    #[test]
+    fn empty_images_are_refused() {
+        let mut encoder = IDPEncoder::new(Cursor::new(Vec::new()));
+        match encoder.encode(0, 3, &DecodingResult::U16(Vec::new())) {
+            Err(ImageError::HeaderError(HeaderError::InvalidDimensions { width: 0, height: 3, offset: 0 })) => {},
+            other => panic!("expected invalid dimensions, got {:?}", other),
+        }
+        assert!(encoder.encode(2, 0, &DecodingResult::F32(Vec::new())).is_err());
+        assert!(encoder.into_inner().into_inner().into_inner().is_empty());
+    }
+}
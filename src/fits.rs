@@ -0,0 +1,689 @@
+//! FITS primary HDUs holding u16 or f32 gray images.
+//!
+//! u16 images are stored with `BITPIX = 16` and `BZERO = 32768`, f32 images with `BITPIX = -32`,
+//! both big endian as the standard requires. Any other 16 bit or -32 bit image is read as f32
+//! physical values `BZERO + BSCALE * stored`. A third axis holds the frames of a stack.
+//!
+//! The keywords that do not describe the data layout are kept in a `FitsHeader`, so they can be
+//! read back and written again.
+
+use std::io::{ Read, Write, Seek, SeekFrom };
+use std::ops::Deref;
+
+use num::NumCast;
+
+use buffer::ImageBuffer;
+use decoder::{ ImageDecoder, bytes_per_pixel };
+use encoder::ImageEncoder;
+use image::error::{ ImageError, ImageResult };
+use image::other::{ PixelType, DecodingResult };
+use stream::{ ByteOrder, EndianReader, EndianWriter, SmartReader, SmartWriter };
+use traits::{ Pixel, GenericImageView };
+
+
+/// Size of a header or data block
+pub const BLOCK_BYTES: usize = 2880;
+
+/// Size of a header card
+const CARD_BYTES: usize = 80;
+
+/// Most bytes of a string value between its quotes, columns 12 to 79
+const MAX_TEXT_BYTES: usize = 68;
+
+/// Offset of the u16 values stored as i16
+const U16_ZERO: f64 = 32768.0;
+
+/// Keywords that describe the data layout, written by the encoder from the image itself
+const STRUCTURAL: [&'static str; 10] = ["SIMPLE", "BITPIX", "NAXIS", "NAXIS1", "NAXIS2", "NAXIS3",
+                                        "EXTEND", "BZERO", "BSCALE", "END"];
+
+
+fn format_error<T>(msg: String) -> ImageResult<T> {
+    Err(ImageError::FormatError(format!("FITS: {}", msg)))
+}
+
+
+/// Strips the blanks that pad a card or string value
+fn trim_blanks(s: &str) -> &str {
+    &s[..s.rfind(|c| c != ' ').map_or(0, |i| i + 1)]
+}
+
+
+/// The value of a header keyword
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Logical(bool),
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl Value {
+    /// The value as a number, if it is one
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Integer(n) => Some(n as f64),
+            Value::Real(x) => Some(x),
+            _ => None
+        }
+    }
+
+    /// Parses the value field of a card, everything after `= ` up to the comment
+    fn parse(field: &str) -> Option<Value> {
+        let field = field.trim();
+        if field.starts_with('\'') {
+            // Quotes inside the string are doubled, trailing blanks are not significant
+            let text = &field[1..];
+            let end = match text.rfind('\'') {
+                Some(end) => end,
+                None => return None
+            };
+            return Some(Value::Text(trim_blanks(&text[..end].replace("''", "'")).to_string()))
+        }
+        match field {
+            "" => None,
+            "T" => Some(Value::Logical(true)),
+            "F" => Some(Value::Logical(false)),
+            _ => match field.parse::<i64>() {
+                Ok(n) => Some(Value::Integer(n)),
+                Err(_) => field.replace('D', "E").parse::<f64>().ok().map(Value::Real),
+            }
+        }
+    }
+
+    /// Formats the value for columns 11 to 80 of a card.
+    /// Text that does not fit between the quotes is cut off, so the card keeps its closing quote.
+    fn format(&self) -> String {
+        match *self {
+            Value::Logical(b) => format!("{:>20}", if b { "T" } else { "F" }),
+            Value::Integer(n) => format!("{:>20}", n),
+            Value::Real(x) => format!("{:>20}", format!("{:E}", x)),
+            Value::Text(ref s) => {
+                let mut quoted = String::new();
+                for c in s.chars().filter(|c| *c >= ' ' && *c <= '~') {
+                    let escaped = if c == '\'' { 2 } else { 1 };
+                    if quoted.len() + escaped > MAX_TEXT_BYTES {
+                        break
+                    }
+                    quoted.push(c);
+                    if c == '\'' {
+                        quoted.push(c);
+                    }
+                }
+                format!("'{:<8}'", quoted)
+            },
+        }
+    }
+}
+
+
+/// A header keyword with its value and comment. Commentary keywords such as
+/// `COMMENT` and `HISTORY` have no value, only text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keyword {
+    pub name: String,
+    pub value: Option<Value>,
+    pub comment: String,
+}
+
+impl Keyword {
+    /// Parses an 80 byte card
+    fn parse(card: &str) -> ImageResult<Keyword> {
+        let name = trim_blanks(&card[..8]).to_string();
+        if &card[8..10] != "= " {
+            return Ok(Keyword { name: name, value: None, comment: trim_blanks(&card[8..]).to_string() })
+        }
+        let field = &card[10..];
+        // A slash inside a quoted string does not start the comment
+        let value_end = if field.trim().starts_with('\'') {
+            let open = field.find('\'').unwrap();
+            let mut i = open + 1;
+            let bytes = field.as_bytes();
+            while i < bytes.len() {
+                if bytes[i] == b'\'' {
+                    if i + 1 < bytes.len() && bytes[i + 1] == b'\'' {
+                        i += 2;
+                        continue
+                    }
+                    break
+                }
+                i += 1;
+            }
+            field[::std::cmp::min(i + 1, field.len())..].find('/').map_or(field.len(), |j| i + 1 + j)
+        } else {
+            field.find('/').unwrap_or(field.len())
+        };
+        let comment = if value_end < field.len() { field[value_end + 1..].trim() } else { "" };
+        // An empty value field means the value is undefined
+        if field[..value_end].trim().is_empty() {
+            return Ok(Keyword { name: name, value: None, comment: comment.to_string() })
+        }
+        match Value::parse(&field[..value_end]) {
+            Some(value) => Ok(Keyword { name: name, value: Some(value), comment: comment.to_string() }),
+            None => format_error(format!("invalid value in card `{}`", trim_blanks(card))),
+        }
+    }
+
+    /// Formats the keyword as an 80 byte card, cutting off what does not fit
+    fn format(&self) -> String {
+        let mut card = format!("{:<8}", self.name);
+        match self.value {
+            Some(ref value) => {
+                card.push_str("= ");
+                card.push_str(&value.format());
+                if !self.comment.is_empty() {
+                    card.push_str(" / ");
+                    card.push_str(&self.comment);
+                }
+            },
+            None => card.push_str(&self.comment),
+        }
+        let mut card: String = card.chars().filter(|c| *c >= ' ' && *c <= '~').take(CARD_BYTES).collect();
+        while card.len() < CARD_BYTES {
+            card.push(' ');
+        }
+        card
+    }
+}
+
+
+/// The keywords of a FITS header that do not describe the data layout, in file order
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FitsHeader {
+    keywords: Vec<Keyword>,
+}
+
+impl FitsHeader {
+    /// Creates an empty header
+    pub fn new() -> FitsHeader {
+        FitsHeader { keywords: Vec::new() }
+    }
+
+    /// All keywords in file order
+    pub fn keywords(&self) -> &[Keyword] {
+        &self.keywords
+    }
+
+    /// The value of the first keyword called `name`
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.keywords.iter().find(|k| k.name == name).and_then(|k| k.value.as_ref())
+    }
+
+    /// Sets the value of the keyword `name`, appending it if it does not exist yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is longer than 8 characters or describes the data layout, like `BITPIX`.
+    pub fn set(&mut self, name: &str, value: Value, comment: &str) {
+        assert!(name.len() <= 8, "FITS keyword `{}` is longer than 8 characters", name);
+        assert!(!STRUCTURAL.contains(&name), "FITS keyword `{}` is written by the encoder", name);
+        let keyword = Keyword { name: name.to_string(), value: Some(value), comment: comment.to_string() };
+        match self.keywords.iter().position(|k| k.name == name) {
+            Some(i) => self.keywords[i] = keyword,
+            None => self.keywords.push(keyword),
+        }
+    }
+
+    /// Appends a `COMMENT` card
+    pub fn add_comment(&mut self, text: &str) {
+        self.keywords.push(Keyword { name: "COMMENT".to_string(), value: None, comment: text.to_string() });
+    }
+
+    /// Removes all keywords called `name`
+    pub fn remove(&mut self, name: &str) {
+        self.keywords.retain(|k| k.name != name);
+    }
+}
+
+
+/// Decodes the primary HDU of FITS streams.
+///
+/// The decoder is always positioned on one frame of the third axis, starting with the first.
+#[derive(Debug)]
+pub struct FitsDecoder<R> where R: Read + Seek {
+    reader: SmartReader<R>,
+    header: FitsHeader,
+    width: u32,
+    height: u32,
+    frames: usize,
+    frame: usize,
+    bitpix: i64,
+    bzero: f64,
+    bscale: f64,
+    /// Offset of the first data byte
+    data_start: u64,
+}
+
+impl<R: Read + Seek> FitsDecoder<R> {
+    /// Create a new decoder that decodes from the stream ```r```
+    ///
+    /// The file starts at the current position of ```r```.
+    pub fn new(r: R) -> ImageResult<FitsDecoder<R>> {
+        let mut reader = SmartReader::wrap(r, ByteOrder::BigEndian);
+
+        let mut cards = Vec::new();
+        let mut block = [0u8; BLOCK_BYTES];
+        'blocks: loop {
+            try!(reader.read_exact(&mut block));
+            for card in block.chunks(CARD_BYTES) {
+                if card.iter().any(|&b| b < b' ' || b > b'~') {
+                    return format_error("header holds non-ASCII characters".to_string())
+                }
+                let card = String::from_utf8(card.to_vec()).unwrap();
+                if cards.is_empty() && !card.starts_with("SIMPLE  =") {
+                    return format_error("not a FITS file".to_string())
+                }
+                if trim_blanks(&card) == "END" {
+                    break 'blocks
+                }
+                cards.push(try!(Keyword::parse(&card)));
+            }
+        }
+        let data_start = try!(reader.seek(SeekFrom::Current(0)));
+
+        let number = |name: &str| cards.iter().find(|k| k.name == name).and_then(|k| k.value.as_ref())
+                                                .and_then(|v| v.as_f64());
+        let integer = |name: &str| match number(name) {
+            Some(n) if n.fract() == 0.0 && n >= 0.0 && n <= ::std::u32::MAX as f64 => Some(n as u32),
+            _ => None,
+        };
+        let bitpix = number("BITPIX").unwrap_or(0.0) as i64;
+        let (width, height, frames) = match integer("NAXIS") {
+            Some(2) => (integer("NAXIS1"), integer("NAXIS2"), Some(1)),
+            Some(3) => (integer("NAXIS1"), integer("NAXIS2"), integer("NAXIS3")),
+            Some(n) => return format_error(format!("{} axes, expected 2 or 3", n)),
+            None => return format_error("missing NAXIS".to_string()),
+        };
+        let (width, height, frames) = match (width, height, frames) {
+            (Some(w), Some(h), Some(f)) if w > 0 && h > 0 && f > 0 => (w, h, f as usize),
+            _ => return format_error("missing or zero axis length".to_string()),
+        };
+        if bitpix != 16 && bitpix != -32 {
+            return format_error(format!("BITPIX {} is not supported, only 16 and -32", bitpix))
+        }
+
+        let header = FitsHeader {
+            keywords: cards.iter().filter(|k| !STRUCTURAL.contains(&&k.name[..])).cloned().collect(),
+        };
+        let mut decoder = FitsDecoder {
+            reader: reader,
+            header: header,
+            width: width,
+            height: height,
+            frames: frames,
+            frame: 0,
+            bitpix: bitpix,
+            bzero: number("BZERO").unwrap_or(0.0),
+            bscale: number("BSCALE").unwrap_or(1.0),
+            data_start: data_start,
+        };
+        // Checked once here, so that frame_bytes and the frame offsets cannot overflow later
+        let data_bytes = match (width as u64).checked_mul(height as u64)
+                                             .and_then(|n| n.checked_mul(if bitpix == 16 { 2 } else { 4 }))
+                                             .and_then(|n| n.checked_mul(frames as u64)) {
+            Some(n) => n,
+            None => return format_error(format!("{} x {} x {} pixels overflow the data size", width, height, frames)),
+        };
+        let stream_len = try!(decoder.reader.seek(SeekFrom::End(0)));
+        if stream_len - data_start < data_bytes {
+            return format_error(format!("data needs {} bytes, stream has {}", data_bytes, stream_len - data_start))
+        }
+        Ok(decoder)
+    }
+
+    /// The keywords that do not describe the data layout
+    pub fn header(&self) -> &FitsHeader {
+        &self.header
+    }
+
+    /// Number of frames along the third axis, 1 for a 2 dimensional image
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
+    /// Index of the current frame, starting at 0
+    pub fn current_frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Moves the decoder to frame `n`.
+    /// Returns `ImageError::ImageEnd` if the file holds `n` frames or fewer.
+    pub fn seek_frame(&mut self, n: usize) -> ImageResult<()> {
+        if n >= self.frames {
+            return Err(ImageError::ImageEnd)
+        }
+        self.frame = n;
+        Ok(())
+    }
+
+    /// Returns true if the file holds another frame after the current one.
+    pub fn more_images(&self) -> bool {
+        self.frame + 1 < self.frames
+    }
+
+    /// Whether the stored values map exactly to u16
+    fn is_u16(&self) -> bool {
+        self.bitpix == 16 && self.bzero == U16_ZERO && self.bscale == 1.0
+    }
+
+    fn frame_bytes(&self) -> u64 {
+        let bytes = if self.bitpix == 16 { 2 } else { 4 };
+        self.width as u64 * self.height as u64 * bytes
+    }
+}
+
+impl<R: Read + Seek> ImageDecoder for FitsDecoder<R> {
+    fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
+        Ok((self.width, self.height))
+    }
+
+    fn pixel_type(&mut self) -> ImageResult<PixelType> {
+        Ok(if self.is_u16() { PixelType::Short16 } else { PixelType::Float32 })
+    }
+
+    fn read_image(&mut self) -> ImageResult<DecodingResult> {
+        let offset = self.data_start + self.frame as u64 * self.frame_bytes();
+        try!(self.reader.seek(SeekFrom::Start(offset)));
+        let number_of_pixels = self.width as usize * self.height as usize;
+        let (bzero, bscale) = (self.bzero, self.bscale);
+        if self.is_u16() {
+            let mut buffer = Vec::with_capacity(number_of_pixels);
+            for _ in 0..number_of_pixels {
+                buffer.push(try!(self.reader.read_u16()) ^ 0x8000);
+            }
+            return Ok(DecodingResult::U16(buffer))
+        }
+        let mut buffer = Vec::with_capacity(number_of_pixels);
+        for _ in 0..number_of_pixels {
+            let stored = if self.bitpix == 16 {
+                try!(self.reader.read_u16()) as i16 as f64
+            } else {
+                try!(self.reader.read_f32()) as f64
+            };
+            buffer.push(if bzero == 0.0 && bscale == 1.0 { stored } else { bzero + bscale * stored } as f32);
+        }
+        Ok(DecodingResult::F32(buffer))
+    }
+}
+
+
+/// Writes images as the primary HDU of FITS files
+#[derive(Debug)]
+pub struct FitsEncoder<W> where W: Write + Seek {
+    writer: SmartWriter<W>,
+    header: FitsHeader,
+}
+
+impl<W: Write + Seek> FitsEncoder<W> {
+    /// Create a new encoder that writes to the stream ```w```
+    pub fn new(w: W) -> FitsEncoder<W> {
+        FitsEncoder {
+            writer: SmartWriter::wrap(w, ByteOrder::BigEndian),
+            header: FitsHeader::new(),
+        }
+    }
+
+    /// Writes the keywords of `header` after the ones describing the data
+    pub fn with_header(mut self, header: FitsHeader) -> FitsEncoder<W> {
+        self.header = header;
+        self
+    }
+
+    /// Returns the wrapped writer
+    pub fn into_inner(self) -> SmartWriter<W> {
+        self.writer
+    }
+
+    /// Writes a header for `frames` frames of `width` x `height` pixels of `pixel_type`
+    fn write_header(&mut self, width: u32, height: u32, frames: usize, pixel_type: PixelType) -> ImageResult<()> {
+        let structural = |name: &str, value: Value, comment: &str| {
+            Keyword { name: name.to_string(), value: Some(value), comment: comment.to_string() }
+        };
+        let mut cards = vec![
+            structural("SIMPLE", Value::Logical(true), "conforms to FITS standard"),
+            structural("BITPIX", Value::Integer(match pixel_type { PixelType::Short16 => 16, PixelType::Float32 => -32 }), ""),
+            structural("NAXIS", Value::Integer(if frames == 1 { 2 } else { 3 }), ""),
+            structural("NAXIS1", Value::Integer(width as i64), "width"),
+            structural("NAXIS2", Value::Integer(height as i64), "height"),
+        ];
+        if frames != 1 {
+            cards.push(structural("NAXIS3", Value::Integer(frames as i64), "frames"));
+        }
+        if pixel_type == PixelType::Short16 {
+            cards.push(structural("BZERO", Value::Integer(U16_ZERO as i64), "stored i16 + 32768 is u16"));
+            cards.push(structural("BSCALE", Value::Integer(1), ""));
+        }
+        cards.extend(self.header.keywords.iter().cloned());
+        cards.push(Keyword { name: "END".to_string(), value: None, comment: String::new() });
+
+        let mut text = String::with_capacity(cards.len() * CARD_BYTES);
+        for card in &cards {
+            text.push_str(&card.format());
+        }
+        while text.len() % BLOCK_BYTES != 0 {
+            text.push(' ');
+        }
+        try!(self.writer.write_all(text.as_bytes()));
+        Ok(())
+    }
+
+    /// Pads the data written so far, `bytes` long, to a whole block
+    fn finish(&mut self, bytes: u64) -> ImageResult<()> {
+        let rest = (BLOCK_BYTES as u64 - bytes % BLOCK_BYTES as u64) % BLOCK_BYTES as u64;
+        try!(self.writer.write_all(&vec![0u8; rest as usize]));
+        try!(self.writer.flush());
+        Ok(())
+    }
+
+    fn write_data(&mut self, data: &DecodingResult) -> ImageResult<u64> {
+        match *data {
+            DecodingResult::U16(ref buffer) => {
+                for &datum in buffer {
+                    try!(self.writer.write_u16(datum ^ 0x8000));
+                }
+                Ok(buffer.len() as u64 * 2)
+            },
+            DecodingResult::F32(ref buffer) => {
+                for &datum in buffer {
+                    try!(self.writer.write_f32(datum));
+                }
+                Ok(buffer.len() as u64 * 4)
+            },
+        }
+    }
+
+    /// Writes `frames`, which must all have `width` x `height` pixels of one type, as a 3 dimensional image
+    pub fn encode_frames(&mut self, width: u32, height: u32, frames: &[DecodingResult]) -> ImageResult<()> {
+        let frames: Vec<&DecodingResult> = frames.iter().collect();
+        self.write_frames(width, height, &frames)
+    }
+
+    fn write_frames(&mut self, width: u32, height: u32, frames: &[&DecodingResult]) -> ImageResult<()> {
+        if width == 0 || height == 0 {
+            return format_error("images cannot be empty".to_string())
+        }
+        let number_of_pixels = width as usize * height as usize;
+        let pixel_type = match frames.first() {
+            Some(&&DecodingResult::U16(_)) => PixelType::Short16,
+            Some(&&DecodingResult::F32(_)) => PixelType::Float32,
+            None => return format_error("no frames to write".to_string()),
+        };
+        for frame in frames {
+            let (frame_type, len) = match *frame {
+                DecodingResult::U16(ref buffer) => (PixelType::Short16, buffer.len()),
+                DecodingResult::F32(ref buffer) => (PixelType::Float32, buffer.len()),
+            };
+            if frame_type != pixel_type || len != number_of_pixels {
+                return format_error(format!("every frame needs {} {:?} pixels", number_of_pixels, pixel_type))
+            }
+        }
+        try!(self.write_header(width, height, frames.len(), pixel_type));
+        let mut bytes = 0;
+        for frame in frames {
+            bytes += try!(self.write_data(frame));
+        }
+        debug_assert_eq!(bytes, number_of_pixels as u64 * frames.len() as u64 * bytes_per_pixel(pixel_type));
+        self.finish(bytes)
+    }
+
+    /// Collects the pixels of any image, in row major order, and writes them
+    fn write_pixels<P, It>(&mut self, width: u32, height: u32, pixels: It) -> ImageResult<()>
+    where P: Pixel, It: Iterator<Item=(u32, u32, P)> {
+        let data = match <P as Pixel>::pixel_type() {
+            PixelType::Short16 => DecodingResult::U16(pixels.map(|(_, _, p)| NumCast::from(*p.value()).unwrap()).collect()),
+            PixelType::Float32 => DecodingResult::F32(pixels.map(|(_, _, p)| NumCast::from(*p.value()).unwrap()).collect()),
+        };
+        self.encode(width, height, &data)
+    }
+}
+
+impl<W: Write + Seek> ImageEncoder for FitsEncoder<W> {
+    fn encode(&mut self, width: u32, height: u32, image: &DecodingResult) -> ImageResult<()> {
+        self.write_frames(width, height, &[image])
+    }
+
+    fn encode_image<I: GenericImageView>(&mut self, image: &I) -> ImageResult<()> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.pixels())
+    }
+
+    fn encode_buffer<P, C>(&mut self, image: &ImageBuffer<P, C>) -> ImageResult<()>
+    where P: Pixel + 'static, P::Subpixel: 'static, C: Deref<Target=[P::Subpixel]> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.enumerate_pixels().map(|(x, y, p)| (x, y, *p)))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+    use decoder::ImageDecoder;
+    use dynimage::DynamicIdpImage;
+    use encoder::ImageEncoder;
+    use image::other::{ PixelType, DecodingResult, GrayU16, GrayF32 };
+    use super::{ FitsDecoder, FitsEncoder, FitsHeader, Value, BLOCK_BYTES };
+
+    #[test]
+    fn u16_roundtrip_with_keywords() {
+        let image: Gray16Image = ImageBuffer::from_fn(40, 30, |x, y| GrayU16((x * 1600 + y) as u16));
+        let mut header = FitsHeader::new();
+        header.set("EXPTIME", Value::Real(0.25), "seconds");
+        header.set("OBJECT", Value::Text("it's / dark".to_string()), "");
+        header.set("FRAMENO", Value::Integer(-7), "");
+        header.set("COOLED", Value::Logical(true), "");
+        header.add_comment("written by a test");
+
+        let mut encoder = FitsEncoder::new(Cursor::new(Vec::new())).with_header(header.clone());
+        encoder.encode_image(&image).unwrap();
+        let bytes = encoder.into_inner().into_inner().into_inner();
+        assert_eq!(bytes.len(), 2 * BLOCK_BYTES);
+        // 0 is stored as -32768
+        assert_eq!(&bytes[BLOCK_BYTES..BLOCK_BYTES + 2], &[0x80, 0x00]);
+
+        let mut decoder = FitsDecoder::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(decoder.header(), &header);
+        assert_eq!(decoder.pixel_type().unwrap(), PixelType::Short16);
+        match DynamicIdpImage::from_decoder(&mut decoder).unwrap() {
+            DynamicIdpImage::U16(decoded) => assert_eq!(&*decoded, &*image),
+            _ => panic!("expected a u16 image"),
+        }
+    }
+
+    #[test]
+    fn float_frames() {
+        let frames: Vec<DecodingResult> = (0..3).map(|i| DecodingResult::F32(vec![i as f32, -0.5, 1e30, 7.0])).collect();
+        let mut encoder = FitsEncoder::new(Cursor::new(Vec::new()));
+        encoder.encode_frames(2, 2, &frames).unwrap();
+        let bytes = encoder.into_inner().into_inner().into_inner();
+
+        let mut decoder = FitsDecoder::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(decoder.frame_count(), 3);
+        decoder.seek_frame(2).unwrap();
+        assert!(!decoder.more_images());
+        match decoder.read_image().unwrap() {
+            DecodingResult::F32(pixels) => assert_eq!(pixels, vec![2.0, -0.5, 1e30, 7.0]),
+            _ => panic!("expected f32 pixels"),
+        }
+        assert!(decoder.seek_frame(3).is_err());
+
+        let image: GrayFloatImage = ImageBuffer::from_pixel(1, 1, GrayF32(1.0));
+        assert!(FitsEncoder::new(Cursor::new(Vec::new())).encode_frames(1, 1, &[]).is_err());
+        let empty = [DecodingResult::F32(Vec::new()), DecodingResult::F32(Vec::new())];
+        assert!(FitsEncoder::new(Cursor::new(Vec::new())).encode_frames(0, 4, &empty).is_err());
+        let mut encoder = FitsEncoder::new(Cursor::new(Vec::new()));
+        encoder.encode_image(&image).unwrap();
+        let bytes = encoder.into_inner().into_inner().into_inner();
+        assert_eq!(&bytes[BLOCK_BYTES..BLOCK_BYTES + 4], &[0x3f, 0x80, 0, 0]);
+        assert!(FitsDecoder::new(Cursor::new(b"SIMPLE  = F".to_vec())).is_err());
+    }
+
+    /// A header block holding `cards`
+    fn header_block(cards: &[&str]) -> Vec<u8> {
+        let mut text = String::new();
+        for card in cards {
+            text.push_str(&format!("{:<80}", card));
+        }
+        format!("{:<2880}", text).into_bytes()
+    }
+
+    #[test]
+    fn scaled_values_become_floats() {
+        let mut bytes = header_block(&["SIMPLE  =                    T", "BITPIX  =                   16",
+                                       "NAXIS   =                    2", "NAXIS1  =                    2",
+                                       "NAXIS2  =                    1", "BZERO   =                 1.5D0",
+                                       "BSCALE  =                    2 / gain", "END"]);
+        bytes.extend_from_slice(&[0xff, 0xfe, 0x00, 0x03]);
+        bytes.resize(2 * BLOCK_BYTES, 0);
+
+        let mut decoder = FitsDecoder::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(decoder.pixel_type().unwrap(), PixelType::Float32);
+        assert!(decoder.header().keywords().is_empty());
+        match decoder.read_image().unwrap() {
+            DecodingResult::F32(pixels) => assert_eq!(pixels, vec![1.5 - 4.0, 1.5 + 6.0]),
+            _ => panic!("expected f32 pixels"),
+        }
+    }
+
+    #[test]
+    fn long_text_is_cut_off_inside_the_quotes() {
+        let long: String = ::std::iter::repeat("it's ").take(20).collect();
+        let mut header = FitsHeader::new();
+        header.set("OBJECT", Value::Text(long.clone()), "cut off");
+        let mut encoder = FitsEncoder::new(Cursor::new(Vec::new())).with_header(header);
+        encoder.encode_image(&GrayFloatImage::new(1, 1)).unwrap();
+        let bytes = encoder.into_inner().into_inner().into_inner();
+
+        let decoder = FitsDecoder::new(Cursor::new(bytes)).unwrap();
+        let object = decoder.header().keywords().iter().find(|k| k.name == "OBJECT").unwrap();
+        match object.value {
+            // 57 characters and 11 doubled quotes fill the 68 bytes between the quotes
+            Some(Value::Text(ref text)) => assert_eq!(&text[..], &long[..57]),
+            ref other => panic!("expected text, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_value_is_undefined() {
+        let mut bytes = header_block(&["SIMPLE  =                    T", "BITPIX  =                  -32",
+                                       "NAXIS   =                    2", "NAXIS1  =                    1",
+                                       "NAXIS2  =                    1", "OBSERVER=", "FILTER  =      / none used", "END"]);
+        bytes.resize(2 * BLOCK_BYTES, 0);
+        let decoder = FitsDecoder::new(Cursor::new(bytes)).unwrap();
+        let keywords = decoder.header().keywords();
+        assert_eq!(keywords.len(), 2);
+        assert_eq!((&keywords[0].name[..], &keywords[0].value), ("OBSERVER", &None));
+        assert_eq!((&keywords[1].value, &keywords[1].comment[..]), (&None, "none used"));
+    }
+
+    #[test]
+    fn huge_axes_are_an_error() {
+        let mut bytes = header_block(&["SIMPLE  =                    T", "BITPIX  =                  -32",
+                                       "NAXIS   =                    3", "NAXIS1  =           4294967295",
+                                       "NAXIS2  =           4294967295", "NAXIS3  =           4294967295", "END"]);
+        bytes.resize(2 * BLOCK_BYTES, 0);
+        assert!(FitsDecoder::new(Cursor::new(bytes)).is_err());
+    }
+}
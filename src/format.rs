@@ -0,0 +1,339 @@
+//! Recognizes the format of image files and dispatches to its decoder and encoder.
+//!
+//! A file is recognized by its first bytes, or by its extension if they match no format.
+//! Files are saved in the format given by the extension of their path, IDP if it names none.
+
+use std::fs::File;
+use std::io::{ BufReader, BufWriter, Read, Seek, SeekFrom };
+use std::path::Path;
+
+use decoder::{ IDPDecoder, ImageDecoder, HEADER_BYTES };
+use dynimage::DynamicIdpImage;
+use encoder::{ IDPEncoder, ImageEncoder };
+use fits::{ FitsDecoder, FitsEncoder };
+use npy::{ self, NpyDecoder, NpyEncoder };
+use image::error::{ ImageError, ImageResult };
+use image::other::{ PixelType, DecodingResult };
+use png::PngEncoder;
+use tiff::{ TiffDecoder, TiffEncoder };
+use traits::GenericImageView;
+
+
+/// The image file formats this crate knows about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Idp,
+    Tiff,
+    Fits,
+    Png,
+    Npy,
+}
+
+impl ImageFormat {
+    /// Looks a format up by a file extension, ignoring case
+    pub fn from_extension(extension: &str) -> Option<ImageFormat> {
+        match &extension.to_lowercase()[..] {
+            "idp" => Some(ImageFormat::Idp),
+            "tif" | "tiff" => Some(ImageFormat::Tiff),
+            "fits" | "fit" | "fts" => Some(ImageFormat::Fits),
+            "png" => Some(ImageFormat::Png),
+            "npy" => Some(ImageFormat::Npy),
+            _ => None
+        }
+    }
+
+    /// Looks a format up by the extension of `path`
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<ImageFormat> {
+        path.as_ref().extension().and_then(|e| e.to_str()).and_then(ImageFormat::from_extension)
+    }
+
+    /// Recognizes a format by the first bytes of a file. At most `MAGIC_BYTES` are looked at.
+    ///
+    /// IDP files have no signature; they are recognized by a zero first word, a known pixel code
+    /// and a non-empty geometry.
+    pub fn from_magic(bytes: &[u8]) -> Option<ImageFormat> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if bytes.starts_with(b"II*\0") || bytes.starts_with(b"MM\0*") {
+            Some(ImageFormat::Tiff)
+        } else if bytes.starts_with(b"SIMPLE  =") {
+            Some(ImageFormat::Fits)
+        } else if bytes.starts_with(npy::MAGIC) {
+            Some(ImageFormat::Npy)
+        } else if bytes.len() >= HEADER_BYTES as usize
+                  && bytes[..4] == [0, 0, 0, 0]
+                  && (bytes[4] == 0 || bytes[4] == 2) && bytes[5..8] == [0, 0, 0]
+                  && bytes[8..12] != [0, 0, 0, 0] && bytes[12..16] != [0, 0, 0, 0] {
+            Some(ImageFormat::Idp)
+        } else {
+            None
+        }
+    }
+
+    /// The name of the format
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ImageFormat::Idp => "IDP",
+            ImageFormat::Tiff => "TIFF",
+            ImageFormat::Fits => "FITS",
+            ImageFormat::Png => "PNG",
+            ImageFormat::Npy => "NumPy",
+        }
+    }
+}
+
+/// Number of bytes `ImageFormat::from_magic` needs to recognize any format
+pub const MAGIC_BYTES: usize = 16;
+
+
+/// Recognizes the format of the stream `r` by its first bytes, see `ImageFormat::from_magic`.
+/// The stream is left where it was.
+pub fn guess_format<R: Read + Seek>(r: &mut R) -> ImageResult<Option<ImageFormat>> {
+    let start = try!(r.seek(SeekFrom::Current(0)));
+    let mut magic = Vec::with_capacity(MAGIC_BYTES);
+    try!(r.by_ref().take(MAGIC_BYTES as u64).read_to_end(&mut magic));
+    try!(r.seek(SeekFrom::Start(start)));
+    Ok(ImageFormat::from_magic(&magic))
+}
+
+
+/// A decoder for any format that can be read, picked at runtime
+pub enum DynamicDecoder<R> where R: Read + Seek {
+    Idp(IDPDecoder<R>),
+    Tiff(TiffDecoder<R>),
+    Fits(FitsDecoder<R>),
+    Npy(NpyDecoder<R>),
+}
+
+/// Evaluates `$action` with `$decoder` bound to whichever decoder `$dyndecoder` holds
+macro_rules! decoder_map {
+    ($dyndecoder: expr, ref mut $decoder: ident => $action: expr) => (
+        match $dyndecoder {
+            DynamicDecoder::Idp(ref mut $decoder) => $action,
+            DynamicDecoder::Tiff(ref mut $decoder) => $action,
+            DynamicDecoder::Fits(ref mut $decoder) => $action,
+            DynamicDecoder::Npy(ref mut $decoder) => $action,
+        }
+    );
+}
+
+impl<R: Read + Seek> DynamicDecoder<R> {
+    /// Creates a decoder for `format` that decodes from the stream ```r```.
+    /// Returns an error if the format cannot be read.
+    pub fn new(r: R, format: ImageFormat) -> ImageResult<DynamicDecoder<R>> {
+        match format {
+            ImageFormat::Idp => IDPDecoder::new(r).map(DynamicDecoder::Idp),
+            ImageFormat::Tiff => TiffDecoder::new(r).map(DynamicDecoder::Tiff),
+            ImageFormat::Fits => FitsDecoder::new(r).map(DynamicDecoder::Fits),
+            ImageFormat::Npy => NpyDecoder::new(r).map(DynamicDecoder::Npy),
+            ImageFormat::Png => Err(ImageError::FormatError(
+                format!("{} files cannot be read", format.name())
+            )),
+        }
+    }
+
+    /// Creates a decoder for the format of the stream ```r```, see `guess_format`.
+    /// `fallback` is used if the first bytes match no format.
+    pub fn guess(mut r: R, fallback: Option<ImageFormat>) -> ImageResult<DynamicDecoder<R>> {
+        match try!(guess_format(&mut r)).or(fallback) {
+            Some(format) => DynamicDecoder::new(r, format),
+            None => Err(ImageError::FormatError("unrecognized image format".to_string())),
+        }
+    }
+
+    /// The format being decoded
+    pub fn format(&self) -> ImageFormat {
+        match *self {
+            DynamicDecoder::Idp(_) => ImageFormat::Idp,
+            DynamicDecoder::Tiff(_) => ImageFormat::Tiff,
+            DynamicDecoder::Fits(_) => ImageFormat::Fits,
+            DynamicDecoder::Npy(_) => ImageFormat::Npy,
+        }
+    }
+
+    /// Returns true if the stream holds another frame after the current one.
+    pub fn more_images(&self) -> bool {
+        match *self {
+            DynamicDecoder::Idp(ref decoder) => decoder.more_images(),
+            DynamicDecoder::Tiff(ref decoder) => decoder.more_images(),
+            DynamicDecoder::Fits(ref decoder) => decoder.more_images(),
+            DynamicDecoder::Npy(ref decoder) => decoder.more_images(),
+        }
+    }
+
+    /// Moves to the next frame.
+    /// Returns `ImageError::ImageEnd` if the current frame is the last one.
+    pub fn next_image(self) -> ImageResult<DynamicDecoder<R>> {
+        match self {
+            DynamicDecoder::Idp(decoder) => decoder.next_image().map(DynamicDecoder::Idp),
+            DynamicDecoder::Tiff(decoder) => decoder.next_image().map(DynamicDecoder::Tiff),
+            DynamicDecoder::Fits(mut decoder) => {
+                let next = decoder.current_frame() + 1;
+                try!(decoder.seek_frame(next));
+                Ok(DynamicDecoder::Fits(decoder))
+            },
+            DynamicDecoder::Npy(mut decoder) => {
+                let next = decoder.current_frame() + 1;
+                try!(decoder.seek_frame(next));
+                Ok(DynamicDecoder::Npy(decoder))
+            }
+        }
+    }
+
+    /// Decodes the current frame and every frame after it
+    pub fn read_frames(self) -> ImageResult<Vec<DynamicIdpImage>> {
+        let mut decoder = self;
+        let mut frames = vec![try!(DynamicIdpImage::from_decoder(&mut decoder))];
+        while decoder.more_images() {
+            decoder = try!(decoder.next_image());
+            frames.push(try!(DynamicIdpImage::from_decoder(&mut decoder)));
+        }
+        Ok(frames)
+    }
+}
+
+impl<R: Read + Seek> ImageDecoder for DynamicDecoder<R> {
+    fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
+        decoder_map!(*self, ref mut decoder => decoder.dimensions())
+    }
+
+    fn pixel_type(&mut self) -> ImageResult<PixelType> {
+        decoder_map!(*self, ref mut decoder => decoder.pixel_type())
+    }
+
+    fn read_image(&mut self) -> ImageResult<DecodingResult> {
+        decoder_map!(*self, ref mut decoder => decoder.read_image())
+    }
+}
+
+
+/// Opens the file at `path` and creates a decoder for its format.
+/// The extension of `path` is used if the first bytes of the file match no format.
+pub fn open_decoder<P: AsRef<Path>>(path: P) -> ImageResult<DynamicDecoder<BufReader<File>>> {
+    let f = try!(File::open(path.as_ref()));
+    DynamicDecoder::guess(BufReader::new(f), ImageFormat::from_path(path))
+}
+
+/// Opens the image file at `path` and decodes its first frame
+pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<DynamicIdpImage> {
+    let mut decoder = try!(open_decoder(path));
+    DynamicIdpImage::from_decoder(&mut decoder)
+}
+
+/// Opens the image file at `path` and decodes all of its frames
+pub fn open_frames<P: AsRef<Path>>(path: P) -> ImageResult<Vec<DynamicIdpImage>> {
+    try!(open_decoder(path)).read_frames()
+}
+
+
+/// Returns the format a file at `path` is saved in
+fn output_format(path: &Path) -> ImageFormat {
+    ImageFormat::from_path(path).unwrap_or(ImageFormat::Idp)
+}
+
+/// Saves `image` to a file at `path` in the format given by its extension, IDP if it names none.
+/// The pixel type of the image is kept; PNG files take only u16 pixels.
+pub fn save<I: GenericImageView, Q: AsRef<Path>>(image: &I, path: Q) -> ImageResult<()> {
+    let format = output_format(path.as_ref());
+    let w = BufWriter::new(try!(File::create(path)));
+    match format {
+        ImageFormat::Idp => IDPEncoder::new(w).encode_image(image),
+        ImageFormat::Tiff => TiffEncoder::new(w).encode_image(image),
+        ImageFormat::Fits => FitsEncoder::new(w).encode_image(image),
+        ImageFormat::Png => PngEncoder::new(w).encode_image(image),
+        ImageFormat::Npy => NpyEncoder::new(w).encode_image(image),
+    }
+}
+
+/// Saves `frames` of `width` x `height` pixels to a file at `path`, see `save`.
+/// Only IDP, FITS and NumPy files hold more than one frame.
+/// NumPy files always get an array of shape (frames, height, width), even for one frame,
+/// while FITS files get a third axis only for several frames; use `save` for a single 2 dimensional image.
+pub fn save_frames<Q: AsRef<Path>>(path: Q, width: u32, height: u32, frames: &[DecodingResult]) -> ImageResult<()> {
+    let format = output_format(path.as_ref());
+    if frames.is_empty() {
+        return Err(ImageError::FormatError("no frames to save".to_string()))
+    }
+    if frames.len() > 1 && (format == ImageFormat::Tiff || format == ImageFormat::Png) {
+        return Err(ImageError::FormatError(
+            format!("{} files hold a single frame, got {}", format.name(), frames.len())
+        ))
+    }
+    let w = BufWriter::new(try!(File::create(path)));
+    match format {
+        ImageFormat::Idp => {
+            let mut encoder = IDPEncoder::new(w);
+            for frame in frames {
+                try!(encoder.encode(width, height, frame));
+            }
+            Ok(())
+        },
+        ImageFormat::Fits => FitsEncoder::new(w).encode_frames(width, height, frames),
+        ImageFormat::Tiff => TiffEncoder::new(w).encode(width, height, &frames[0]),
+        ImageFormat::Npy => NpyEncoder::new(w).encode_frames(width, height, frames),
+        ImageFormat::Png => PngEncoder::new(w).encode(width, height, &frames[0]),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{ self, File };
+    use std::io::Read;
+
+    use buffer::{ ImageBuffer, Gray16Image };
+    use image::other::{ GrayU16, DecodingResult };
+    use super::{ ImageFormat, open, open_frames, save, save_frames };
+
+    #[test]
+    fn magic_and_extensions() {
+        assert_eq!(ImageFormat::from_magic(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::from_magic(b"MM\0*\0\0\0\x08"), Some(ImageFormat::Tiff));
+        assert_eq!(ImageFormat::from_magic(b"SIMPLE  =                    T"), Some(ImageFormat::Fits));
+        assert_eq!(ImageFormat::from_magic(b"\x93NUMPY\x01\0v\0"), Some(ImageFormat::Npy));
+        assert_eq!(ImageFormat::from_magic(&[0, 0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 3, 0, 0, 0]), Some(ImageFormat::Idp));
+        assert_eq!(ImageFormat::from_magic(&[0, 0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 3, 0, 0, 0]), None);
+        assert_eq!(ImageFormat::from_magic(&[0, 0, 0, 0]), None);
+        assert_eq!(ImageFormat::from_path("dark.FITS"), Some(ImageFormat::Fits));
+        assert_eq!(ImageFormat::from_path("flat.tif"), Some(ImageFormat::Tiff));
+        assert_eq!(ImageFormat::from_path("frame.dat"), None);
+    }
+
+    #[test]
+    fn save_and_open_every_format() {
+        let image: Gray16Image = ImageBuffer::from_fn(5, 3, |x, y| GrayU16((x * 1000 + y) as u16));
+        for extension in ["idp", "tif", "fits", "npy"].iter() {
+            let path = env::temp_dir().join(format!("idp_format_{}.{}", ::std::process::id(), extension));
+            save(&image, &path).unwrap();
+            assert_eq!(&*open(&path).unwrap().to_u16(), &*image);
+
+            // The first bytes decide, whatever the extension says
+            let renamed = path.with_extension("dat");
+            fs::rename(&path, &renamed).unwrap();
+            assert_eq!(&*open(&renamed).unwrap().to_u16(), &*image);
+            fs::remove_file(&renamed).unwrap();
+        }
+
+        let frames = vec![DecodingResult::U16(vec![1, 2]), DecodingResult::U16(vec![3, 4])];
+        for extension in ["fits", "npy"].iter() {
+            let path = env::temp_dir().join(format!("idp_format_{}.{}", ::std::process::id(), extension));
+            save_frames(&path, 2, 1, &frames).unwrap();
+            let opened = open_frames(&path).unwrap();
+            assert_eq!(opened.len(), 2);
+            assert_eq!(&*opened[1].to_u16(), &[3, 4]);
+            fs::remove_file(&path).unwrap();
+        }
+        let path = env::temp_dir().join(format!("idp_format_{}.tif", ::std::process::id()));
+        assert!(save_frames(&path, 2, 1, &frames).is_err());
+
+        // One frame still makes a stack of shape (1, height, width)
+        let path = path.with_extension("npy");
+        save_frames(&path, 2, 1, &frames[..1]).unwrap();
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        assert!(String::from_utf8_lossy(&bytes).contains("'shape': (1, 1, 2)"));
+        assert!(save_frames(&path, 0, 1, &[DecodingResult::U16(Vec::new())]).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
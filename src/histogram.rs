@@ -0,0 +1,273 @@
+//! Histograms of pixel values
+
+use std::io::{ self, Write };
+
+use image::other::GrayU16;
+use mask::{ PixelMask, is_masked, assert_fits, unmasked_values };
+use traits::GenericImageView;
+
+
+/// Number of bins of an exact u16 histogram, one per value
+pub const U16_BINS: usize = 65536;
+
+/// Number of bins over the range of the values when no binning is given for float pixels
+pub const DEFAULT_BINS: usize = 256;
+
+
+/// How `histogram` divides the range of values into bins
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Binning {
+    /// Number of bins of equal width
+    pub bins: usize,
+    /// Lower and upper limit of the histogram. If None the range spans the smallest
+    /// to the largest valid value.
+    pub range: Option<(f64, f64)>,
+}
+
+impl Binning {
+    /// `bins` bins over the range of the values
+    pub fn auto(bins: usize) -> Binning {
+        Binning { bins: bins, range: None }
+    }
+
+    /// `bins` bins from `min` to `max`
+    pub fn fixed(bins: usize, min: f64, max: f64) -> Binning {
+        Binning { bins: bins, range: Some((min, max)) }
+    }
+}
+
+
+/// Counts of values in bins of equal width.
+///
+/// Bin `i` holds the values from `min + i * width` up to, but excluding, the lower edge of the
+/// next bin; the last bin also holds `max`. Values outside the range are counted separately,
+/// NaN values are not counted at all.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    min: f64,
+    max: f64,
+    counts: Vec<u64>,
+    below: u64,
+    above: u64,
+}
+
+impl Histogram {
+    /// Creates an empty histogram of `bins` bins from `min` to `max`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bins` is 0 or the range is empty or not finite.
+    pub fn new(bins: usize, min: f64, max: f64) -> Histogram {
+        assert!(bins > 0, "a histogram needs at least one bin");
+        assert!(min < max && min.is_finite() && max.is_finite(), "invalid histogram range {} to {}", min, max);
+        Histogram {
+            min: min,
+            max: max,
+            counts: vec![0; bins],
+            below: 0,
+            above: 0,
+        }
+    }
+
+    /// Returns the bin that holds `value`, or None if it is outside the range or NaN
+    #[inline(always)]
+    pub fn bin_of(&self, value: f64) -> Option<usize> {
+        if !(value >= self.min && value <= self.max) {
+            return None
+        }
+        let bin = ((value - self.min) / self.bin_width()) as usize;
+        Some(::std::cmp::min(bin, self.counts.len() - 1))
+    }
+
+    /// Counts `value`
+    #[inline(always)]
+    pub fn add(&mut self, value: f64) {
+        match self.bin_of(value) {
+            Some(bin) => self.counts[bin] += 1,
+            None if value < self.min => self.below += 1,
+            None if value > self.max => self.above += 1,
+            None => {},
+        }
+    }
+
+    /// Number of bins
+    pub fn bins(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// The lower and upper limit of the histogram
+    pub fn range(&self) -> (f64, f64) {
+        (self.min, self.max)
+    }
+
+    /// The width of every bin
+    pub fn bin_width(&self) -> f64 {
+        (self.max - self.min) / self.counts.len() as f64
+    }
+
+    /// The lower and upper edge of bin `i`
+    pub fn bin_edges(&self, i: usize) -> (f64, f64) {
+        let width = self.bin_width();
+        (self.min + i as f64 * width, self.min + (i + 1) as f64 * width)
+    }
+
+    /// The center of bin `i`
+    pub fn bin_center(&self, i: usize) -> f64 {
+        let (low, high) = self.bin_edges(i);
+        (low + high) / 2.0
+    }
+
+    /// The count of every bin
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Number of values below the range
+    pub fn below(&self) -> u64 {
+        self.below
+    }
+
+    /// Number of values above the range
+    pub fn above(&self) -> u64 {
+        self.above
+    }
+
+    /// Number of values in the bins
+    pub fn total(&self) -> u64 {
+        self.counts.iter().fold(0, |acc, &n| acc + n)
+    }
+
+    /// The running sum of the counts, so entry `i` is the number of values up to bin `i`
+    pub fn cumulative(&self) -> Vec<u64> {
+        self.counts.iter().scan(0, |acc, &n| { *acc += n; Some(*acc) }).collect()
+    }
+
+    /// The index of the fullest bin, the first one if several have the same count.
+    /// Returns None if the histogram is empty.
+    pub fn mode_bin(&self) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for (i, &n) in self.counts.iter().enumerate() {
+            if n > 0 && best.map_or(true, |b| n > self.counts[b]) {
+                best = Some(i);
+            }
+        }
+        best
+    }
+
+    /// The lower edge of the fullest bin, which is the most frequent value of an exact u16 histogram
+    pub fn mode(&self) -> Option<f64> {
+        self.mode_bin().map(|i| self.bin_edges(i).0)
+    }
+
+    /// Writes one line per bin with its edges, count and cumulative count
+    pub fn write_csv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(writeln!(w, "lower,upper,count,cumulative"));
+        for (i, (&n, c)) in self.counts.iter().zip(self.cumulative()).enumerate() {
+            let (low, high) = self.bin_edges(i);
+            try!(writeln!(w, "{},{},{},{}", low, high, n, c));
+        }
+        Ok(())
+    }
+
+    /// Writes the histogram as a JSON object
+    pub fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let edges: Vec<String> = (0..self.bins() + 1).map(|i| format!("{}", self.min + i as f64 * self.bin_width())).collect();
+        let counts: Vec<String> = self.counts.iter().map(|n| n.to_string()).collect();
+        let cumulative: Vec<String> = self.cumulative().iter().map(|n| n.to_string()).collect();
+        try!(writeln!(w, "{{"));
+        try!(writeln!(w, "  \"min\": {},", self.min));
+        try!(writeln!(w, "  \"max\": {},", self.max));
+        try!(writeln!(w, "  \"below\": {},", self.below));
+        try!(writeln!(w, "  \"above\": {},", self.above));
+        try!(writeln!(w, "  \"edges\": [{}],", edges.join(", ")));
+        try!(writeln!(w, "  \"counts\": [{}],", counts.join(", ")));
+        try!(writeln!(w, "  \"cumulative\": [{}]", cumulative.join(", ")));
+        writeln!(w, "}}")
+    }
+}
+
+
+/// Computes the histogram of `image` with `binning`, skipping pixels masked by `mask`.
+///
+/// An automatic range spans the smallest to the largest finite value, widened by one
+/// if they are equal, and is 0 to 1 if no value is left.
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size, or if `binning` is invalid, see `Histogram::new`.
+pub fn histogram<I: GenericImageView>(image: &I, mask: Option<&PixelMask>, binning: Binning) -> Histogram {
+    let values = unmasked_values(image, mask);
+    let (min, max) = match binning.range {
+        Some(range) => range,
+        None => {
+            let finite = values.iter().cloned().filter(|v| v.is_finite());
+            let (min, max) = finite.fold((::std::f64::INFINITY, ::std::f64::NEG_INFINITY),
+                                         |(lo, hi), v| (lo.min(v), hi.max(v)));
+            if min > max { (0.0, 1.0) } else if min == max { (min, min + 1.0) } else { (min, max) }
+        }
+    };
+    let mut histogram = Histogram::new(binning.bins, min, max);
+    for v in values {
+        histogram.add(v);
+    }
+    histogram
+}
+
+/// Computes the exact histogram of a u16 image with one bin per value, skipping pixels masked by `mask`.
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size.
+pub fn gray16_histogram<I>(image: &I, mask: Option<&PixelMask>) -> Histogram
+where I: GenericImageView<Pixel=GrayU16<u16>> {
+    assert_fits(mask, image);
+    let mut histogram = Histogram::new(U16_BINS, 0.0, U16_BINS as f64);
+    for (x, y, p) in image.pixels() {
+        if !is_masked(mask, x, y) {
+            histogram.counts[p.data as usize] += 1;
+        }
+    }
+    histogram
+}
+
+#[cfg(test)]
+mod test {
+    use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+    use image::other::{ GrayU16, GrayF32 };
+    use mask::PixelMask;
+    use traits::GenericImageView;
+    use super::{ histogram, gray16_histogram, Binning };
+
+    #[test]
+    fn exact_u16_histogram() {
+        let image: Gray16Image = ImageBuffer::from_fn(4, 4, |x, y| GrayU16(if x < 3 { 7 } else { 65535 - y as u16 }));
+        let mask = PixelMask::from_fn(4, 4, |x, y| (x, y) == (0, 0));
+        let h = gray16_histogram(&image, Some(&mask));
+        assert_eq!(h.counts()[7], 11);
+        assert_eq!(h.counts()[65535], 1);
+        assert_eq!(h.mode(), Some(7.0));
+        assert_eq!(*h.cumulative().last().unwrap(), 15);
+
+        let roi = gray16_histogram(&image.view(2, 1, 2, 2), None);
+        assert_eq!((roi.counts()[7], roi.counts()[65534], roi.counts()[65533]), (2, 1, 1));
+    }
+
+    #[test]
+    fn float_histogram_with_auto_and_fixed_range() {
+        let image: GrayFloatImage = ImageBuffer::from_fn(5, 2, |x, y| {
+            GrayF32(if (x, y) == (4, 1) { ::std::f32::NAN } else { x as f32 + y as f32 * 0.5 })
+        });
+        let auto = histogram(&image, None, Binning::auto(4));
+        assert_eq!(auto.range(), (0.0, 4.0));
+        assert_eq!(auto.counts(), &[2, 2, 2, 3]);
+        assert_eq!(auto.total(), 9);
+
+        let fixed = histogram(&image, None, Binning::fixed(2, 1.0, 3.0));
+        assert_eq!(fixed.counts(), &[2, 3]);
+        assert_eq!((fixed.below(), fixed.above()), (2, 2));
+
+        let mut csv = Vec::new();
+        fixed.write_csv(&mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "lower,upper,count,cumulative\n1,2,2,2\n2,3,3,5\n");
+    }
+}
@@ -10,16 +10,60 @@ use byteorder;
 pub enum ImageError {
     /// The Image is not formatted properly
     FormatError(String),
+    /// The IDP header of the image is invalid
+    HeaderError(HeaderError),
+    /// Two images or masks that must be the same size are not
+    DimensionMismatch((u32, u32), (u32, u32)),
+    /// The end of the image sequence has been reached
+    ImageEnd,
         /// An I/O Error occurred while decoding the image
     IoError(io::Error)
 }
 
 
+/// The ways an IDP header can fail validation.
+/// Every variant carries the byte offset of the header within the stream.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The first u32 of the header is not 0
+    NonzeroMagic { value: u32, offset: u64 },
+    /// The second u32 of the header is neither 0 (u16) nor 2 (f32)
+    UnsupportedPixelCode { code: u32, offset: u64 },
+    /// The width or height is zero, or the frame is too large to address
+    InvalidDimensions { width: u32, height: u32, offset: u64 },
+    /// The stream length does not match `16 + width * height * bytes_per_pixel`
+    LengthMismatch { expected: u64, actual: u64, offset: u64 },
+    /// The stream ends inside the header, `actual` bytes after its start
+    Truncated { actual: u64, offset: u64 },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            HeaderError::NonzeroMagic { value, offset } =>
+                write!(fmt, "expected 0 at byte {}, found {}", offset, value),
+            HeaderError::UnsupportedPixelCode { code, offset } =>
+                write!(fmt, "unsupported pixel code {} at byte {}", code, offset + 4),
+            HeaderError::InvalidDimensions { width, height, offset } =>
+                write!(fmt, "invalid dimensions {}x{} at byte {}", width, height, offset + 8),
+            HeaderError::LengthMismatch { expected, actual, offset } =>
+                write!(fmt, "frame at byte {} needs {} bytes, stream has {}", offset, expected, actual),
+            HeaderError::Truncated { actual, offset } =>
+                write!(fmt, "header at byte {} is cut off after {} bytes", offset, actual),
+        }
+    }
+}
+
+
 
 impl fmt::Display for ImageError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
             &ImageError::FormatError(ref e) => write!(fmt, "Format error: {}", e),
+            &ImageError::HeaderError(ref e) => write!(fmt, "Invalid IDP header: {}", e),
+            &ImageError::DimensionMismatch((w1, h1), (w2, h2)) =>
+                write!(fmt, "Dimension mismatch: {}x{} and {}x{}", w1, h1, w2, h2),
+            &ImageError::ImageEnd => write!(fmt, "The end of the image sequence has been reached"),
             &ImageError::IoError(ref e) => e.fmt(fmt)
         }
     }
@@ -29,6 +73,9 @@ impl Error for ImageError {
     fn description (&self) -> &str {
         match *self {
             ImageError::FormatError(..) => &"Format error",
+            ImageError::HeaderError(..) => &"Invalid IDP header",
+            ImageError::DimensionMismatch(..) => &"Dimension mismatch",
+            ImageError::ImageEnd => &"The end of the image sequence has been reached",
             ImageError::IoError(..) => &"IO error"
         }
     }
@@ -51,7 +98,7 @@ impl From<io::Error> for ImageError {
 impl From<byteorder::Error> for ImageError {
     fn from(err: byteorder::Error) -> ImageError {
         match err {
-            byteorder::Error::UnexpectedEOF => ImageError::FormatError( "Format error: ".to_string()  ),
+            byteorder::Error::UnexpectedEOF => ImageError::FormatError( "unexpected end of stream".to_string() ),
             byteorder::Error::Io(err) => ImageError::IoError(err),
         }
     }
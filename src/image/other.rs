@@ -1,7 +1,7 @@
 
-use traits::{ Primitive, Pixel, GenericImage };
+use traits::{ Primitive, Pixel, GenericImage, GenericImageView };
 use buffer::ImageBuffer;
-use std::ops::{ Index, IndexMut };
+use std::ops::{ Deref, DerefMut, Index, IndexMut };
 use std::mem;
 
 
@@ -56,7 +56,7 @@ impl<'a, I: 'a> Pixels<'a, I > {
     }
 }
 
-impl<'a, I: GenericImage> Iterator for Pixels<'a, I> {
+impl<'a, I: GenericImageView> Iterator for Pixels<'a, I> {
     type Item = (u32, u32, I::Pixel);
 
     fn next(&mut self) -> Option<(u32, u32, I::Pixel)> {
@@ -82,7 +82,148 @@ impl<'a, I: GenericImage> Iterator for Pixels<'a, I> {
 ///////////////////////////////////////////////////
 
 
-/// A View into another image
+/// Panics unless the rectangle `(x, y, width, height)` fits into `(outer_width, outer_height)`
+fn assert_inside(outer_width: u32, outer_height: u32, x: u32, y: u32, width: u32, height: u32) {
+    assert!(x as u64 + width as u64 <= outer_width as u64 && y as u64 + height as u64 <= outer_height as u64,
+            "view {}x{} at ({}, {}) does not fit into {}x{}", width, height, x, y, outer_width, outer_height);
+}
+
+
+/// A read-only view into another image.
+///
+/// The view only borrows the image, so several views of the same image, or of an image
+/// that is itself borrowed, can be used at once.
+pub struct SubImageRef<'a, I: 'a> {
+    image:   &'a I,
+    xoffset: u32,
+    yoffset: u32,
+    xstride: u32,
+    ystride: u32,
+}
+
+impl<'a, I: GenericImageView> SubImageRef<'a, I> {
+
+    /// Construct a new view
+    ///
+    /// # Panics
+    ///
+    /// Panics if the rectangle does not fit into `image`.
+    pub fn new(image: &'a I, x: u32, y: u32, width: u32, height: u32) -> SubImageRef<'a, I> {
+        let (w, h) = image.dimensions();
+        assert_inside(w, h, x, y, width, height);
+        SubImageRef {
+            image:   image,
+            xoffset: x,
+            yoffset: y,
+            xstride: width,
+            ystride: height,
+        }
+    }
+
+    /// Returns a reference to the wrapped image.
+    pub fn inner(&self) -> &'a I {
+        self.image
+    }
+
+    /// Returns a view into this view, at (x, y) relative to its top-left corner.
+    /// The new view borrows the wrapped image directly, so views can be nested freely.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the rectangle does not fit into this view.
+    pub fn view(&self, x: u32, y: u32, width: u32, height: u32) -> SubImageRef<'a, I> {
+        assert_inside(self.xstride, self.ystride, x, y, width, height);
+        SubImageRef {
+            image:   self.image,
+            xoffset: self.xoffset + x,
+            yoffset: self.yoffset + y,
+            xstride: width,
+            ystride: height,
+        }
+    }
+
+    /// Convert this view to an ImageBuffer
+    pub fn to_image(&self) -> ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>
+    where I::Pixel: 'static, <I::Pixel as Pixel>::Subpixel: 'static {
+        ImageBuffer::from_fn(self.xstride, self.ystride, |x, y| self.get_pixel(x, y))
+    }
+}
+
+impl<'a, P, Container> SubImageRef<'a, ImageBuffer<P, Container>>
+where P: Pixel + 'static,
+      P::Subpixel: 'static,
+      Container: Deref<Target=[P::Subpixel]> {
+
+    /// Returns the subpixels of row `y` of this view as a slice
+    ///
+    /// # Panics
+    ///
+    /// Panics if `y` is out of bounds.
+    pub fn row(&self, y: u32) -> &'a [P::Subpixel] {
+        assert!(y < self.ystride, "row {} out of bounds", y);
+        let row = self.image.row(y + self.yoffset);
+        &row[self.xoffset as usize .. (self.xoffset + self.xstride) as usize]
+    }
+
+    /// Returns an iterator over the rows of this view as slices
+    pub fn rows(&self) -> Rows<'a, P, Container> {
+        Rows {
+            view: *self,
+            y:    0,
+        }
+    }
+}
+
+impl<'a, I: 'a> Clone for SubImageRef<'a, I> {
+    fn clone(&self) -> SubImageRef<'a, I> {
+        SubImageRef { ..*self }
+    }
+}
+
+impl<'a, I: 'a> Copy for SubImageRef<'a, I> {}
+
+impl<'a, I: GenericImageView> GenericImageView for SubImageRef<'a, I> {
+
+    type Pixel = I::Pixel;
+
+    fn dimensions(&self) -> (u32, u32) {
+        (self.xstride, self.ystride)
+    }
+
+    fn bounds(&self) -> (u32, u32, u32, u32) {
+        (self.xoffset, self.yoffset, self.xstride, self.ystride)
+    }
+
+    fn get_pixel(&self, x: u32, y: u32) -> I::Pixel {
+        self.image.get_pixel(x + self.xoffset, y + self.yoffset)
+    }
+}
+
+
+/// Iterates over the rows of a view into an image buffer
+pub struct Rows<'a, P: Pixel + 'a, Container: 'a> {
+    view: SubImageRef<'a, ImageBuffer<P, Container>>,
+    y:    u32,
+}
+
+impl<'a, P, Container> Iterator for Rows<'a, P, Container>
+where P: Pixel + 'static,
+      P::Subpixel: 'static,
+      Container: Deref<Target=[P::Subpixel]> {
+    type Item = &'a [P::Subpixel];
+
+    fn next(&mut self) -> Option<&'a [P::Subpixel]> {
+        if self.y >= self.view.ystride {
+            None
+        } else {
+            self.y += 1;
+            Some(self.view.row(self.y - 1))
+        }
+    }
+}
+
+
+/// A mutable view into another image
 pub struct SubImage <'a, I: 'a> {
     image:   &'a mut I,
     xoffset: u32,
@@ -91,13 +232,16 @@ pub struct SubImage <'a, I: 'a> {
     ystride: u32,
 }
 
-// TODO: Do we really need the 'static bound on `I`? Can we avoid it?
-impl<'a, I: GenericImage + 'static> SubImage<'a, I>
-    where I::Pixel: 'static,
-          <I::Pixel as Pixel>::Subpixel: 'static {
+impl<'a, I: GenericImage> SubImage<'a, I> {
 
     /// Construct a new subimage
+    ///
+    /// # Panics
+    ///
+    /// Panics if the rectangle does not fit into `image`.
     pub fn new(image: &mut I, x: u32, y: u32, width: u32, height: u32) -> SubImage<I> {
+        let (w, h) = image.dimensions();
+        assert_inside(w, h, x, y, width, height);
         SubImage {
             image:   image,
             xoffset: x,
@@ -120,26 +264,75 @@ impl<'a, I: GenericImage + 'static> SubImage<'a, I>
         self.ystride = height;
     }
 
-    /// Convert this subimage to an ImageBuffer
-    pub fn to_image(&self) -> ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>> {
-        let mut out = ImageBuffer::new(self.xstride, self.ystride);
-
-        for y in (0..self.ystride) {
-            for x in (0..self.xstride) {
-                let p = self.get_pixel(x, y);
-                out.put_pixel(x, y, p);
-            }
+    /// Returns a read-only view into this subimage, at (x, y) relative to its top-left corner.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the rectangle does not fit into this subimage.
+    pub fn view(&self, x: u32, y: u32, width: u32, height: u32) -> SubImageRef<I> {
+        assert_inside(self.xstride, self.ystride, x, y, width, height);
+        SubImageRef {
+            image:   &*self.image,
+            xoffset: self.xoffset + x,
+            yoffset: self.yoffset + y,
+            xstride: width,
+            ystride: height,
         }
+    }
 
-        out
+    /// Returns a mutable view into this subimage, at (x, y) relative to its top-left corner.
+    /// The new view borrows the wrapped image directly, so views can be nested freely.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the rectangle does not fit into this subimage.
+    pub fn sub_image(&mut self, x: u32, y: u32, width: u32, height: u32) -> SubImage<I> {
+        assert_inside(self.xstride, self.ystride, x, y, width, height);
+        SubImage {
+            image:   &mut *self.image,
+            xoffset: self.xoffset + x,
+            yoffset: self.yoffset + y,
+            xstride: width,
+            ystride: height,
+        }
+    }
+
+    /// Convert this subimage to an ImageBuffer
+    pub fn to_image(&self) -> ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>
+    where I::Pixel: 'static, <I::Pixel as Pixel>::Subpixel: 'static {
+        self.view(0, 0, self.xstride, self.ystride).to_image()
     }
 }
 
-#[allow(deprecated)]
-// TODO: Is the 'static bound on `I` really required? Can we avoid it?
-impl<'a, I: GenericImage + 'static> GenericImage for SubImage<'a, I>
-    where I::Pixel: 'static,
-          <I::Pixel as Pixel>::Subpixel: 'static {
+impl<'a, P, Container> SubImage<'a, ImageBuffer<P, Container>>
+where P: Pixel + 'static,
+      P::Subpixel: 'static,
+      Container: Deref<Target=[P::Subpixel]> + DerefMut {
+
+    /// Returns the subpixels of row `y` of this subimage as a slice
+    ///
+    /// # Panics
+    ///
+    /// Panics if `y` is out of bounds.
+    pub fn row(&self, y: u32) -> &[P::Subpixel] {
+        assert!(y < self.ystride, "row {} out of bounds", y);
+        let row = self.image.row(y + self.yoffset);
+        &row[self.xoffset as usize .. (self.xoffset + self.xstride) as usize]
+    }
+
+    /// Returns the subpixels of row `y` of this subimage as a mutable slice
+    ///
+    /// # Panics
+    ///
+    /// Panics if `y` is out of bounds.
+    pub fn row_mut(&mut self, y: u32) -> &mut [P::Subpixel] {
+        assert!(y < self.ystride, "row {} out of bounds", y);
+        let (start, end) = (self.xoffset as usize, (self.xoffset + self.xstride) as usize);
+        &mut self.image.row_mut(y + self.yoffset)[start .. end]
+    }
+}
+
+impl<'a, I: GenericImage> GenericImageView for SubImage<'a, I> {
 
     type Pixel = I::Pixel;
 
@@ -154,6 +347,9 @@ impl<'a, I: GenericImage + 'static> GenericImage for SubImage<'a, I>
     fn get_pixel(&self, x: u32, y: u32) -> I::Pixel {
         self.image.get_pixel(x + self.xoffset, y + self.yoffset)
     }
+}
+
+impl<'a, I: GenericImage> GenericImage for SubImage<'a, I> {
 
     fn put_pixel(&mut self, x: u32, y: u32, pixel: I::Pixel) {
         self.image.put_pixel(x + self.xoffset, y + self.yoffset, pixel)
@@ -162,18 +358,6 @@ impl<'a, I: GenericImage + 'static> GenericImage for SubImage<'a, I>
     fn get_pixel_mut(&mut self, x: u32, y: u32) -> &mut I::Pixel {
         self.image.get_pixel_mut(x + self.xoffset, y + self.yoffset)
     }
-    fn pixels(&self) -> Pixels<Self> {
-        let (width, height) = self.dimensions();
-
-        Pixels {
-            image:  self,
-            x:      0,
-            y:      0,
-            width:  width,
-            height: height,
-        }
-    }
-
 }
 
 ////////////////////////////////////////////////////////////////////////
@@ -240,22 +424,19 @@ impl<T: Primitive + 'static> Pixel for $ident<T> {
     }
 
     fn apply<F>(&mut self, f: F) where F: Fn(T) -> T {
-        let mut v = self.data;
-            v = f(v)
-        }
+        self.data = f(self.data)
     }
 
-//    fn map2<F>(&self, other: &Self, f: F) -> $ident<T> where F: Fn(T, T) -> T {
-//        let mut this = (*self).clone();
-//        this.apply2(other, f);
-//        this
-//    }
+    fn map2<F>(&self, other: &Self, f: F) -> $ident<T> where F: Fn(T, T) -> T {
+        let mut this = (*self).clone();
+        this.apply2(other, f);
+        this
+    }
 
-//    fn apply2<F>(&mut self, other: &$ident<T>, f: F) where F: Fn(T, T) -> T {
-//        let mut (a, &b) = (self.data, other.data );
-//        *a = f(*a, b);
-//    }
-    
+    fn apply2<F>(&mut self, other: &$ident<T>, f: F) where F: Fn(T, T) -> T {
+        self.data = f(self.data, other.data)
+    }
+}
 
 impl<T: Primitive> Index<usize> for $ident<T> {
     type Output = T;
@@ -287,3 +468,39 @@ define_colors! {
 
 
 
+
+#[cfg(test)]
+mod test {
+    use buffer::{ ImageBuffer, Gray16Image };
+    use stats::frame_statistics;
+    use traits::{ GenericImage, GenericImageView };
+    use super::GrayU16;
+
+    #[test]
+    fn shared_views_of_a_borrowed_buffer() {
+        let data: Vec<u16> = (0..20).collect();
+        let image: ImageBuffer<GrayU16<u16>, &[u16]> = ImageBuffer::from_raw(5, 4, &data[..]).unwrap();
+        let left = image.view(0, 0, 2, 4);
+        let right = image.view(3, 1, 2, 3);
+        assert_eq!(frame_statistics(&left, None).unwrap().max, 16.0);
+        assert_eq!(frame_statistics(&right, None).unwrap().min, 8.0);
+
+        let nested = right.view(1, 1, 1, 2);
+        assert_eq!(nested.bounds(), (4, 2, 1, 2));
+        assert_eq!(nested.pixels().map(|(_, _, p)| p.data).collect::<Vec<_>>(), vec![14, 19]);
+        assert_eq!(right.rows().collect::<Vec<_>>(), vec![&[8, 9][..], &[13, 14], &[18, 19]]);
+    }
+
+    #[test]
+    fn nested_mutable_views_write_through() {
+        let mut image: Gray16Image = ImageBuffer::new(4, 3);
+        {
+            let mut outer = image.sub_image(1, 1, 3, 2);
+            outer.sub_image(1, 0, 2, 2).put_pixel(1, 1, GrayU16(7));
+            outer.row_mut(0)[0] = 5;
+            assert_eq!(outer.view(0, 0, 3, 2).to_image().into_raw(), vec![5, 0, 0, 0, 0, 7]);
+        }
+        assert_eq!(image.row(2), &[0, 0, 0, 7]);
+        assert_eq!(image.get_pixel(1, 1).data, 5);
+    }
+}
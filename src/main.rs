@@ -1,97 +1,824 @@
+#![cfg_attr(feature = "bench", feature(test))]
+
 extern crate byteorder;
+extern crate memmap;
 extern crate num;
+extern crate rustc_serialize;
 
-use std::io::{BufReader, BufWriter};
-use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::fs::{self, File};
 use std::path::Path;
+use std::env;
+use std::process;
+use std::collections::HashMap;
 // use byteorder::{ ReadBytesExt, BigEndian, LittleEndian};
 mod stream;
-mod decoder; 
+mod decoder;
+mod encoder;
 mod buffer;
 mod image;
 mod traits;
+mod arithmetic;
+mod mask;
+mod stats;
+mod threshold;
+mod dynimage;
+mod mmap;
+mod stack;
+mod calibration;
+mod defects;
+mod defect_file;
+mod correction;
+mod histogram;
+mod roi;
+mod preview;
+mod png;
+mod tiff;
+mod fits;
+mod format;
+mod npy;
+mod raw;
 
-use stream::{
-    ByteOrder,
-    EndianWriter,
-    SmartWriter,
-    SmartReader
-};
 
 use image::error::{
+    ImageError,
     ImageResult
 };
 
-use image::other::{
-    DecodingResult
-};
+use image::other::PixelType;
 
+use arithmetic::OverflowPolicy;
 
-use decoder::{
-    IDPDecoder,
-    ImageDecoder
-};
+use dynimage::DynamicIdpImage;
+
+use mmap::MappedIdp;
+
+use stack::ImageStack;
+
+use calibration::Calibration;
+
+use correction::{ Correction, Axis };
+
+use histogram::Binning;
+
+use roi::read_rois;
+
+use preview::{ Preview, Window, Palette };
+
+use raw::{ RawDecoder, RawEncoder, RawOptions };
+
+use stream::ByteOrder;
+
+use defects::{ DefectMap, DefectKind, Detector, DetectionConfig };
+
+use mask::PixelMask;
+
+use threshold::Threshold;
+
+use decoder::ImageDecoder;
+
+use encoder::ImageEncoder;
+
+use format::ImageFormat;
+
+use traits::GenericImageView;
 
 
+const USAGE: &'static str = "\
+Usage: idp_analyzer <command> [options] <files>...
 
+Commands:
+    info      <file>...                      Print format, dimensions and pixel type
+    stats     <file> [--by <frame|rows|columns>] [--percentiles <p,...>]
+                                             Print mean, median, std, min and max
+    subtract  <a> <b> -o <out> [--policy <float|saturate|wrap>] [--mask <file>]
+                                             Write a - b; two u16 inputs stay u16 unless
+                                             the policy is float, the default
+    roi       <in> --rois <file>             Print count, masked count, mean, median, std, min
+                                             and max of every ROI in a text or JSON file
+    histogram <in> [--bins <n>] [--range <min>,<max>] [--format <csv|json>] [-o <out>]
+                                             Print or write the histogram; u16 images get one
+                                             bin per value unless --bins or --range is given,
+                                             f32 images 256 bins over their range by default
+    convert   <in> -o <out> --to <u16|f32>   Change the pixel type
+    import    <raw> -o <out> --size <w>,<h> --type <u16|f32> [--order <little|big>]
+              [--skip <bytes>] [--padding <bytes>]
+                                             Convert a headerless dump, skipping a header
+                                             and the padding after every row; every whole
+                                             frame in the dump becomes a frame of the output
+    export    <in> -o <out> [--order <little|big>] [--padding <bytes>]
+                                             Write the pixels without a header, little
+                                             endian unless --order big is given
+    png       <in> -o <out>                  Write a u16 image losslessly as a 16 bit PNG
+    preview   <in> -o <out> [--window <level>,<width> | --percentile <low>,<high>]
+              [--palette <gray|inverted|hot|jet>]
+                                             Write an 8 bit PNG for display; by default the
+                                             0.5 and 99.5 percentiles span black to white
+    mask      <in> -o <out> <limits>         Write a u16 mask, 1 marks a selected pixel
+    threshold <in> <limits> [--by <frame|rows|columns>]
+                                             Count the selected pixels
+    calibrate <raw> --dark <file> --flat <file> -o <out> [--gain <low>,<high>]
+                                             Write the f32 frame (raw - dark) / (flat - dark)
+                                             * mean(flat - dark); dark and flat may hold
+                                             several frames, their median is used
+    correct   <in> --mask <file> -o <out> [--method <method>]
+                                             Replace the dead pixels by interpolation; method
+                                             is average, median3, median5, rows (along rows,
+                                             for dead columns), columns or cluster, the default
+    defects   --dark <file> --flat <file> -o <out> [--sigma <k> | --absolute <v>] [--radius <r>]
+                                             Classify hot, dead, noisy and unstable pixels
+                                             and write their codes (1, 2, 4, 8) as a u16
+                                             image, usable as --mask, and as a text list
+                                             next to it with the extension .txt, which
+                                             <out> itself must not have
 
-fn make_test_idp( input_path: &Path) {
-    println!("This shold create a test IDP file!");
-    // let inpfile = r#"dsr_test_f32.idp"#;
-    let f = match File::create( input_path ) {
-        Ok( file ) => file,
-        Err( msg ) => { println!("{}", msg); panic!( "room" ); }
+Formats:
+    Inputs may be IDP, TIFF, FITS or NumPy files, recognized by their first bytes. Outputs
+    are written in the format named by their extension: .idp, .tif/.tiff, .fits/.fit/.fts,
+    .npy or .png (u16 only); any other extension gives IDP. IDP, FITS and NumPy files hold
+    several frames, a NumPy stack is a 3 dimensional array of shape (frames, height, width).
+
+Limits:
+    --below <v>           Select pixels below v
+    --above <v>           Select pixels above v
+    --below <a> --above <b>
+                          Select pixels below a or above b
+    --within <a>,<b>      Select pixels between a and b, both included
+
+Options:
+    -o, --output <path>   Output file
+    --mask <path>         Image whose nonzero pixels are dead; they are skipped
+                          by stats, threshold, histogram, roi and the percentiles of
+                          preview, merged into the
+                          output of mask, NaN in the output of calibrate and
+                          replaced by correct; subtract keeps them from <a>, or
+                          makes them NaN when the difference is f32
+    --gain <low>,<high>   Relative gains considered good by calibrate, default 0.5,2;
+                          pixels outside are counted and NaN in the output
+    -h, --help            Print this message
+
+Exit codes:
+    0 success, 1 usage error, 2 malformed image or header, 3 I/O error,
+    4 images or masks of different size
+";
+
+/// Exit status for a malformed command line
+const EXIT_USAGE: i32 = 1;
+
+/// Exit status for a malformed image, header or other input file
+const EXIT_FORMAT: i32 = 2;
+
+/// Exit status for a failed read or write
+const EXIT_IO: i32 = 3;
+
+/// Exit status for images or masks of different size
+const EXIT_DIMENSIONS: i32 = 4;
+
+
+/// A parsed command line
+struct Args {
+    command: String,
+    files: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    /// Splits `argv` (without the program name) into a command, files and options.
+    fn parse(argv: Vec<String>) -> Result<Args, String> {
+        let mut iter = argv.into_iter();
+        let command = match iter.next() {
+            // `idp_analyzer --help` asks for help without a command
+            Some( ref c ) if c == "-h" || c == "--help" => "help".to_string(),
+            Some( c ) => c,
+            None => return Err( "no command given".to_string() )
+        };
+
+        let mut files = Vec::new();
+        let mut options = HashMap::new();
+        while let Some( arg ) = iter.next() {
+            let key = match &arg[..] {
+                "-h" | "--help" => { options.insert( "help".to_string(), String::new() ); continue },
+                "-o" | "--output" => "output".to_string(),
+                a if a.starts_with( "--" ) => a[2..].to_string(),
+                _ => { files.push( arg ); continue }
+            };
+            match iter.next() {
+                Some( value ) => { options.insert( key, value ); },
+                None => return Err( format!( "option `{}` needs a value", arg ) )
+            }
+        }
+
+        Ok( Args { command: command, files: files, options: options } )
+    }
+
+    fn output(&self) -> Result<&Path, String> {
+        match self.options.get( "output" ) {
+            Some( p ) => Ok( Path::new( p ) ),
+            None => Err( format!( "`{}` needs an output file (-o)", self.command ) )
+        }
+    }
+
+    fn number(&self, key: &str) -> Result<Option<f64>, String> {
+        match self.options.get( key ) {
+            Some( v ) => match v.parse::<f64>() {
+                Ok( n ) => Ok( Some( n ) ),
+                Err( _ ) => Err( format!( "--{} expects a number, got `{}`", key, v ) )
+            },
+            None => Ok( None )
+        }
+    }
+
+    /// Parses `--<key> <n>` for a non-negative integer n
+    fn integer(&self, key: &str) -> Result<Option<u64>, String> {
+        match try!( self.number( key ) ) {
+            Some( n ) if n >= 0.0 && n.fract() == 0.0 => Ok( Some( n as u64 ) ),
+            Some( n ) => Err( format!( "--{} expects a whole number, got `{}`", key, n ) ),
+            None => Ok( None )
+        }
+    }
+
+    /// Parses `--order <little|big>`, little endian by default
+    fn byte_order(&self) -> Result<ByteOrder, String> {
+        match self.options.get( "order" ).map( |s| &s[..] ) {
+            None | Some( "little" ) => Ok( ByteOrder::LittleEndian ),
+            Some( "big" ) => Ok( ByteOrder::BigEndian ),
+            Some( other ) => Err( format!( "unknown byte order `{}`", other ) )
+        }
+    }
+
+    fn group_by(&self) -> Result<GroupBy, String> {
+        match self.options.get( "by" ).map( |s| &s[..] ) {
+            None | Some( "frame" ) => Ok( GroupBy::Frame ),
+            Some( "rows" ) => Ok( GroupBy::Rows ),
+            Some( "columns" ) => Ok( GroupBy::Columns ),
+            Some( other ) => Err( format!( "unknown grouping `{}`", other ) )
+        }
+    }
+
+    /// Parses `--<key> <a>,<b>`
+    fn pair(&self, key: &str) -> Result<Option<(f64, f64)>, String> {
+        match self.options.get( key ) {
+            Some( range ) => {
+                let values: Vec<f64> = range.split( ',' ).filter_map( |v| v.trim().parse().ok() ).collect();
+                if values.len() == 2 && range.split( ',' ).count() == 2 {
+                    Ok( Some( (values[0], values[1]) ) )
+                } else {
+                    Err( format!( "--{} expects <low>,<high>, got `{}`", key, range ) )
+                }
+            },
+            None => Ok( None )
+        }
+    }
+
+    /// Builds a threshold from `--within`, or from `--below` and/or `--above`
+    fn threshold(&self) -> Result<Threshold, String> {
+        if let Some( (low, high) ) = try!( self.pair( "within" ) ) {
+            return Ok( Threshold::Within( low, high ) )
+        }
+        match (try!( self.number( "below" ) ), try!( self.number( "above" ) )) {
+            (Some( below ), Some( above )) => Ok( Threshold::Outside( below, above ) ),
+            (Some( below ), None) => Ok( Threshold::Below( below ) ),
+            (None, Some( above )) => Ok( Threshold::Above( above ) ),
+            (None, None) => Err( format!( "`{}` needs --below, --above or --within", self.command ) )
+        }
+    }
+
+    fn expect_files(&self, n: usize) -> Result<(), String> {
+        if self.files.len() == n {
+            Ok(())
+        } else {
+            Err( format!( "`{}` takes {} file(s), got {}", self.command, n, self.files.len() ) )
+        }
+    }
+}
+
+
+/// Opens the first frame of an image in any readable format; IDP files are mapped into memory
+fn open( name: &str ) -> ImageResult<DynamicIdpImage> {
+    match try!( open_mapped( name ) ) {
+        Some( mapped ) => Ok( mapped.to_dynamic() ),
+        None => format::open( name )
+    }
+}
+
+
+/// Maps the file `name` into memory if it is an IDP file, so that read-only commands
+/// can borrow its pixels instead of copying them. Other formats give None.
+fn open_mapped( name: &str ) -> ImageResult<Option<MappedIdp>> {
+    let f = try!( File::open( name ) );
+    match try!( format::guess_format( &mut BufReader::new( f ) ) ).or( ImageFormat::from_path( name ) ) {
+        Some( ImageFormat::Idp ) => MappedIdp::open( Path::new( name ) ).map( Some ),
+        _ => Ok( None )
+    }
+}
+
+
+/// Reads the dead pixel mask given by `--mask`, if any, and checks it fits an image of `dimensions`
+fn read_mask( args: &Args, dimensions: (u32, u32) ) -> ImageResult<Option<PixelMask>> {
+    let name = match args.options.get( "mask" ) {
+        Some( name ) => name,
+        None => return Ok( None )
     };
+    let mask = PixelMask::from_image( &try!( open( name ) ).to_u16() );
+    try!( mask.check_dimensions( dimensions ) );
+    Ok( Some( mask ) )
+}
 
-    let w = BufWriter::new( &f );
-    let mut wtr = SmartWriter::wrap(w, ByteOrder::LittleEndian);
 
-    let fmt1 = 0u32;
-    let fmt2 = 2u32;
-      
-    let number_of_columns :u32 = 20u32;
-    let number_of_rows: u32    = 10u32;
-    
-    wtr.write_u32( fmt1 ).unwrap();
-    wtr.write_u32( fmt2 ).unwrap();
-    wtr.write_u32( number_of_columns ).unwrap();
-    wtr.write_u32( number_of_rows    ).unwrap();
-    for row in (0..number_of_rows ) {
-        for col in ( 0..number_of_columns ) {
-            wtr.write_f32( ( col * 100 + row ) as f32  ).unwrap()
+fn info( args: &Args ) -> ImageResult<()> {
+    for name in &args.files {
+        let len = try!( fs::metadata( name ) ).len();
+        let mut decoder = try!( format::open_decoder( name ) );
+        let format = decoder.format();
+        let (width, height) = try!( decoder.dimensions() );
+        let pixel_type = try!( decoder.pixel_type() );
+        let mut frames = 1;
+        while decoder.more_images() {
+            decoder = try!( decoder.next_image() );
+            frames += 1;
         }
+        println!( "{}: {} {}x{} {:?}, {} frame(s), {} bytes", name, format.name(), width, height, pixel_type, frames, len );
     }
+    Ok(())
+}
+
+
+/// How `stats` and `threshold` group the pixels of a frame
+#[derive(Clone, Copy)]
+enum GroupBy {
+    Frame,
+    Rows,
+    Columns,
 }
 
 
-fn read_test_idp( input_path: &Path) -> ImageResult<DecodingResult> {
-    let f = match File::open( input_path ) {
-        Ok( file ) => file,
-        Err( msg ) => { println!("{}", msg); panic!( "could not open input file" ); }
+fn stats( args: &Args, by: GroupBy, percentiles: &[f64] ) -> ImageResult<()> {
+    let name = &args.files[0];
+    let mapped = try!( open_mapped( name ) );
+    // Mapped pixels are borrowed where the host allows it, anything else is decoded
+    let image = match mapped {
+        Some( ref mapped ) => match ( mapped.as_gray16(), mapped.as_gray_float() ) {
+            ( Some( image ), _ ) => {
+                let mask = try!( read_mask( args, mapped.dimensions() ) );
+                print_stats( &image, mask.as_ref(), by, percentiles );
+                return Ok(())
+            },
+            ( _, Some( image ) ) => {
+                let mask = try!( read_mask( args, mapped.dimensions() ) );
+                print_stats( &image, mask.as_ref(), by, percentiles );
+                return Ok(())
+            },
+            _ => mapped.to_dynamic(),
+        },
+        None => try!( format::open( name ) ),
     };
+    let mask = try!( read_mask( args, image.dimensions() ) );
+    match image {
+        DynamicIdpImage::U16( ref image ) => print_stats( image, mask.as_ref(), by, percentiles ),
+        DynamicIdpImage::F32( ref image ) => print_stats( image, mask.as_ref(), by, percentiles ),
+    }
+    Ok(())
+}
 
-    let bufr = BufReader::new( &f );
-    let rdr = SmartReader::wrap( bufr, ByteOrder::LittleEndian );
-    let mut idp_decoder = IDPDecoder::new( rdr ).unwrap();
-    let decoding_result = idp_decoder.read_image().unwrap();
 
-//    match decoding_result {
-//        DecodingResult::U16(ref mut buffer) =>
-//        {
-//            // Make a U16 Image buffer
-//        },
-//        DecodingResult::F32(ref mut buffer) =>
-//        {
-//            // make a F32 Image buffer
-//        },
-//    } 
+/// Prints the statistics table of `image` and the `percentiles` of the whole frame
+fn print_stats<I: GenericImageView>( image: &I, mask: Option<&PixelMask>, by: GroupBy, percentiles: &[f64] ) {
+    let table = match by {
+        GroupBy::Frame => vec![ stats::frame_statistics( image, mask ) ],
+        GroupBy::Rows => stats::row_statistics( image, mask ),
+        GroupBy::Columns => stats::column_statistics( image, mask ),
+    };
 
-    Ok( decoding_result )
+    println!( "{:>6} {:>10} {:>14} {:>14} {:>14} {:>14} {:>14}",
+              "index", "count", "mean", "median", "std", "min", "max" );
+    for (i, s) in table.iter().enumerate() {
+        match *s {
+            Some( ref s ) => println!( "{:>6} {:>10} {:>14.4} {:>14.4} {:>14.4} {:>14.4} {:>14.4}",
+                                       i, s.count, s.mean, s.median, s.std_dev, s.min, s.max ),
+            None => println!( "{:>6} {:>10}", i, 0 ),
+        }
+    }
+
+    if !percentiles.is_empty() {
+        for (p, v) in percentiles.iter().zip( stats::frame_percentiles( image, mask, percentiles ) ) {
+            println!( "p{:<5} {}", p, v );
+        }
+    }
+}
+
+
+fn roi( args: &Args, rois: &str ) -> ImageResult<()> {
+    let image = try!( open( &args.files[0] ) );
+    let mask = try!( read_mask( args, image.dimensions() ) );
+    let rois = try!( read_rois( rois ) );
+    let table = image.roi_statistics( &rois, mask.as_ref() );
+
+    println!( "{:<16} {:>10} {:>10} {:>14} {:>14} {:>14} {:>14} {:>14}",
+              "roi", "pixels", "masked", "mean", "median", "std", "min", "max" );
+    for row in &table {
+        match row.statistics {
+            Some( ref s ) => println!( "{:<16} {:>10} {:>10} {:>14.4} {:>14.4} {:>14.4} {:>14.4} {:>14.4}",
+                                       row.name, row.pixels, row.masked, s.mean, s.median, s.std_dev, s.min, s.max ),
+            None => println!( "{:<16} {:>10} {:>10}", row.name, row.pixels, row.masked ),
+        }
+    }
+    Ok(())
+}
+
+
+fn histogram( args: &Args, binning: Option<Binning>, json: bool ) -> ImageResult<()> {
+    let name = &args.files[0];
+    // Mapped pixels are borrowed where the host allows it, anything else is decoded
+    let histogram = match try!( open_mapped( name ) ) {
+        Some( mapped ) => {
+            let mask = try!( read_mask( args, mapped.dimensions() ) );
+            let mask = mask.as_ref();
+            match ( mapped.as_gray16(), mapped.as_gray_float(), binning ) {
+                ( Some( ref image ), _, None ) => histogram::gray16_histogram( image, mask ),
+                ( Some( ref image ), _, Some( binning ) ) => histogram::histogram( image, mask, binning ),
+                ( _, Some( ref image ), binning ) =>
+                    histogram::histogram( image, mask, binning.unwrap_or( Binning::auto( histogram::DEFAULT_BINS ) ) ),
+                _ => mapped.to_dynamic().histogram( mask, binning ),
+            }
+        },
+        None => {
+            let image = try!( format::open( name ) );
+            let mask = try!( read_mask( args, image.dimensions() ) );
+            image.histogram( mask.as_ref(), binning )
+        },
+    };
+    let mut out: Box<dyn Write> = match args.options.get( "output" ) {
+        Some( name ) => Box::new( io::BufWriter::new( try!( File::create( name ) ) ) ),
+        None => Box::new( io::stdout() ),
+    };
+    if json {
+        try!( histogram.write_json( &mut out ) );
+    } else {
+        try!( histogram.write_csv( &mut out ) );
+    }
+    Ok(())
+}
+
+
+fn subtract( args: &Args, output: &Path, policy: Option<OverflowPolicy> ) -> ImageResult<()> {
+    let a = try!( open( &args.files[0] ) );
+    let b = try!( open( &args.files[1] ) );
+    let mask = try!( read_mask( args, a.dimensions() ) );
+    try!( a.subtract( &b, policy, mask.as_ref() ) ).save( output )
+}
+
+
+fn convert( args: &Args, to: PixelType, output: &Path ) -> ImageResult<()> {
+    try!( open( &args.files[0] ) ).convert( to ).save( output )
+}
+
+
+fn import( args: &Args, output: &Path, size: (u32, u32), pixel_type: PixelType, options: RawOptions ) -> ImageResult<()> {
+    let f = try!( File::open( &args.files[0] ) );
+    let mut decoder = try!( RawDecoder::new( BufReader::new( f ), size.0, size.1, pixel_type, options ) );
+    let mut frames = Vec::with_capacity( decoder.frame_count() );
+    for n in 0..decoder.frame_count() {
+        try!( decoder.seek_frame( n ) );
+        frames.push( try!( decoder.read_image() ) );
+    }
+    try!( format::save_frames( output, size.0, size.1, &frames ) );
+    println!( "{} frame(s) of {}x{} {:?}", decoder.frame_count(), size.0, size.1, pixel_type );
+    Ok(())
+}
+
+
+fn export( args: &Args, output: &Path, options: RawOptions ) -> ImageResult<()> {
+    let image = try!( open( &args.files[0] ) );
+    let mut encoder = RawEncoder::new( io::BufWriter::new( try!( File::create( output ) ) ), options );
+    match image {
+        DynamicIdpImage::U16( ref image ) => encoder.encode_image( image ),
+        DynamicIdpImage::F32( ref image ) => encoder.encode_image( image ),
+    }
+}
+
+
+fn png( args: &Args, output: &Path ) -> ImageResult<()> {
+    try!( open( &args.files[0] ) ).save_png( output )
+}
+
+
+fn preview( args: &Args, output: &Path, preview: &Preview ) -> ImageResult<()> {
+    let image = try!( open( &args.files[0] ) );
+    let mask = try!( read_mask( args, image.dimensions() ) );
+    image.save_preview( output, mask.as_ref(), preview )
+}
+
+
+fn mask( args: &Args, output: &Path, t: Threshold ) -> ImageResult<()> {
+    let image = try!( open( &args.files[0] ) );
+    let mut dead = image.binary_map( t, None );
+    if let Some( existing ) = try!( read_mask( args, image.dimensions() ) ) {
+        dead = try!( dead.union( &existing ) );
+    }
+    dead.to_image().save( output )
+}
+
+
+fn threshold( args: &Args, t: Threshold, by: GroupBy ) -> ImageResult<()> {
+    let image = try!( open( &args.files[0] ) );
+    let mask = try!( read_mask( args, image.dimensions() ) );
+    let mask = mask.as_ref();
+    match by {
+        GroupBy::Frame => {
+            let (width, height) = image.dimensions();
+            let valid = width as usize * height as usize - mask.map_or( 0, |m| m.count() );
+            println!( "{} of {} pixels", image.count( t, mask ), valid )
+        },
+        GroupBy::Rows => for (y, n) in image.count_per_row( t, mask ).iter().enumerate() {
+            println!( "{:>6} {:>10}", y, n );
+        },
+        GroupBy::Columns => for (x, n) in image.count_per_column( t, mask ).iter().enumerate() {
+            println!( "{:>6} {:>10}", x, n );
+        },
+    }
+    Ok(())
+}
+
+
+fn calibrate( args: &Args, output: &Path, dark: &str, flat: &str, gain: (f64, f64) ) -> ImageResult<()> {
+    let raw = try!( open( &args.files[0] ) );
+    let darks = try!( ImageStack::open( dark ) );
+    let flats = try!( ImageStack::open( flat ) );
+    let mut calibration = try!( Calibration::from_stacks( &darks, &flats ) ).with_gain_limits( gain.0, gain.1 );
+    if let Some( mask ) = try!( read_mask( args, raw.dimensions() ) ) {
+        calibration = try!( calibration.with_mask( mask ) );
+    }
+    let corrected = try!( calibration.apply_dynamic( &raw ) );
+    println!( "{} pixel(s) with a gain outside {},{}", calibration.bad_gain().count(), gain.0, gain.1 );
+    corrected.save( output )
+}
+
+
+fn correct( args: &Args, output: &Path, correction: Correction ) -> ImageResult<()> {
+    let mut image = try!( open( &args.files[0] ) );
+    let mask = match try!( read_mask( args, image.dimensions() ) ) {
+        Some( mask ) => mask,
+        None => return Ok( () )
+    };
+    let n = try!( image.correct( &mask, correction ) );
+    println!( "{} of {} dead pixel(s) corrected", n, mask.count() );
+    image.save( output )
+}
+
+
+fn defects( output: &Path, dark: &str, flat: &str, detector: Detector ) -> ImageResult<()> {
+    let darks = try!( ImageStack::open( dark ) );
+    let flats = try!( ImageStack::open( flat ) );
+    let config = DetectionConfig {
+        hot: Some( detector ),
+        dead: Some( detector ),
+        noisy: Some( detector ),
+        unstable: Some( detector ),
+    };
+    let map = try!( DefectMap::detect( &darks, &flats, &config ) );
+    for kind in DefectKind::all().iter() {
+        println!( "{:<10} {:>10}", format!( "{:?}", kind ), map.count( *kind ) );
+    }
+    println!( "{:<10} {:>10}", "total", map.defective() );
+    map.save( output )
+}
+
+
+/// Why a command could not be completed
+enum CliError {
+    /// The command line was malformed
+    Usage(String),
+    /// Reading, writing or processing an image failed
+    Image(ImageError),
+}
+
+impl From<String> for CliError {
+    fn from(msg: String) -> CliError {
+        CliError::Usage(msg)
+    }
+}
+
+impl From<ImageError> for CliError {
+    fn from(err: ImageError) -> CliError {
+        CliError::Image(err)
+    }
+}
+
+
+/// Validates the arguments of `args.command` and runs it.
+fn run( args: &Args ) -> Result<(), CliError> {
+    match &args.command[..] {
+        "info" => {
+            if args.files.is_empty() {
+                return Err( CliError::Usage( "`info` needs at least one file".to_string() ) )
+            }
+            try!( info( args ) );
+        },
+        "stats" => {
+            try!( args.expect_files( 1 ) );
+            let by = try!( args.group_by() );
+            let mut percentiles = Vec::new();
+            if let Some( list ) = args.options.get( "percentiles" ) {
+                for p in list.split( ',' ) {
+                    match p.trim().parse::<f64>() {
+                        Ok( p ) if p >= 0.0 && p <= 100.0 => percentiles.push( p ),
+                        _ => return Err( CliError::Usage( format!( "invalid percentile `{}`", p ) ) )
+                    }
+                }
+            }
+            try!( stats( args, by, &percentiles ) );
+        },
+        "roi" => {
+            try!( args.expect_files( 1 ) );
+            let rois = match args.options.get( "rois" ) {
+                Some( rois ) => rois,
+                None => return Err( CliError::Usage( "`roi` needs --rois".to_string() ) )
+            };
+            try!( roi( args, rois ) );
+        },
+        "histogram" => {
+            try!( args.expect_files( 1 ) );
+            let bins = match try!( args.number( "bins" ) ) {
+                Some( n ) if n >= 1.0 && n.fract() == 0.0 => Some( n as usize ),
+                Some( n ) => return Err( CliError::Usage( format!( "invalid bin count `{}`", n ) ) ),
+                None => None,
+            };
+            let range = try!( args.pair( "range" ) );
+            if let Some( (min, max) ) = range {
+                if min >= max || !min.is_finite() || !max.is_finite() {
+                    return Err( CliError::Usage( format!( "invalid range {},{}", min, max ) ) )
+                }
+            }
+            let binning = match (bins, range) {
+                (None, None) => None,
+                (bins, range) => Some( Binning { bins: bins.unwrap_or( histogram::DEFAULT_BINS ), range: range } ),
+            };
+            let json = match args.options.get( "format" ).map( |s| &s[..] ) {
+                None | Some( "csv" ) => false,
+                Some( "json" ) => true,
+                Some( other ) => return Err( CliError::Usage( format!( "unknown format `{}`", other ) ) )
+            };
+            try!( histogram( args, binning, json ) );
+        },
+        "subtract" => {
+            try!( args.expect_files( 2 ) );
+            let policy = match args.options.get( "policy" ).map( |s| &s[..] ) {
+                None | Some( "float" ) => None,
+                Some( "saturate" ) => Some( OverflowPolicy::Saturate ),
+                Some( "wrap" ) => Some( OverflowPolicy::Wrap ),
+                Some( other ) => return Err( CliError::Usage( format!( "unknown overflow policy `{}`", other ) ) )
+            };
+            try!( subtract( args, try!( args.output() ), policy ) );
+        },
+        "convert" => {
+            try!( args.expect_files( 1 ) );
+            let output = try!( args.output() );
+            let to = match args.options.get( "to" ).map( |s| &s[..] ) {
+                Some( "u16" ) => PixelType::Short16,
+                Some( "f32" ) => PixelType::Float32,
+                _ => return Err( CliError::Usage( "`convert` needs --to u16 or --to f32".to_string() ) )
+            };
+            try!( convert( args, to, output ) );
+        },
+        "import" => {
+            try!( args.expect_files( 1 ) );
+            let output = try!( args.output() );
+            let size = match try!( args.pair( "size" ) ) {
+                Some( (w, h) ) if w >= 1.0 && h >= 1.0 && w.fract() == 0.0 && h.fract() == 0.0
+                                  && w <= ::std::u32::MAX as f64 && h <= ::std::u32::MAX as f64 => (w as u32, h as u32),
+                Some( (w, h) ) => return Err( CliError::Usage( format!( "invalid size {},{}", w, h ) ) ),
+                None => return Err( CliError::Usage( "`import` needs --size <width>,<height>".to_string() ) )
+            };
+            let pixel_type = match args.options.get( "type" ).map( |s| &s[..] ) {
+                Some( "u16" ) => PixelType::Short16,
+                Some( "f32" ) => PixelType::Float32,
+                _ => return Err( CliError::Usage( "`import` needs --type u16 or --type f32".to_string() ) )
+            };
+            let options = RawOptions::default().with_byte_order( try!( args.byte_order() ) )
+                                               .with_header_bytes( try!( args.integer( "skip" ) ).unwrap_or( 0 ) )
+                                               .with_row_padding( try!( args.integer( "padding" ) ).unwrap_or( 0 ) );
+            try!( import( args, output, size, pixel_type, options ) );
+        },
+        "export" => {
+            try!( args.expect_files( 1 ) );
+            let output = try!( args.output() );
+            let options = RawOptions::default().with_byte_order( try!( args.byte_order() ) )
+                                               .with_row_padding( try!( args.integer( "padding" ) ).unwrap_or( 0 ) );
+            try!( export( args, output, options ) );
+        },
+        "png" => {
+            try!( args.expect_files( 1 ) );
+            try!( png( args, try!( args.output() ) ) );
+        },
+        "preview" => {
+            try!( args.expect_files( 1 ) );
+            let output = try!( args.output() );
+            let window = match (try!( args.pair( "window" ) ), try!( args.pair( "percentile" ) )) {
+                (Some( _ ), Some( _ )) => return Err( CliError::Usage( "--window and --percentile exclude each other".to_string() ) ),
+                (Some( (level, width) ), None) if width > 0.0 => Window::Level { level: level, width: width },
+                (Some( (_, width) ), None) => return Err( CliError::Usage( format!( "invalid window width `{}`", width ) ) ),
+                (None, Some( (low, high) )) if 0.0 <= low && low < high && high <= 100.0 => Window::Percentile { low: low, high: high },
+                (None, Some( (low, high) )) => return Err( CliError::Usage( format!( "invalid percentiles {},{}", low, high ) ) ),
+                (None, None) => Window::auto(),
+            };
+            let palette = match args.options.get( "palette" ) {
+                Some( name ) => match Palette::from_name( name ) {
+                    Some( palette ) => palette,
+                    None => return Err( CliError::Usage( format!( "unknown palette `{}`", name ) ) )
+                },
+                None => Palette::Gray,
+            };
+            try!( preview( args, output, &Preview { window: window, palette: palette } ) );
+        },
+        "mask" => {
+            try!( args.expect_files( 1 ) );
+            try!( mask( args, try!( args.output() ), try!( args.threshold() ) ) );
+        },
+        "threshold" => {
+            try!( args.expect_files( 1 ) );
+            try!( threshold( args, try!( args.threshold() ), try!( args.group_by() ) ) );
+        },
+        "calibrate" => {
+            try!( args.expect_files( 1 ) );
+            let output = try!( args.output() );
+            let (dark, flat) = match (args.options.get( "dark" ), args.options.get( "flat" )) {
+                (Some( dark ), Some( flat )) => (dark, flat),
+                _ => return Err( CliError::Usage( "`calibrate` needs --dark and --flat".to_string() ) )
+            };
+            let gain = try!( args.pair( "gain" ) ).unwrap_or( calibration::DEFAULT_GAIN_LIMITS );
+            try!( calibrate( args, output, dark, flat, gain ) );
+        },
+        "correct" => {
+            try!( args.expect_files( 1 ) );
+            let output = try!( args.output() );
+            if !args.options.contains_key( "mask" ) {
+                return Err( CliError::Usage( "`correct` needs --mask".to_string() ) )
+            }
+            let correction = match args.options.get( "method" ).map( |s| &s[..] ) {
+                Some( "average" ) => Correction::NeighborAverage,
+                Some( "median3" ) => Correction::Median( 1 ),
+                Some( "median5" ) => Correction::Median( 2 ),
+                Some( "rows" ) => Correction::Interpolate( Axis::Horizontal ),
+                Some( "columns" ) => Correction::Interpolate( Axis::Vertical ),
+                None | Some( "cluster" ) => Correction::Cluster,
+                Some( other ) => return Err( CliError::Usage( format!( "unknown correction method `{}`", other ) ) )
+            };
+            try!( correct( args, output, correction ) );
+        },
+        "defects" => {
+            try!( args.expect_files( 0 ) );
+            let output = try!( args.output() );
+            let (dark, flat) = match (args.options.get( "dark" ), args.options.get( "flat" )) {
+                (Some( dark ), Some( flat )) => (dark, flat),
+                _ => return Err( CliError::Usage( "`defects` needs --dark and --flat".to_string() ) )
+            };
+            let radius = match try!( args.number( "radius" ) ) {
+                Some( r ) if r >= 1.0 && r.fract() == 0.0 => r as u32,
+                Some( r ) => return Err( CliError::Usage( format!( "invalid radius `{}`", r ) ) ),
+                None => 2,
+            };
+            let detector = match (try!( args.number( "sigma" ) ), try!( args.number( "absolute" ) )) {
+                (Some( _ ), Some( _ )) => return Err( CliError::Usage( "--sigma and --absolute exclude each other".to_string() ) ),
+                (None, Some( v )) => Detector::absolute( v, radius ),
+                (k, None) => Detector::sigma( k.unwrap_or( 5.0 ), radius ),
+            };
+            try!( defects( output, dark, flat, detector ) );
+        },
+        c => return Err( CliError::Usage( format!( "unknown command `{}`", c ) ) )
+    }
+    Ok(())
+}
+
+
+/// Maps an error to the process exit status documented in `USAGE`
+fn exit_code( err: &CliError ) -> i32 {
+    match *err {
+        CliError::Usage(..) => EXIT_USAGE,
+        CliError::Image(ImageError::FormatError(..)) => EXIT_FORMAT,
+        CliError::Image(ImageError::HeaderError(..)) => EXIT_FORMAT,
+        CliError::Image(ImageError::DimensionMismatch(..)) => EXIT_DIMENSIONS,
+        CliError::Image(ImageError::ImageEnd) => EXIT_FORMAT,
+        CliError::Image(ImageError::IoError(..)) => EXIT_IO,
+    }
 }
 
 
 fn main() {
-    let inpfile = Path::new( r#"dsr_test_f32.idp"# );
-    make_test_idp( inpfile );
-    read_test_idp( inpfile ).unwrap();
+    let result = Args::parse( env::args().skip( 1 ).collect() ).map_err( CliError::Usage ).and_then( |args| {
+        if args.options.contains_key( "help" ) || args.command == "help" {
+            println!( "{}", USAGE );
+            Ok(())
+        } else {
+            run( &args )
+        }
+    });
+
+    if let Err( e ) = result {
+        let mut stderr = io::stderr();
+        match e {
+            CliError::Usage( ref msg ) => { let _ = writeln!( stderr, "error: {}\n\n{}", msg, USAGE ); },
+            CliError::Image( ref err ) => { let _ = writeln!( stderr, "error: {}", err ); },
+        }
+        process::exit( exit_code( &e ) );
+    }
 }
@@ -0,0 +1,246 @@
+//! Dead pixel masks
+//!
+//! Statistics, threshold counts, profiles and histograms skip masked pixels.
+//! `arithmetic::combine` leaves them at the value of the first image, `arithmetic::combine_to_float`
+//! makes them NaN.
+
+use num::{ Zero, ToPrimitive };
+
+use buffer::{ ImageBuffer, Gray16Image };
+use image::error::{ ImageError, ImageResult };
+use image::other::GrayU16;
+use traits::{ Pixel, GenericImageView };
+
+
+const WORD_BITS: usize = 64;
+
+/// A bitmap of dead pixels, sized to the dimensions of an image.
+///
+/// A set bit marks a dead pixel, which is skipped by statistics,
+/// threshold counts and row/column profiles.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PixelMask {
+    width: u32,
+    height: u32,
+    bits: Vec<u64>,
+}
+
+impl PixelMask {
+    /// Creates a mask of the given size with no pixel masked
+    pub fn new(width: u32, height: u32) -> PixelMask {
+        let len = width as usize * height as usize;
+        PixelMask {
+            width: width,
+            height: height,
+            bits: vec![0; (len + WORD_BITS - 1) / WORD_BITS],
+        }
+    }
+
+    /// Creates an empty mask with the dimensions of `image`
+    pub fn for_image<I: GenericImageView>(image: &I) -> PixelMask {
+        let (width, height) = image.dimensions();
+        PixelMask::new(width, height)
+    }
+
+    /// Creates a mask by calling `f` for the coordinates of every pixel,
+    /// masking those for which it returns true.
+    pub fn from_fn<F>(width: u32, height: u32, f: F) -> PixelMask
+    where F: Fn(u32, u32) -> bool {
+        let mut mask = PixelMask::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                if f(x, y) {
+                    mask.set(x, y, true);
+                }
+            }
+        }
+        mask
+    }
+
+    /// Creates a mask from an image, masking every pixel that is not zero
+    pub fn from_image<I: GenericImageView>(image: &I) -> PixelMask {
+        let (width, height) = image.dimensions();
+        PixelMask::from_fn(width, height, |x, y| !image.get_pixel(x, y).value().is_zero())
+    }
+
+    /// Converts the mask to an image holding 1 for masked and 0 for valid pixels
+    pub fn to_image(&self) -> Gray16Image {
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            GrayU16(if self.is_masked(x, y) { 1 } else { 0 })
+        })
+    }
+
+    /// Returns the part of the mask covering the rectangle at (x, y) of the given size,
+    /// e.g. to use the mask of a frame with a `SubImage` of it
+    ///
+    /// # Panics
+    ///
+    /// Panics if the rectangle is not inside the mask.
+    pub fn sub_mask(&self, x: u32, y: u32, width: u32, height: u32) -> PixelMask {
+        let inside = |start: u32, len: u32, limit: u32| start.checked_add(len).map_or(false, |end| end <= limit);
+        assert!(inside(x, width, self.width) && inside(y, height, self.height),
+                "{}x{} at ({}, {}) is outside the {}x{} mask", width, height, x, y, self.width, self.height);
+        PixelMask::from_fn(width, height, |sx, sy| self.is_masked(x + sx, y + sy))
+    }
+
+    /// The width and height of this mask.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    #[inline(always)]
+    fn index(&self, x: u32, y: u32) -> usize {
+        assert!(x < self.width && y < self.height,
+                "({}, {}) is outside the {}x{} mask", x, y, self.width, self.height);
+        y as usize * self.width as usize + x as usize
+    }
+
+    /// Returns true if the pixel at (x, y) is masked
+    ///
+    /// # Panics
+    ///
+    /// Panics if `(x, y)` is out of bounds.
+    #[inline(always)]
+    pub fn is_masked(&self, x: u32, y: u32) -> bool {
+        let i = self.index(x, y);
+        self.bits[i / WORD_BITS] & (1 << (i % WORD_BITS)) != 0
+    }
+
+    /// Masks or unmasks the pixel at (x, y)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `(x, y)` is out of bounds.
+    pub fn set(&mut self, x: u32, y: u32, masked: bool) {
+        let i = self.index(x, y);
+        if masked {
+            self.bits[i / WORD_BITS] |= 1 << (i % WORD_BITS);
+        } else {
+            self.bits[i / WORD_BITS] &= !(1 << (i % WORD_BITS));
+        }
+    }
+
+    /// Returns the number of masked pixels
+    pub fn count(&self) -> usize {
+        self.bits.iter().fold(0, |acc, w| acc + w.count_ones() as usize)
+    }
+
+    /// Returns the coordinates of all masked pixels in row major order
+    pub fn masked_pixels(&self) -> Vec<(u32, u32)> {
+        let mut out = Vec::with_capacity(self.count());
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.is_masked(x, y) {
+                    out.push((x, y));
+                }
+            }
+        }
+        out
+    }
+
+    /// Groups the masked pixels into clusters of 8-connected pixels.
+    /// Clusters are ordered by their first pixel in row major order, as are the pixels within.
+    pub fn clusters(&self) -> Vec<Vec<(u32, u32)>> {
+        let mut seen = PixelMask::new(self.width, self.height);
+        let mut clusters = Vec::new();
+        for (x, y) in self.masked_pixels() {
+            if seen.is_masked(x, y) {
+                continue
+            }
+            seen.set(x, y, true);
+            let mut cluster = Vec::new();
+            let mut todo = vec![(x, y)];
+            while let Some((cx, cy)) = todo.pop() {
+                cluster.push((cx, cy));
+                for ny in cy.saturating_sub(1)..::std::cmp::min(cy + 2, self.height) {
+                    for nx in cx.saturating_sub(1)..::std::cmp::min(cx + 2, self.width) {
+                        if self.is_masked(nx, ny) && !seen.is_masked(nx, ny) {
+                            seen.set(nx, ny, true);
+                            todo.push((nx, ny));
+                        }
+                    }
+                }
+            }
+            cluster.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)));
+            clusters.push(cluster);
+        }
+        clusters
+    }
+
+    /// Returns a mask with every pixel flipped
+    pub fn invert(&self) -> PixelMask {
+        let mut out = PixelMask {
+            width: self.width,
+            height: self.height,
+            bits: self.bits.iter().map(|w| !w).collect(),
+        };
+        out.clear_padding();
+        out
+    }
+
+    /// Returns a mask of the pixels masked in `self` or `other`
+    pub fn union(&self, other: &PixelMask) -> ImageResult<PixelMask> {
+        self.combine(other, |a, b| a | b)
+    }
+
+    /// Returns a mask of the pixels masked in both `self` and `other`
+    pub fn intersection(&self, other: &PixelMask) -> ImageResult<PixelMask> {
+        self.combine(other, |a, b| a & b)
+    }
+
+    /// Returns an error if `dimensions` differ from those of this mask
+    pub fn check_dimensions(&self, dimensions: (u32, u32)) -> ImageResult<()> {
+        if self.dimensions() == dimensions {
+            Ok(())
+        } else {
+            Err(ImageError::DimensionMismatch(self.dimensions(), dimensions))
+        }
+    }
+
+    fn combine<F>(&self, other: &PixelMask, f: F) -> ImageResult<PixelMask>
+    where F: Fn(u64, u64) -> u64 {
+        try!(self.check_dimensions(other.dimensions()));
+        Ok(PixelMask {
+            width: self.width,
+            height: self.height,
+            bits: self.bits.iter().zip(other.bits.iter()).map(|(&a, &b)| f(a, b)).collect(),
+        })
+    }
+
+    /// Clears the unused bits of the last word so `count` and `==` stay exact
+    fn clear_padding(&mut self) {
+        let len = self.width as usize * self.height as usize;
+        if len % WORD_BITS != 0 {
+            if let Some(last) = self.bits.last_mut() {
+                *last &= (1 << (len % WORD_BITS)) - 1;
+            }
+        }
+    }
+}
+
+
+/// Returns true if `mask` is given and masks the pixel at (x, y)
+#[inline(always)]
+pub fn is_masked(mask: Option<&PixelMask>, x: u32, y: u32) -> bool {
+    match mask {
+        Some(m) => m.is_masked(x, y),
+        None => false
+    }
+}
+
+/// Panics unless `mask` is absent or matches the dimensions of `image`
+pub fn assert_fits<I: GenericImageView>(mask: Option<&PixelMask>, image: &I) {
+    if let Some(m) = mask {
+        assert!(m.dimensions() == image.dimensions(),
+                "mask is {:?} but the image is {:?}", m.dimensions(), image.dimensions());
+    }
+}
+
+/// Returns the values of all pixels of `image` not masked by `mask`, widened to f64
+pub fn unmasked_values<I: GenericImageView>(image: &I, mask: Option<&PixelMask>) -> Vec<f64> {
+    assert_fits(mask, image);
+    image.pixels()
+         .filter(|&(x, y, _)| !is_masked(mask, x, y))
+         .map(|(_, _, p)| p.value().to_f64().unwrap())
+         .collect()
+}
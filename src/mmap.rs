@@ -0,0 +1,202 @@
+//! Memory-mapped, zero-copy access to IDP files
+
+use std::fs::File;
+use std::io::Cursor;
+use std::mem;
+use std::path::Path;
+use std::slice;
+
+use byteorder::{ self, LittleEndian };
+use memmap::Mmap;
+
+use buffer::ImageBuffer;
+use decoder::{ IDPDecoder, ImageDecoder, HEADER_BYTES, bytes_per_pixel };
+use dynimage::DynamicIdpImage;
+use image::error::ImageResult;
+use image::other::{ PixelType, GrayU16, GrayF32 };
+
+
+/// An IDP file mapped into memory.
+///
+/// The header of the first frame is validated on `open`, after which its pixels can be borrowed
+/// as an `ImageBuffer` without reading or copying the file.
+pub struct MappedIdp {
+    map: Mmap,
+    width: u32,
+    height: u32,
+    pixel_type: PixelType,
+}
+
+impl MappedIdp {
+    /// Maps the IDP file at `path` and validates its header
+    pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<MappedIdp> {
+        let f = try!(File::open(path));
+        // Safe as long as no other process truncates the file while it is mapped
+        let map = try!(unsafe { Mmap::map(&f) });
+        let (width, height, pixel_type) = {
+            let mut decoder = try!(IDPDecoder::new(Cursor::new(&map[..])));
+            let (width, height) = try!(decoder.dimensions());
+            (width, height, try!(decoder.pixel_type()))
+        };
+        Ok(MappedIdp {
+            map: map,
+            width: width,
+            height: height,
+            pixel_type: pixel_type,
+        })
+    }
+
+    /// The width and height of the image.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// The pixel type of the image.
+    pub fn pixel_type(&self) -> PixelType {
+        self.pixel_type
+    }
+
+    /// Returns the little endian pixel data of the first frame
+    pub fn pixel_bytes(&self) -> &[u8] {
+        let len = self.width as usize * self.height as usize * bytes_per_pixel(self.pixel_type) as usize;
+        &self.map[HEADER_BYTES as usize .. HEADER_BYTES as usize + len]
+    }
+
+    /// Reinterprets the pixel data as a slice of `T`.
+    /// Returns None on big endian hosts or if the data is not aligned for `T`.
+    fn cast_pixels<T>(&self) -> Option<&[T]> {
+        let bytes = self.pixel_bytes();
+        if cfg!(target_endian = "big") || bytes.as_ptr() as usize % mem::align_of::<T>() != 0 {
+            return None
+        }
+        // The header check guarantees the length is a multiple of the pixel size
+        Some(unsafe { slice::from_raw_parts(bytes.as_ptr() as *const T, bytes.len() / mem::size_of::<T>()) })
+    }
+
+    /// Borrows the pixels of a u16 file without copying them.
+    /// Returns None for f32 files, on big endian hosts, or if the mapping is misaligned.
+    pub fn as_gray16(&self) -> Option<ImageBuffer<GrayU16<u16>, &[u16]>> {
+        match self.pixel_type {
+            PixelType::Short16 => self.cast_pixels().and_then(|data| {
+                ImageBuffer::from_raw(self.width, self.height, data)
+            }),
+            PixelType::Float32 => None,
+        }
+    }
+
+    /// Borrows the pixels of an f32 file without copying them.
+    /// Returns None for u16 files, on big endian hosts, or if the mapping is misaligned.
+    pub fn as_gray_float(&self) -> Option<ImageBuffer<GrayF32<f32>, &[f32]>> {
+        match self.pixel_type {
+            PixelType::Float32 => self.cast_pixels().and_then(|data| {
+                ImageBuffer::from_raw(self.width, self.height, data)
+            }),
+            PixelType::Short16 => None,
+        }
+    }
+
+    /// Copies the pixels into an owned image, reading each one from its little endian bytes.
+    /// This works on every host and is the fallback when borrowing is not possible.
+    pub fn to_dynamic(&self) -> DynamicIdpImage {
+        let bytes = self.pixel_bytes();
+        match self.pixel_type {
+            PixelType::Short16 => {
+                let data = bytes.chunks(2).map(<LittleEndian as byteorder::ByteOrder>::read_u16).collect();
+                DynamicIdpImage::U16(ImageBuffer::from_raw(self.width, self.height, data).unwrap())
+            },
+            PixelType::Float32 => {
+                let data = bytes.chunks(4).map(<LittleEndian as byteorder::ByteOrder>::read_f32).collect();
+                DynamicIdpImage::F32(ImageBuffer::from_raw(self.width, self.height, data).unwrap())
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use buffer::{ ImageBuffer, GrayFloatImage };
+    use image::other::GrayF32;
+    use super::MappedIdp;
+
+    #[test]
+    fn borrowed_and_copied_pixels_match_the_file() {
+        let path = env::temp_dir().join(format!("idp_mmap_{}.idp", ::std::process::id()));
+        let image: GrayFloatImage = ImageBuffer::from_fn(5, 3, |x, y| GrayF32(x as f32 - y as f32 * 0.25));
+        image.save(&path).unwrap();
+        let mapped = MappedIdp::open(&path).unwrap();
+
+        assert_eq!(mapped.dimensions(), (5, 3));
+        assert!(mapped.as_gray16().is_none());
+        if cfg!(target_endian = "little") {
+            assert_eq!(&*mapped.as_gray_float().unwrap(), &*image);
+        }
+        assert_eq!(&*mapped.to_dynamic().to_f32(), &*image);
+        drop(mapped);
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+
+#[cfg(all(test, feature = "bench"))]
+mod bench {
+    extern crate test;
+
+    use std::env;
+    use std::fs::{ self, File };
+    use std::io::BufReader;
+    use std::path::PathBuf;
+
+    use buffer::{ ImageBuffer, Gray16Image };
+    use decoder::{ IDPDecoder, ImageDecoder };
+    use image::other::GrayU16;
+    use super::MappedIdp;
+
+    const SIZE: u32 = 2048;
+
+    /// Writes a SIZE x SIZE u16 frame and returns its path
+    fn frame(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("idp_bench_{}_{}.idp", name, ::std::process::id()));
+        let image: Gray16Image = ImageBuffer::from_fn(SIZE, SIZE, |x, y| GrayU16((x ^ y) as u16));
+        image.save(&path).unwrap();
+        path
+    }
+
+    #[bench]
+    fn bench_decoder(b: &mut test::Bencher) {
+        let path = frame("decoder");
+        b.iter(|| {
+            let f = File::open(&path).unwrap();
+            let mut decoder = IDPDecoder::new(BufReader::new(f)).unwrap();
+            test::black_box(decoder.read_image().unwrap());
+        });
+        fs::remove_file(&path).unwrap();
+        b.bytes = SIZE as u64 * SIZE as u64 * 2;
+    }
+
+    #[bench]
+    fn bench_mapped_borrow(b: &mut test::Bencher) {
+        let path = frame("borrow");
+        b.iter(|| {
+            let mapped = MappedIdp::open(&path).unwrap();
+            let image = mapped.as_gray16().unwrap();
+            test::black_box(image.iter().fold(0u64, |acc, &p| acc + p as u64));
+        });
+        fs::remove_file(&path).unwrap();
+        b.bytes = SIZE as u64 * SIZE as u64 * 2;
+    }
+
+    #[bench]
+    fn bench_mapped_copy(b: &mut test::Bencher) {
+        let path = frame("copy");
+        b.iter(|| {
+            let mapped = MappedIdp::open(&path).unwrap();
+            test::black_box(mapped.to_dynamic());
+        });
+        fs::remove_file(&path).unwrap();
+        b.bytes = SIZE as u64 * SIZE as u64 * 2;
+    }
+}
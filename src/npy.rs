@@ -0,0 +1,399 @@
+//! NumPy `.npy` arrays of u16 or f32 values, as written by `numpy.save`.
+//!
+//! A 2 dimensional array of shape (height, width) is one frame, a 3 dimensional array of shape
+//! (frames, height, width) a stack of frames. Only C order is supported; the decoder reads
+//! either byte order, the encoder writes little endian version 1.0 files.
+
+use std::io::{ Read, Write, Seek, SeekFrom };
+use std::ops::Deref;
+
+use num::NumCast;
+
+use buffer::ImageBuffer;
+use decoder::{ ImageDecoder, bytes_per_pixel };
+use encoder::ImageEncoder;
+use image::error::{ ImageError, ImageResult };
+use image::other::{ PixelType, DecodingResult };
+use stream::{ ByteOrder, EndianReader, EndianWriter, SmartReader, SmartWriter };
+use traits::{ Pixel, GenericImageView };
+
+
+/// The first bytes of every .npy file
+pub const MAGIC: &'static [u8] = b"\x93NUMPY";
+
+/// The encoder pads the header so the data starts at a multiple of this many bytes
+const ALIGNMENT: usize = 64;
+
+
+fn format_error<T>(msg: String) -> ImageResult<T> {
+    Err(ImageError::FormatError(format!("NumPy: {}", msg)))
+}
+
+
+/// Returns the text following `'key':` in the header dictionary, or None if `key` is missing
+fn value_of<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    [format!("'{}'", key), format!("\"{}\"", key)].iter()
+        .filter_map(|quoted| header.find(&quoted[..]).map(|i| &header[i + quoted.len()..]))
+        .next()
+        .and_then(|rest| {
+            let rest = rest.trim();
+            if rest.starts_with(':') { Some(rest[1..].trim()) } else { None }
+        })
+}
+
+/// Parses the `descr` of the header into the byte order and the pixel type
+fn parse_descr(header: &str) -> ImageResult<(ByteOrder, PixelType)> {
+    let value = match value_of(header, "descr") {
+        Some(value) => value,
+        None => return format_error("header has no descr".to_string()),
+    };
+    let descr = match value.chars().next() {
+        Some(quote) if quote == '\'' || quote == '"' => value[1..].split(quote).next().unwrap_or(""),
+        _ => return format_error(format!("descr is not a string: {}", value)),
+    };
+    let byte_order = match descr.chars().next() {
+        Some('<') => ByteOrder::LittleEndian,
+        Some('>') => ByteOrder::BigEndian,
+        _ => return format_error(format!("dtype `{}` has no byte order", descr)),
+    };
+    match &descr[1..] {
+        "u2" => Ok((byte_order, PixelType::Short16)),
+        "f4" => Ok((byte_order, PixelType::Float32)),
+        _ => format_error(format!("dtype `{}` is not supported, only uint16 and float32", descr)),
+    }
+}
+
+/// Parses the `shape` of the header into width, height and number of frames
+fn parse_shape(header: &str) -> ImageResult<(u32, u32, usize)> {
+    let value = match value_of(header, "shape") {
+        Some(value) if value.starts_with('(') && value.contains(')') => &value[1..value.find(')').unwrap()],
+        _ => return format_error("header has no shape".to_string()),
+    };
+    let mut shape = Vec::new();
+    for axis in value.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        match axis.trim_matches('L').parse::<u32>() {
+            Ok(n) if n > 0 => shape.push(n),
+            _ => return format_error(format!("invalid axis length `{}`", axis)),
+        }
+    }
+    match shape.len() {
+        2 => Ok((shape[1], shape[0], 1)),
+        3 => Ok((shape[2], shape[1], shape[0] as usize)),
+        n => format_error(format!("{} dimensions, expected 2 or 3", n)),
+    }
+}
+
+
+/// Decodes .npy files.
+///
+/// The decoder is always positioned on one frame, starting with the first.
+#[derive(Debug)]
+pub struct NpyDecoder<R> where R: Read + Seek {
+    reader: SmartReader<R>,
+    width: u32,
+    height: u32,
+    pixel_type: PixelType,
+    frames: usize,
+    frame: usize,
+    /// Offset of the first data byte
+    data_start: u64,
+}
+
+impl<R: Read + Seek> NpyDecoder<R> {
+    /// Create a new decoder that decodes from the stream ```r```
+    ///
+    /// The file starts at the current position of ```r```.
+    pub fn new(r: R) -> ImageResult<NpyDecoder<R>> {
+        let mut reader = SmartReader::wrap(r, ByteOrder::LittleEndian);
+        let mut magic = [0u8; 8];
+        try!(reader.read_exact(&mut magic));
+        if &magic[..6] != MAGIC {
+            return format_error("not a .npy file".to_string())
+        }
+        let header_len = match magic[6] {
+            1 => try!(reader.read_u16()) as usize,
+            2 | 3 => try!(reader.read_u32()) as usize,
+            major => return format_error(format!("version {}.{} is not supported", major, magic[7])),
+        };
+        // Versions 2 and 3 allow headers of up to 4 GiB, check the length before allocating
+        let header_start = try!(reader.seek(SeekFrom::Current(0)));
+        let stream_len = try!(reader.seek(SeekFrom::End(0)));
+        if stream_len - header_start < header_len as u64 {
+            return format_error(format!("header needs {} bytes, stream has {}", header_len, stream_len - header_start))
+        }
+        try!(reader.seek(SeekFrom::Start(header_start)));
+        let mut header = vec![0u8; header_len];
+        try!(reader.read_exact(&mut header));
+        let header = match String::from_utf8(header) {
+            Ok(header) => header,
+            Err(_) => return format_error("header is not text".to_string()),
+        };
+        if !header.trim().starts_with('{') {
+            return format_error("header is not a dictionary".to_string())
+        }
+        match value_of(&header, "fortran_order") {
+            Some(value) if value.starts_with("False") => (),
+            Some(value) if value.starts_with("True") => return format_error("Fortran order is not supported".to_string()),
+            _ => return format_error("header has no fortran_order".to_string()),
+        }
+        let (byte_order, pixel_type) = try!(parse_descr(&header));
+        let (width, height, frames) = try!(parse_shape(&header));
+
+        let data_start = try!(reader.seek(SeekFrom::Current(0)));
+        let data_bytes = match (width as u64).checked_mul(height as u64)
+                                             .and_then(|n| n.checked_mul(bytes_per_pixel(pixel_type)))
+                                             .and_then(|n| n.checked_mul(frames as u64)) {
+            Some(n) => n,
+            None => return format_error(format!("shape ({}, {}, {}) overflows the data size", frames, height, width)),
+        };
+        if stream_len - data_start < data_bytes {
+            return format_error(format!("data needs {} bytes, stream has {}", data_bytes, stream_len - data_start))
+        }
+        reader.byte_order = byte_order;
+        Ok(NpyDecoder {
+            reader: reader,
+            width: width,
+            height: height,
+            pixel_type: pixel_type,
+            frames: frames,
+            frame: 0,
+            data_start: data_start,
+        })
+    }
+
+    /// Number of frames along the first axis, 1 for a 2 dimensional array
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
+    /// Index of the current frame, starting at 0
+    pub fn current_frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Moves the decoder to frame `n`.
+    /// Returns `ImageError::ImageEnd` if the file holds `n` frames or fewer.
+    pub fn seek_frame(&mut self, n: usize) -> ImageResult<()> {
+        if n >= self.frames {
+            return Err(ImageError::ImageEnd)
+        }
+        self.frame = n;
+        Ok(())
+    }
+
+    /// Returns true if the file holds another frame after the current one.
+    pub fn more_images(&self) -> bool {
+        self.frame + 1 < self.frames
+    }
+}
+
+impl<R: Read + Seek> ImageDecoder for NpyDecoder<R> {
+    fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
+        Ok((self.width, self.height))
+    }
+
+    fn pixel_type(&mut self) -> ImageResult<PixelType> {
+        Ok(self.pixel_type)
+    }
+
+    fn read_image(&mut self) -> ImageResult<DecodingResult> {
+        let number_of_pixels = self.width as usize * self.height as usize;
+        let frame_bytes = number_of_pixels as u64 * bytes_per_pixel(self.pixel_type);
+        try!(self.reader.seek(SeekFrom::Start(self.data_start + self.frame as u64 * frame_bytes)));
+        match self.pixel_type {
+            PixelType::Short16 => {
+                let mut buffer = Vec::with_capacity(number_of_pixels);
+                for _ in 0..number_of_pixels {
+                    buffer.push(try!(self.reader.read_u16()));
+                }
+                Ok(DecodingResult::U16(buffer))
+            },
+            PixelType::Float32 => {
+                let mut buffer = Vec::with_capacity(number_of_pixels);
+                for _ in 0..number_of_pixels {
+                    buffer.push(try!(self.reader.read_f32()));
+                }
+                Ok(DecodingResult::F32(buffer))
+            },
+        }
+    }
+}
+
+
+/// Writes .npy files, an image as a 2 dimensional array or frames as a 3 dimensional one
+#[derive(Debug)]
+pub struct NpyEncoder<W> where W: Write + Seek {
+    writer: SmartWriter<W>,
+}
+
+impl<W: Write + Seek> NpyEncoder<W> {
+    /// Create a new encoder that writes to the stream ```w```
+    pub fn new(w: W) -> NpyEncoder<W> {
+        NpyEncoder {
+            writer: SmartWriter::wrap(w, ByteOrder::LittleEndian),
+        }
+    }
+
+    /// Returns the wrapped writer
+    pub fn into_inner(self) -> SmartWriter<W> {
+        self.writer
+    }
+
+    /// Writes `frames` of `width` x `height` pixels as one array of shape (frames, height, width).
+    /// All frames need the same pixel type.
+    pub fn encode_frames(&mut self, width: u32, height: u32, frames: &[DecodingResult]) -> ImageResult<()> {
+        let frames: Vec<&DecodingResult> = frames.iter().collect();
+        let shape = format!("({}, {}, {})", frames.len(), height, width);
+        self.write_array(width, height, &shape, &frames)
+    }
+
+    fn write_array(&mut self, width: u32, height: u32, shape: &str, frames: &[&DecodingResult]) -> ImageResult<()> {
+        if width == 0 || height == 0 {
+            return format_error(format!("{}x{} frames are empty", width, height))
+        }
+        let number_of_pixels = width as usize * height as usize;
+        let (pixel_type, descr) = match frames.first() {
+            Some(&&DecodingResult::U16(_)) => (PixelType::Short16, "<u2"),
+            Some(&&DecodingResult::F32(_)) => (PixelType::Float32, "<f4"),
+            None => return format_error("no frames to write".to_string()),
+        };
+        for frame in frames {
+            let (frame_type, len) = match *frame {
+                DecodingResult::U16(ref buffer) => (PixelType::Short16, buffer.len()),
+                DecodingResult::F32(ref buffer) => (PixelType::Float32, buffer.len()),
+            };
+            if frame_type != pixel_type || len != number_of_pixels {
+                return format_error(format!("every frame needs {} {:?} pixels", number_of_pixels, pixel_type))
+            }
+        }
+
+        // Magic, version and header length come first, the header ends with a newline
+        let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+        let unpadded = MAGIC.len() + 4 + header.len() + 1;
+        let padding = (ALIGNMENT - unpadded % ALIGNMENT) % ALIGNMENT;
+        header.extend(::std::iter::repeat(' ').take(padding));
+        header.push('\n');
+        if header.len() > ::std::u16::MAX as usize {
+            return format_error("header too long".to_string())
+        }
+
+        try!(self.writer.write_all(MAGIC));
+        try!(self.writer.write_all(&[1, 0]));
+        try!(self.writer.write_u16(header.len() as u16));
+        try!(self.writer.write_all(header.as_bytes()));
+        for frame in frames {
+            match **frame {
+                DecodingResult::U16(ref buffer) => for &datum in buffer {
+                    try!(self.writer.write_u16(datum));
+                },
+                DecodingResult::F32(ref buffer) => for &datum in buffer {
+                    try!(self.writer.write_f32(datum));
+                },
+            }
+        }
+        try!(self.writer.flush());
+        Ok(())
+    }
+
+    /// Writes the pixels of any image, in row major order
+    fn write_pixels<P, It>(&mut self, width: u32, height: u32, pixels: It) -> ImageResult<()>
+    where P: Pixel, It: Iterator<Item=(u32, u32, P)> {
+        let data = match <P as Pixel>::pixel_type() {
+            PixelType::Short16 => DecodingResult::U16(pixels.map(|(_, _, p)| NumCast::from(*p.value()).unwrap()).collect()),
+            PixelType::Float32 => DecodingResult::F32(pixels.map(|(_, _, p)| NumCast::from(*p.value()).unwrap()).collect()),
+        };
+        self.encode(width, height, &data)
+    }
+}
+
+impl<W: Write + Seek> ImageEncoder for NpyEncoder<W> {
+    fn encode(&mut self, width: u32, height: u32, image: &DecodingResult) -> ImageResult<()> {
+        let shape = format!("({}, {})", height, width);
+        self.write_array(width, height, &shape, &[image])
+    }
+
+    fn encode_image<I: GenericImageView>(&mut self, image: &I) -> ImageResult<()> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.pixels())
+    }
+
+    fn encode_buffer<P, C>(&mut self, image: &ImageBuffer<P, C>) -> ImageResult<()>
+    where P: Pixel + 'static, P::Subpixel: 'static, C: Deref<Target=[P::Subpixel]> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.enumerate_pixels().map(|(x, y, p)| (x, y, *p)))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use buffer::{ ImageBuffer, Gray16Image };
+    use decoder::ImageDecoder;
+    use encoder::ImageEncoder;
+    use image::other::{ PixelType, DecodingResult, GrayU16 };
+    use super::{ NpyDecoder, NpyEncoder };
+
+    #[test]
+    fn roundtrip_and_header_layout() {
+        let image: Gray16Image = ImageBuffer::from_fn(3, 2, |x, y| GrayU16((x + 10 * y) as u16));
+        let mut encoder = NpyEncoder::new(Cursor::new(Vec::new()));
+        encoder.encode_image(&image).unwrap();
+        let bytes = encoder.into_inner().into_inner().into_inner();
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header_len = bytes[8] as usize + bytes[9] as usize * 256;
+        assert_eq!((10 + header_len) % 64, 0);
+        assert_eq!(bytes[9 + header_len], b'\n');
+        assert!(String::from_utf8(bytes[10..10 + header_len].to_vec()).unwrap().contains("'shape': (2, 3)"));
+
+        let mut decoder = NpyDecoder::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (3, 2));
+        assert_eq!(decoder.frame_count(), 1);
+        match decoder.read_image().unwrap() {
+            DecodingResult::U16(pixels) => assert_eq!(&pixels[..], &image.into_raw()[..]),
+            _ => panic!("expected u16 pixels"),
+        }
+    }
+
+    #[test]
+    fn big_endian_stack_from_numpy() {
+        // numpy.save of numpy.array([[[1.5, 2]], [[-1, 0]]], dtype='>f4'), header unpadded
+        let npy = |header: &str, data: &[u8]| {
+            let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+            bytes.push(header.len() as u8);
+            bytes.push(0);
+            bytes.extend(header.bytes());
+            bytes.extend(data);
+            Cursor::new(bytes)
+        };
+        let header = "{'descr': '>f4', 'fortran_order': False, 'shape': (2, 1, 2), }\n";
+        let data = [0x3f, 0xc0, 0, 0,  0x40, 0, 0, 0,  0xbf, 0x80, 0, 0,  0, 0, 0, 0];
+        let mut decoder = NpyDecoder::new(npy(header, &data)).unwrap();
+        assert_eq!(decoder.pixel_type().unwrap(), PixelType::Float32);
+        assert_eq!(decoder.frame_count(), 2);
+        decoder.seek_frame(1).unwrap();
+        match decoder.read_image().unwrap() {
+            DecodingResult::F32(pixels) => assert_eq!(pixels, vec![-1.0, 0.0]),
+            _ => panic!("expected f32 pixels"),
+        }
+
+        assert!(NpyDecoder::new(npy(&header.replace("False", "True"), &data)).is_err());
+        assert!(NpyDecoder::new(npy(&header.replace("f4", "i4"), &data)).is_err());
+        assert!(NpyDecoder::new(npy(header, &data[1..])).is_err());
+    }
+
+    #[test]
+    fn huge_header_and_shape_are_errors() {
+        // A version 2 header claiming 4 GiB in a file of a few bytes
+        let bytes = b"\x93NUMPY\x02\x00\xff\xff\xff\xff{}".to_vec();
+        assert!(NpyDecoder::new(Cursor::new(bytes)).is_err());
+
+        let header = "{'descr': '<f4', 'fortran_order': False, 'shape': (4294967295, 4294967295, 4294967295), }\n";
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.push(header.len() as u8);
+        bytes.push(0);
+        bytes.extend(header.bytes());
+        assert!(NpyDecoder::new(Cursor::new(bytes)).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+//! PNG output: lossless 16 bit grayscale and 8 bit previews.
+//!
+//! The pixel data is stored in uncompressed deflate blocks, so every file is a little larger
+//! than the raw pixels but any PNG reader can open it.
+
+use std::io::Write;
+use std::ops::Deref;
+
+use num::NumCast;
+
+use buffer::ImageBuffer;
+use encoder::ImageEncoder;
+use image::error::{ ImageError, ImageResult };
+use image::other::{ PixelType, DecodingResult };
+use mask::PixelMask;
+use preview::{ self, Preview };
+use traits::{ Pixel, GenericImageView };
+
+
+/// The eight bytes every PNG file starts with
+const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/// Largest payload of a stored deflate block
+const MAX_STORED_BLOCK: usize = 65535;
+
+/// PNG color types
+const GRAYSCALE: u8 = 0;
+const INDEXED: u8 = 3;
+
+
+/// Table of the CRC-32 used by PNG chunks, one entry per byte value
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for n in 0..256 {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 == 1 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+        }
+        table[n] = c;
+    }
+    table
+}
+
+/// Computes the CRC-32 of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let table = crc32_table();
+    !bytes.iter().fold(0xffffffff, |c, &b| table[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8))
+}
+
+/// Computes the Adler-32 checksum of `bytes` that ends a zlib stream
+pub fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 is the largest run for which b cannot overflow before the modulo
+    for chunk in bytes.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// Wraps `data` in a zlib stream of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = ::std::cmp::max(1, (data.len() + MAX_STORED_BLOCK - 1) / MAX_STORED_BLOCK);
+    let mut out = Vec::with_capacity(data.len() + blocks * 5 + 6);
+    // 32K window, no preset dictionary, fastest compression; 0x7801 is a multiple of 31
+    out.push(0x78);
+    out.push(0x01);
+    let mut chunks: Vec<&[u8]> = data.chunks(MAX_STORED_BLOCK).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let last = chunks.len() - 1;
+    for (i, chunk) in chunks.iter().enumerate() {
+        let len = chunk.len() as u16;
+        out.push(if i == last { 1 } else { 0 });
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&be32(adler32(data)));
+    out
+}
+
+fn be32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+
+/// Writes images as PNG files
+pub struct PngEncoder<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PngEncoder<W> {
+    /// Create a new encoder that writes to the stream ```w```
+    pub fn new(w: W) -> PngEncoder<W> {
+        PngEncoder { writer: w }
+    }
+
+    /// Returns the wrapped writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_chunk(&mut self, kind: &[u8; 4], data: &[u8]) -> ImageResult<()> {
+        let mut checked = Vec::with_capacity(data.len() + 4);
+        checked.extend_from_slice(kind);
+        checked.extend_from_slice(data);
+        try!(self.writer.write_all(&be32(data.len() as u32)));
+        try!(self.writer.write_all(&checked));
+        try!(self.writer.write_all(&be32(crc32(&checked))));
+        Ok(())
+    }
+
+    /// Writes a complete file. `samples` holds the rows without filter bytes,
+    /// `palette` the RGB triples of an indexed image.
+    fn write_png(&mut self, width: u32, height: u32, bit_depth: u8, color_type: u8,
+                 palette: Option<&[u8]>, samples: &[u8]) -> ImageResult<()> {
+        if width == 0 || height == 0 {
+            return Err(ImageError::FormatError("PNG images cannot be empty".to_string()))
+        }
+        let row_len = width as usize * (bit_depth as usize / 8);
+        if samples.len() != row_len * height as usize {
+            return Err(ImageError::FormatError(
+                format!("{}x{} image needs {} bytes, got {}", width, height, row_len * height as usize, samples.len())
+            ))
+        }
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&be32(width));
+        header.extend_from_slice(&be32(height));
+        // bit depth, color type, deflate, adaptive filtering, no interlace
+        header.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+
+        // Every row is preceded by filter type 0, no filtering
+        let mut filtered = Vec::with_capacity(samples.len() + height as usize);
+        for row in samples.chunks(row_len) {
+            filtered.push(0);
+            filtered.extend_from_slice(row);
+        }
+
+        try!(self.writer.write_all(&SIGNATURE));
+        try!(self.write_chunk(b"IHDR", &header));
+        if let Some(palette) = palette {
+            try!(self.write_chunk(b"PLTE", palette));
+        }
+        try!(self.write_chunk(b"IDAT", &zlib_stored(&filtered)));
+        try!(self.write_chunk(b"IEND", &[]));
+        try!(self.writer.flush());
+        Ok(())
+    }
+
+    /// Writes 16 bit gray values, row major
+    pub fn encode_gray16(&mut self, width: u32, height: u32, pixels: &[u16]) -> ImageResult<()> {
+        let mut samples = Vec::with_capacity(pixels.len() * 2);
+        for &p in pixels {
+            samples.push((p >> 8) as u8);
+            samples.push(p as u8);
+        }
+        self.write_png(width, height, 16, GRAYSCALE, None, &samples)
+    }
+
+    /// Writes 8 bit gray values, row major
+    pub fn encode_gray8(&mut self, width: u32, height: u32, pixels: &[u8]) -> ImageResult<()> {
+        self.write_png(width, height, 8, GRAYSCALE, None, pixels)
+    }
+
+    /// Writes 8 bit indices into `palette`, row major
+    pub fn encode_indexed(&mut self, width: u32, height: u32, pixels: &[u8], palette: &[[u8; 3]]) -> ImageResult<()> {
+        if palette.is_empty() || palette.len() > 256 {
+            return Err(ImageError::FormatError(format!("a PNG palette holds 1 to 256 colors, got {}", palette.len())))
+        }
+        if let Some(&i) = pixels.iter().find(|&&i| i as usize >= palette.len()) {
+            return Err(ImageError::FormatError(format!("index {} outside the palette", i)))
+        }
+        let rgb: Vec<u8> = palette.iter().flat_map(|c| c.iter().cloned()).collect();
+        self.write_png(width, height, 8, INDEXED, Some(&rgb), pixels)
+    }
+
+    /// Writes an 8 bit rendering of any image, windowed and colored by `preview`.
+    /// Gray palettes give a grayscale file, the others an indexed one.
+    /// `mask` only excludes pixels from the percentiles of the window.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the mask and the image differ in size.
+    pub fn encode_preview<I: GenericImageView>(&mut self, image: &I, mask: Option<&PixelMask>,
+                                               preview: &Preview) -> ImageResult<()> {
+        let (width, height) = image.dimensions();
+        let levels = preview::render(image, mask, preview.window);
+        let palette = preview.palette;
+        if palette.is_gray() {
+            let gray: Vec<u8> = levels.iter().map(|&l| palette.color(l)[0]).collect();
+            self.encode_gray8(width, height, &gray)
+        } else {
+            let colors: Vec<[u8; 3]> = (0..256).map(|l| palette.color(l as u8)).collect();
+            self.encode_indexed(width, height, &levels, &colors)
+        }
+    }
+
+    /// Writes the pixels of a u16 image losslessly, failing for any other pixel type
+    fn write_pixels<P, It>(&mut self, width: u32, height: u32, pixels: It) -> ImageResult<()>
+    where P: Pixel, It: Iterator<Item=(u32, u32, P)> {
+        match <P as Pixel>::pixel_type() {
+            PixelType::Short16 => {
+                let values: Vec<u16> = pixels.map(|(_, _, p)| NumCast::from(*p.value()).unwrap()).collect();
+                self.encode_gray16(width, height, &values)
+            },
+            PixelType::Float32 => Err(lossless_float_error()),
+        }
+    }
+}
+
+fn lossless_float_error() -> ImageError {
+    ImageError::FormatError("PNG cannot hold f32 pixels losslessly, write a preview instead".to_string())
+}
+
+
+impl<W: Write> ImageEncoder for PngEncoder<W> {
+    fn encode(&mut self, width: u32, height: u32, image: &DecodingResult) -> ImageResult<()> {
+        match *image {
+            DecodingResult::U16(ref buffer) => self.encode_gray16(width, height, buffer),
+            DecodingResult::F32(_) => Err(lossless_float_error()),
+        }
+    }
+
+    fn encode_image<I: GenericImageView>(&mut self, image: &I) -> ImageResult<()> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.pixels())
+    }
+
+    fn encode_buffer<P, C>(&mut self, image: &ImageBuffer<P, C>) -> ImageResult<()>
+    where P: Pixel + 'static, P::Subpixel: 'static, C: Deref<Target=[P::Subpixel]> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.enumerate_pixels().map(|(x, y, p)| (x, y, *p)))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+    use encoder::ImageEncoder;
+    use image::other::{ GrayU16, GrayF32 };
+    use preview::{ Preview, Palette, Window };
+    use super::{ PngEncoder, crc32, adler32 };
+
+    /// Splits a PNG file into its chunks, checking their CRCs
+    fn chunks(png: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(&png[..8], &[137, 80, 78, 71, 13, 10, 26, 10]);
+        let mut chunks = Vec::new();
+        let mut pos = 8;
+        while pos < png.len() {
+            let be = |b: &[u8]| (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32;
+            let len = be(&png[pos..]) as usize;
+            let body = &png[pos + 4..pos + 8 + len];
+            assert_eq!(be(&png[pos + 8 + len..]), crc32(body));
+            chunks.push((String::from_utf8(body[..4].to_vec()).unwrap(), body[4..].to_vec()));
+            pos += 12 + len;
+        }
+        chunks
+    }
+
+    /// Unpacks the stored deflate blocks of a zlib stream
+    fn inflate_stored(zlib: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut pos = 2;
+        loop {
+            let last = zlib[pos] & 1 == 1;
+            let len = zlib[pos + 1] as usize | (zlib[pos + 2] as usize) << 8;
+            out.extend_from_slice(&zlib[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+            if last {
+                break
+            }
+        }
+        assert_eq!(&zlib[pos..], &super::be32(adler32(&out)));
+        out
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+
+    #[test]
+    fn gray16_is_lossless() {
+        let image: Gray16Image = ImageBuffer::from_fn(3, 2, |x, y| GrayU16(x as u16 * 0x1234 + y as u16 * 0x8000));
+        let mut encoder = PngEncoder::new(Vec::new());
+        encoder.encode_image(&image).unwrap();
+        let chunks = chunks(&encoder.into_inner());
+
+        let names: Vec<&str> = chunks.iter().map(|c| &c.0[..]).collect();
+        assert_eq!(names, vec!["IHDR", "IDAT", "IEND"]);
+        assert_eq!(chunks[0].1, vec![0, 0, 0, 3, 0, 0, 0, 2, 16, 0, 0, 0, 0]);
+        assert_eq!(inflate_stored(&chunks[1].1), vec![0, 0x00, 0x00, 0x12, 0x34, 0x24, 0x68,
+                                                      0, 0x80, 0x00, 0x92, 0x34, 0xa4, 0x68]);
+
+        let float: GrayFloatImage = ImageBuffer::from_pixel(1, 1, GrayF32(0.5));
+        assert!(PngEncoder::new(Vec::new()).encode_image(&float).is_err());
+    }
+
+    #[test]
+    fn false_color_preview_is_indexed() {
+        let image: GrayFloatImage = ImageBuffer::from_fn(300, 300, |x, _| GrayF32(x as f32));
+        let preview = Preview { window: Window::Level { level: 100.0, width: 200.0 }, palette: Palette::Hot };
+        let mut encoder = PngEncoder::new(Vec::new());
+        encoder.encode_preview(&image, None, &preview).unwrap();
+        let chunks = chunks(&encoder.into_inner());
+
+        assert_eq!(&chunks[0].1[8..10], &[8, 3]);
+        assert_eq!(chunks[1].0, "PLTE");
+        assert_eq!(&chunks[1].1[765..], &[255, 255, 255]);
+        // More than one stored block
+        let rows = inflate_stored(&chunks[2].1);
+        assert_eq!(rows.len(), 301 * 300);
+        assert_eq!(&rows[..3], &[0, 0, 1]);
+        assert_eq!(rows[301], 0);
+        assert_eq!(rows[300], 255);
+    }
+}
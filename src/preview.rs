@@ -0,0 +1,173 @@
+//! 8-bit renderings of images for display: windowing and palettes
+
+use num::ToPrimitive;
+
+use mask::{ PixelMask, assert_fits, unmasked_values };
+use stats::{ sort, percentile };
+use traits::{ Pixel, GenericImageView };
+
+
+/// The range of values spread over the 256 levels of a preview
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Window {
+    /// Values from `level - width / 2` to `level + width / 2` span black to white
+    Level { level: f64, width: f64 },
+    /// The `low` and `high` percentiles of the valid values span black to white
+    Percentile { low: f64, high: f64 },
+}
+
+impl Window {
+    /// Auto-contrast that clips the darkest and brightest 0.5% of the pixels
+    pub fn auto() -> Window {
+        Window::Percentile { low: 0.5, high: 99.5 }
+    }
+
+    /// Returns the values that become black and white in a preview of `image`.
+    /// Percentiles skip pixels masked by `mask` and NaN; if no value is left the range is 0 to 1.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the mask and the image differ in size.
+    pub fn limits<I: GenericImageView>(&self, image: &I, mask: Option<&PixelMask>) -> (f64, f64) {
+        match *self {
+            Window::Level { level, width } => (level - width / 2.0, level + width / 2.0),
+            Window::Percentile { low, high } => {
+                let mut values = unmasked_values(image, mask);
+                values.retain(|v| !v.is_nan());
+                if values.is_empty() {
+                    return (0.0, 1.0)
+                }
+                sort(&mut values);
+                (percentile(&values, low), percentile(&values, high))
+            }
+        }
+    }
+}
+
+impl Default for Window {
+    fn default() -> Window {
+        Window::auto()
+    }
+}
+
+
+/// How the 256 levels of a preview are colored
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Palette {
+    /// Black to white
+    Gray,
+    /// White to black
+    Inverted,
+    /// Black through red and yellow to white
+    Hot,
+    /// Blue through cyan, yellow and red to dark red
+    Jet,
+}
+
+impl Palette {
+    /// Looks a palette up by its lower case name
+    pub fn from_name(name: &str) -> Option<Palette> {
+        match name {
+            "gray" => Some(Palette::Gray),
+            "inverted" => Some(Palette::Inverted),
+            "hot" => Some(Palette::Hot),
+            "jet" => Some(Palette::Jet),
+            _ => None
+        }
+    }
+
+    /// Whether all colors are shades of gray, so a preview needs no color information
+    pub fn is_gray(&self) -> bool {
+        match *self {
+            Palette::Gray | Palette::Inverted => true,
+            Palette::Hot | Palette::Jet => false,
+        }
+    }
+
+    /// The red, green and blue components of `level`
+    pub fn color(&self, level: u8) -> [u8; 3] {
+        let t = level as f64 / 255.0;
+        let channel = |v: f64| (v.max(0.0).min(1.0) * 255.0).round() as u8;
+        match *self {
+            Palette::Gray => [level, level, level],
+            Palette::Inverted => [255 - level, 255 - level, 255 - level],
+            Palette::Hot => [channel(3.0 * t), channel(3.0 * t - 1.0), channel(3.0 * t - 2.0)],
+            Palette::Jet => [channel(1.5 - (4.0 * t - 3.0).abs()),
+                             channel(1.5 - (4.0 * t - 2.0).abs()),
+                             channel(1.5 - (4.0 * t - 1.0).abs())],
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::Gray
+    }
+}
+
+
+/// How an image is rendered for display
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Preview {
+    pub window: Window,
+    pub palette: Palette,
+}
+
+
+/// Maps `value` to one of 256 levels, 0 at or below `low` and 255 at or above `high`. NaN becomes 0.
+#[inline(always)]
+pub fn level(value: f64, low: f64, high: f64) -> u8 {
+    if value.is_nan() {
+        0
+    } else if !(high > low) {
+        if value >= high { 255 } else { 0 }
+    } else {
+        ((value - low) / (high - low) * 255.0).round().max(0.0).min(255.0) as u8
+    }
+}
+
+/// Returns the levels of the pixels of `image` in row major order, windowed by `window`.
+/// `mask` only excludes pixels from the percentiles of the window.
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size.
+pub fn render<I: GenericImageView>(image: &I, mask: Option<&PixelMask>, window: Window) -> Vec<u8> {
+    assert_fits(mask, image);
+    let (low, high) = window.limits(image, mask);
+    image.pixels().map(|(_, _, p)| level(p.value().to_f64().unwrap(), low, high)).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use buffer::{ ImageBuffer, GrayFloatImage };
+    use image::other::GrayF32;
+    use mask::PixelMask;
+    use super::{ render, Window, Palette };
+
+    #[test]
+    fn window_and_level() {
+        let image: GrayFloatImage = ImageBuffer::from_fn(5, 1, |x, _| {
+            GrayF32(if x == 4 { ::std::f32::NAN } else { x as f32 * 100.0 })
+        });
+        let levels = render(&image, None, Window::Level { level: 150.0, width: 200.0 });
+        assert_eq!(levels, vec![0, 64, 191, 255, 0]);
+
+        let mask = PixelMask::from_fn(5, 1, |x, _| x == 3);
+        let auto = render(&image, Some(&mask), Window::Percentile { low: 0.0, high: 100.0 });
+        assert_eq!(auto, vec![0, 128, 255, 255, 0]);
+    }
+
+    #[test]
+    fn palettes() {
+        assert_eq!(Palette::Inverted.color(0), [255, 255, 255]);
+        assert_eq!(Palette::Hot.color(0), [0, 0, 0]);
+        assert_eq!(Palette::Hot.color(255), [255, 255, 255]);
+        assert_eq!(Palette::Jet.color(0), [0, 0, 128]);
+        assert_eq!(Palette::Jet.color(255), [128, 0, 0]);
+        assert!(Palette::from_name("inverted").unwrap().is_gray());
+        assert!(!Palette::from_name("jet").unwrap().is_gray());
+        assert_eq!(Palette::from_name("rainbow"), None);
+    }
+}
@@ -0,0 +1,354 @@
+//! Headerless raw pixel dumps.
+//!
+//! A raw file holds no description of its pixels, so the geometry, pixel type and byte order
+//! are given by the caller. The pixels may follow a header of any length that is skipped,
+//! and every row, the last one included, may be followed by padding bytes. Several frames
+//! of the same geometry may follow each other.
+
+use std::io::{ Read, Write, Seek, SeekFrom };
+use std::ops::Deref;
+
+use num::NumCast;
+
+use buffer::ImageBuffer;
+use decoder::{ ImageDecoder, bytes_per_pixel };
+use encoder::ImageEncoder;
+use image::error::{ ImageError, ImageResult };
+use image::other::{ PixelType, DecodingResult };
+use stream::{ ByteOrder, EndianReader, EndianWriter, SmartReader, SmartWriter };
+use traits::{ Pixel, GenericImageView };
+
+
+/// Size of the blocks of zeros written for headers and padding
+const ZERO_BLOCK_BYTES: usize = 64 * 1024;
+
+
+/// Returns an error for an empty geometry
+fn check_geometry(width: u32, height: u32) -> ImageResult<()> {
+    if width == 0 || height == 0 {
+        return Err(ImageError::FormatError(format!("invalid raw geometry {}x{}", width, height)))
+    }
+    Ok(())
+}
+
+
+/// How pixels are laid out in a raw file, apart from the geometry and the pixel type
+#[derive(Clone, Copy, Debug)]
+pub struct RawOptions {
+    /// Byte order of the pixels
+    pub byte_order: ByteOrder,
+    /// Number of bytes before the first pixel
+    pub header_bytes: u64,
+    /// Number of bytes after every row
+    pub row_padding: u64,
+}
+
+impl Default for RawOptions {
+    fn default() -> RawOptions {
+        RawOptions {
+            byte_order: ByteOrder::LittleEndian,
+            header_bytes: 0,
+            row_padding: 0,
+        }
+    }
+}
+
+impl RawOptions {
+    /// Sets the byte order of the pixels
+    pub fn with_byte_order(mut self, byte_order: ByteOrder) -> RawOptions {
+        self.byte_order = byte_order;
+        self
+    }
+
+    /// Sets the number of bytes before the first pixel
+    pub fn with_header_bytes(mut self, header_bytes: u64) -> RawOptions {
+        self.header_bytes = header_bytes;
+        self
+    }
+
+    /// Sets the number of bytes after every row
+    pub fn with_row_padding(mut self, row_padding: u64) -> RawOptions {
+        self.row_padding = row_padding;
+        self
+    }
+
+    /// Number of bytes a frame of `width` x `height` pixels of `pixel_type` occupies, padding included.
+    /// Returns None if the number does not fit into a u64.
+    pub fn frame_bytes(&self, width: u32, height: u32, pixel_type: PixelType) -> Option<u64> {
+        (width as u64 * bytes_per_pixel(pixel_type)).checked_add(self.row_padding)
+                                                     .and_then(|row| row.checked_mul(height as u64))
+    }
+}
+
+
+/// Decodes raw pixel dumps.
+///
+/// The decoder is always positioned on one frame, starting with the first.
+#[derive(Debug)]
+pub struct RawDecoder<R> where R: Read + Seek {
+    reader: SmartReader<R>,
+    width: u32,
+    height: u32,
+    pixel_type: PixelType,
+    options: RawOptions,
+    /// Offset of the first pixel of the first frame
+    data_start: u64,
+    frames: usize,
+    frame: usize,
+}
+
+impl<R: Read + Seek> RawDecoder<R> {
+    /// Create a new decoder that decodes frames of `width` x `height` pixels of `pixel_type`
+    /// from the stream ```r```, starting at its current position.
+    ///
+    /// Returns an error if the geometry is empty, the padding or the frame size is too large to seek over,
+    /// or the stream is too short for one frame. Bytes after the last whole frame are ignored.
+    pub fn new(r: R, width: u32, height: u32, pixel_type: PixelType, options: RawOptions) -> ImageResult<RawDecoder<R>> {
+        try!(check_geometry(width, height));
+        if options.row_padding > ::std::i64::MAX as u64 {
+            return Err(ImageError::FormatError(format!("row padding of {} bytes is too large", options.row_padding)))
+        }
+        let frame_bytes = match options.frame_bytes(width, height, pixel_type) {
+            Some(n) => n,
+            None => return Err(ImageError::FormatError(
+                format!("a raw {}x{} frame with {} bytes of row padding is too large", width, height, options.row_padding)
+            )),
+        };
+        let mut reader = SmartReader::wrap(r, options.byte_order);
+        let start = try!(reader.seek(SeekFrom::Current(0)));
+        let stream_len = try!(reader.seek(SeekFrom::End(0)));
+        let data_bytes = (stream_len - start).saturating_sub(options.header_bytes);
+        if data_bytes < frame_bytes {
+            return Err(ImageError::FormatError(
+                format!("a raw {}x{} {:?} frame needs {} bytes after the {} byte header, the stream has {}",
+                        width, height, pixel_type, frame_bytes, options.header_bytes, data_bytes)
+            ))
+        }
+        Ok(RawDecoder {
+            reader: reader,
+            width: width,
+            height: height,
+            pixel_type: pixel_type,
+            options: options,
+            data_start: start + options.header_bytes,
+            frames: (data_bytes / frame_bytes) as usize,
+            frame: 0,
+        })
+    }
+
+    /// Number of whole frames in the stream
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
+    /// Index of the current frame, starting at 0
+    pub fn current_frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Moves the decoder to frame `n`.
+    /// Returns `ImageError::ImageEnd` if the stream holds `n` frames or fewer.
+    pub fn seek_frame(&mut self, n: usize) -> ImageResult<()> {
+        if n >= self.frames {
+            return Err(ImageError::ImageEnd)
+        }
+        self.frame = n;
+        Ok(())
+    }
+
+    /// Returns true if the stream holds another frame after the current one.
+    pub fn more_images(&self) -> bool {
+        self.frame + 1 < self.frames
+    }
+}
+
+impl<R: Read + Seek> ImageDecoder for RawDecoder<R> {
+    fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
+        Ok((self.width, self.height))
+    }
+
+    fn pixel_type(&mut self) -> ImageResult<PixelType> {
+        Ok(self.pixel_type)
+    }
+
+    fn read_image(&mut self) -> ImageResult<DecodingResult> {
+        // Checked by `new`
+        let frame_bytes = self.options.frame_bytes(self.width, self.height, self.pixel_type).unwrap();
+        try!(self.reader.seek(SeekFrom::Start(self.data_start + self.frame as u64 * frame_bytes)));
+        let number_of_pixels = self.width as usize * self.height as usize;
+        let mut result = match self.pixel_type {
+            PixelType::Short16 => DecodingResult::U16(Vec::with_capacity(number_of_pixels)),
+            PixelType::Float32 => DecodingResult::F32(Vec::with_capacity(number_of_pixels)),
+        };
+        for _ in 0..self.height {
+            match result {
+                DecodingResult::U16(ref mut buffer) => for _ in 0..self.width {
+                    buffer.push(try!(self.reader.read_u16()));
+                },
+                DecodingResult::F32(ref mut buffer) => for _ in 0..self.width {
+                    buffer.push(try!(self.reader.read_f32()));
+                },
+            }
+            if self.options.row_padding > 0 {
+                try!(self.reader.seek(SeekFrom::Current(self.options.row_padding as i64)));
+            }
+        }
+        Ok(result)
+    }
+}
+
+
+/// Writes images as raw pixel dumps. Header and row padding are filled with zeros.
+#[derive(Debug)]
+pub struct RawEncoder<W> where W: Write + Seek {
+    writer: SmartWriter<W>,
+    options: RawOptions,
+}
+
+impl<W: Write + Seek> RawEncoder<W> {
+    /// Create a new encoder that writes to the stream ```w``` as described by `options`
+    pub fn new(w: W, options: RawOptions) -> RawEncoder<W> {
+        RawEncoder {
+            writer: SmartWriter::wrap(w, options.byte_order),
+            options: options,
+        }
+    }
+
+    /// Returns the wrapped writer
+    pub fn into_inner(self) -> SmartWriter<W> {
+        self.writer
+    }
+
+    /// Writes `n` zero bytes, a block at a time
+    fn write_zeros(&mut self, mut n: u64) -> ImageResult<()> {
+        let zeros = [0u8; ZERO_BLOCK_BYTES];
+        while n > 0 {
+            let len = ::std::cmp::min(n, ZERO_BLOCK_BYTES as u64) as usize;
+            try!(self.writer.write_all(&zeros[..len]));
+            n -= len as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes the pixels of any image, in row major order
+    fn write_pixels<P, It>(&mut self, width: u32, height: u32, pixels: It) -> ImageResult<()>
+    where P: Pixel, It: Iterator<Item=(u32, u32, P)> {
+        let data = match <P as Pixel>::pixel_type() {
+            PixelType::Short16 => DecodingResult::U16(pixels.map(|(_, _, p)| NumCast::from(*p.value()).unwrap()).collect()),
+            PixelType::Float32 => DecodingResult::F32(pixels.map(|(_, _, p)| NumCast::from(*p.value()).unwrap()).collect()),
+        };
+        self.encode(width, height, &data)
+    }
+}
+
+impl<W: Write + Seek> ImageEncoder for RawEncoder<W> {
+    fn encode(&mut self, width: u32, height: u32, image: &DecodingResult) -> ImageResult<()> {
+        try!(check_geometry(width, height));
+        let number_of_pixels = width as usize * height as usize;
+        let len = match *image {
+            DecodingResult::U16(ref buffer) => buffer.len(),
+            DecodingResult::F32(ref buffer) => buffer.len(),
+        };
+        if len != number_of_pixels {
+            return Err(ImageError::FormatError(
+                format!("{}x{} image needs {} pixels, got {}", width, height, number_of_pixels, len)
+            ))
+        }
+
+        let (header_bytes, row_padding) = (self.options.header_bytes, self.options.row_padding);
+        try!(self.write_zeros(header_bytes));
+        for y in 0..height as usize {
+            let row = y * width as usize..(y + 1) * width as usize;
+            match *image {
+                DecodingResult::U16(ref buffer) => for &datum in &buffer[row] {
+                    try!(self.writer.write_u16(datum));
+                },
+                DecodingResult::F32(ref buffer) => for &datum in &buffer[row] {
+                    try!(self.writer.write_f32(datum));
+                },
+            }
+            try!(self.write_zeros(row_padding));
+        }
+        try!(self.writer.flush());
+        Ok(())
+    }
+
+    fn encode_image<I: GenericImageView>(&mut self, image: &I) -> ImageResult<()> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.pixels())
+    }
+
+    fn encode_buffer<P, C>(&mut self, image: &ImageBuffer<P, C>) -> ImageResult<()>
+    where P: Pixel + 'static, P::Subpixel: 'static, C: Deref<Target=[P::Subpixel]> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.enumerate_pixels().map(|(x, y, p)| (x, y, *p)))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use buffer::{ ImageBuffer, GrayFloatImage };
+    use decoder::ImageDecoder;
+    use encoder::ImageEncoder;
+    use image::other::{ PixelType, DecodingResult, GrayF32 };
+    use stream::ByteOrder;
+    use super::{ RawDecoder, RawEncoder, RawOptions };
+
+    #[test]
+    fn big_endian_with_header_and_padding() {
+        // 3 byte header, 2x2 u16 rows padded by 1 byte, then a second frame and a stray byte
+        let bytes = vec![9, 9, 9,  0x01, 0x02, 0x03, 0x04, 0xee,  0xff, 0xff, 0x00, 0x00, 0xee,
+                         0x00, 0x07, 0x00, 0x08, 0xee,  0x00, 0x09, 0x00, 0x0a, 0xee,  0x42];
+        let options = RawOptions::default().with_byte_order(ByteOrder::BigEndian)
+                                           .with_header_bytes(3).with_row_padding(1);
+        let mut decoder = RawDecoder::new(Cursor::new(bytes.clone()), 2, 2, PixelType::Short16, options).unwrap();
+        assert_eq!(decoder.frame_count(), 2);
+        match decoder.read_image().unwrap() {
+            DecodingResult::U16(pixels) => assert_eq!(pixels, vec![0x0102, 0x0304, 0xffff, 0]),
+            _ => panic!("expected u16 pixels"),
+        }
+        decoder.seek_frame(1).unwrap();
+        let second = decoder.read_image().unwrap();
+        match second {
+            DecodingResult::U16(ref pixels) => assert_eq!(pixels, &vec![7, 8, 9, 10]),
+            _ => panic!("expected u16 pixels"),
+        }
+
+        let mut encoder = RawEncoder::new(Cursor::new(Vec::new()), options);
+        encoder.encode(2, 2, &second).unwrap();
+        let written = encoder.into_inner().into_inner().into_inner();
+        assert_eq!(written, vec![0, 0, 0,  0, 7, 0, 8, 0,  0, 9, 0, 10, 0]);
+    }
+
+    #[test]
+    fn float_roundtrip_and_short_stream() {
+        let image: GrayFloatImage = ImageBuffer::from_fn(3, 2, |x, y| GrayF32(x as f32 - y as f32 * 0.5));
+        let mut encoder = RawEncoder::new(Cursor::new(Vec::new()), RawOptions::default());
+        encoder.encode_image(&image).unwrap();
+        let bytes = encoder.into_inner().into_inner().into_inner();
+        assert_eq!(bytes.len(), 24);
+
+        let mut decoder = RawDecoder::new(Cursor::new(bytes.clone()), 3, 2, PixelType::Float32, RawOptions::default()).unwrap();
+        match decoder.read_image().unwrap() {
+            DecodingResult::F32(pixels) => assert_eq!(&pixels[..], &image.into_raw()[..]),
+            _ => panic!("expected f32 pixels"),
+        }
+        assert!(RawDecoder::new(Cursor::new(bytes), 3, 3, PixelType::Float32, RawOptions::default()).is_err());
+    }
+
+    #[test]
+    fn huge_padding_and_empty_geometry_are_errors() {
+        let bytes = vec![0u8; 16];
+        for &padding in &[::std::u64::MAX, ::std::i64::MAX as u64 + 1, ::std::u64::MAX / 2] {
+            let options = RawOptions::default().with_row_padding(padding);
+            assert!(RawDecoder::new(Cursor::new(bytes.clone()), 2, 4, PixelType::Short16, options).is_err());
+        }
+        assert!(RawDecoder::new(Cursor::new(bytes), 0, 4, PixelType::Short16, RawOptions::default()).is_err());
+
+        let mut encoder = RawEncoder::new(Cursor::new(Vec::new()), RawOptions::default());
+        assert!(encoder.encode(0, 3, &DecodingResult::U16(Vec::new())).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! Named regions of interest and their statistics
+//!
+//! ROIs can be read from a text file with one ROI per line,
+//!
+//! ```text
+//! # coordinates in pixels, (0, 0) is the top left corner of the top left pixel
+//! rectangle <name> <x> <y> <width> <height>
+//! circle <name> <center x> <center y> <radius>
+//! polygon <name> <x>,<y> <x>,<y> <x>,<y> ...
+//! ```
+//!
+//! or from a JSON array of objects with the same fields,
+//!
+//! ```text
+//! [{"name": "center", "type": "rectangle", "x": 10, "y": 10, "width": 20, "height": 20},
+//!  {"name": "spot", "type": "circle", "x": 50.5, "y": 40, "radius": 6},
+//!  {"name": "edge", "type": "polygon", "points": [[0, 0], [30, 0], [0, 30]]}]
+//! ```
+//!
+//! A pixel belongs to a circle or polygon if its center lies inside.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use num::ToPrimitive;
+use rustc_serialize::json::Json;
+
+use image::error::{ ImageError, ImageResult };
+use mask::{ PixelMask, is_masked, assert_fits };
+use stats::Statistics;
+use traits::{ Pixel, GenericImageView };
+
+
+/// The outline of a region of interest
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    /// The pixels from column `x` and row `y` on, `width` by `height` of them
+    Rectangle { x: u32, y: u32, width: u32, height: u32 },
+    /// The pixels whose center is at most `radius` from (x, y)
+    Circle { x: f64, y: f64, radius: f64 },
+    /// The pixels whose center lies inside the polygon through the points, by the even-odd rule
+    Polygon(Vec<(f64, f64)>),
+}
+
+/// A named region of interest
+#[derive(Clone, Debug, PartialEq)]
+pub struct Roi {
+    pub name: String,
+    pub shape: Shape,
+}
+
+impl Roi {
+    /// Creates an ROI
+    pub fn new<S: Into<String>>(name: S, shape: Shape) -> Roi {
+        Roi { name: name.into(), shape: shape }
+    }
+
+    /// Returns true if the pixel at (x, y) belongs to the ROI
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        let (cx, cy) = (x as f64 + 0.5, y as f64 + 0.5);
+        match self.shape {
+            Shape::Rectangle { x: rx, y: ry, width, height } =>
+                x >= rx && y >= ry && ((x - rx) as u64) < width as u64 && ((y - ry) as u64) < height as u64,
+            Shape::Circle { x: ox, y: oy, radius } =>
+                (cx - ox) * (cx - ox) + (cy - oy) * (cy - oy) <= radius * radius,
+            Shape::Polygon(ref points) => {
+                // Count the edges a ray to the right of the center crosses
+                let mut inside = false;
+                let mut j = points.len().wrapping_sub(1);
+                for i in 0..points.len() {
+                    let ((xi, yi), (xj, yj)) = (points[i], points[j]);
+                    if (yi > cy) != (yj > cy) && cx < (xj - xi) * (cy - yi) / (yj - yi) + xi {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+                inside
+            }
+        }
+    }
+
+    /// The smallest rectangle `(x, y, width, height)` inside a `width` x `height` image that holds
+    /// every pixel of the ROI, e.g. for `GenericImageView::view`. Returns None if the ROI is
+    /// outside the image.
+    pub fn bounds(&self, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+        let (x0, y0, x1, y1) = match self.shape {
+            Shape::Rectangle { x, y, width, height } =>
+                (x as f64, y as f64, x as f64 + width as f64, y as f64 + height as f64),
+            Shape::Circle { x, y, radius } => (x - radius, y - radius, x + radius, y + radius),
+            Shape::Polygon(ref points) => points.iter().fold(
+                (::std::f64::INFINITY, ::std::f64::INFINITY, ::std::f64::NEG_INFINITY, ::std::f64::NEG_INFINITY),
+                |(x0, y0, x1, y1), &(x, y)| (x0.min(x), y0.min(y), x1.max(x), y1.max(y))),
+        };
+        let clamp = |v: f64, max: u32| v.max(0.0).min(max as f64) as u32;
+        let (x0, y0) = (clamp(x0.floor(), width), clamp(y0.floor(), height));
+        let (x1, y1) = (clamp(x1.ceil(), width), clamp(y1.ceil(), height));
+        if x0 < x1 && y0 < y1 {
+            Some((x0, y0, x1 - x0, y1 - y0))
+        } else {
+            None
+        }
+    }
+
+    /// Rasterizes the ROI into a `width` x `height` mask with the pixels of the ROI set
+    pub fn to_mask(&self, width: u32, height: u32) -> PixelMask {
+        let mut mask = PixelMask::new(width, height);
+        if let Some((x0, y0, w, h)) = self.bounds(width, height) {
+            for y in y0..y0 + h {
+                for x in x0..x0 + w {
+                    if self.contains(x, y) {
+                        mask.set(x, y, true);
+                    }
+                }
+            }
+        }
+        mask
+    }
+}
+
+
+/// Statistics of one ROI of an image
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoiStatistics {
+    pub name: String,
+    /// Number of pixels of the ROI inside the image
+    pub pixels: usize,
+    /// Number of those pixels that are masked
+    pub masked: usize,
+    /// Statistics of the pixels that are not masked, None if there is none
+    pub statistics: Option<Statistics>,
+}
+
+/// Computes the statistics of every ROI of `rois` in `image`, skipping pixels masked by `mask`.
+/// Parts of an ROI outside the image are ignored.
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size.
+pub fn roi_statistics<I: GenericImageView>(image: &I, rois: &[Roi], mask: Option<&PixelMask>) -> Vec<RoiStatistics> {
+    assert_fits(mask, image);
+    let (width, height) = image.dimensions();
+    rois.iter().map(|roi| {
+        let mut pixels = 0;
+        let mut masked = 0;
+        let mut values = Vec::new();
+        if let Some((x0, y0, w, h)) = roi.bounds(width, height) {
+            for y in y0..y0 + h {
+                for x in x0..x0 + w {
+                    if !roi.contains(x, y) {
+                        continue
+                    }
+                    pixels += 1;
+                    if is_masked(mask, x, y) {
+                        masked += 1;
+                    } else {
+                        values.push(image.get_pixel(x, y).value().to_f64().unwrap());
+                    }
+                }
+            }
+        }
+        RoiStatistics {
+            name: roi.name.clone(),
+            pixels: pixels,
+            masked: masked,
+            statistics: Statistics::from_values(&mut values),
+        }
+    }).collect()
+}
+
+
+/// Reads ROIs from a text or JSON file, see the module documentation.
+/// The format is JSON if the first character that is not white space is `[`.
+pub fn read_rois<P: AsRef<Path>>(path: P) -> ImageResult<Vec<Roi>> {
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+    parse_rois(&text)
+}
+
+/// Parses ROIs in the text or JSON format, see `read_rois`
+pub fn parse_rois(text: &str) -> ImageResult<Vec<Roi>> {
+    if text.chars().find(|c| !c.is_whitespace()) == Some('[') {
+        parse_json(text)
+    } else {
+        parse_text(text)
+    }
+}
+
+fn roi_error(msg: String) -> ImageError {
+    ImageError::FormatError(format!("ROI file: {}", msg))
+}
+
+fn parse_text(text: &str) -> ImageResult<Vec<Roi>> {
+    let mut rois = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let number = |k: usize| fields.get(k).and_then(|f| f.parse::<f64>().ok());
+        let integer = |k: usize| fields.get(k).and_then(|f| f.parse::<u32>().ok());
+        let error = |what: &str| roi_error(format!("line {}: expected `{}`", i + 1, what));
+        let shape = match fields[0] {
+            "rectangle" => match (integer(2), integer(3), integer(4), integer(5)) {
+                (Some(x), Some(y), Some(width), Some(height)) if fields.len() == 6 =>
+                    Shape::Rectangle { x: x, y: y, width: width, height: height },
+                _ => return Err(error("rectangle <name> <x> <y> <width> <height>")),
+            },
+            "circle" => match (number(2), number(3), number(4)) {
+                (Some(x), Some(y), Some(radius)) if fields.len() == 5 =>
+                    Shape::Circle { x: x, y: y, radius: radius },
+                _ => return Err(error("circle <name> <x> <y> <radius>")),
+            },
+            "polygon" => {
+                let points: Vec<Option<(f64, f64)>> = fields.iter().skip(2).map(|p| {
+                    let xy: Vec<&str> = p.split(',').collect();
+                    match (xy.len(), xy[0].parse().ok(), xy.get(1).and_then(|v| v.parse().ok())) {
+                        (2, Some(x), Some(y)) => Some((x, y)),
+                        _ => None,
+                    }
+                }).collect();
+                if points.len() < 3 || points.iter().any(|p| p.is_none()) {
+                    return Err(error("polygon <name> <x>,<y> <x>,<y> <x>,<y> ..."))
+                }
+                Shape::Polygon(points.into_iter().map(|p| p.unwrap()).collect())
+            },
+            other => return Err(roi_error(format!("line {}: unknown shape `{}`", i + 1, other))),
+        };
+        rois.push(Roi::new(fields[1], shape));
+    }
+    Ok(rois)
+}
+
+fn parse_json(text: &str) -> ImageResult<Vec<Roi>> {
+    let json = try!(Json::from_str(text).map_err(|e| roi_error(format!("{}", e))));
+    let list = try!(json.as_array().ok_or(roi_error("expected an array of ROIs".to_string())));
+    let mut rois = Vec::new();
+    for (i, item) in list.iter().enumerate() {
+        let error = |what: &str| roi_error(format!("ROI {}: {}", i, what));
+        let field = |key: &str| item.find(key);
+        let number = |key: &str| field(key).and_then(|v| v.as_f64());
+        let integer = |key: &str| field(key).and_then(|v| v.as_u64()).and_then(|v| v.to_u32());
+        let name = try!(field("name").and_then(|v| v.as_string()).ok_or(error("needs a \"name\"")));
+        let shape = match field("type").and_then(|v| v.as_string()) {
+            Some("rectangle") => match (integer("x"), integer("y"), integer("width"), integer("height")) {
+                (Some(x), Some(y), Some(width), Some(height)) =>
+                    Shape::Rectangle { x: x, y: y, width: width, height: height },
+                _ => return Err(error("a rectangle needs integer \"x\", \"y\", \"width\" and \"height\"")),
+            },
+            Some("circle") => match (number("x"), number("y"), number("radius")) {
+                (Some(x), Some(y), Some(radius)) => Shape::Circle { x: x, y: y, radius: radius },
+                _ => return Err(error("a circle needs \"x\", \"y\" and \"radius\"")),
+            },
+            Some("polygon") => {
+                let points = field("points").and_then(|v| v.as_array()).map(|points| {
+                    points.iter().map(|p| match p.as_array() {
+                        Some(xy) if xy.len() == 2 => match (xy[0].as_f64(), xy[1].as_f64()) {
+                            (Some(x), Some(y)) => Some((x, y)),
+                            _ => None,
+                        },
+                        _ => None,
+                    }).collect::<Vec<_>>()
+                });
+                match points {
+                    Some(ref points) if points.len() >= 3 && points.iter().all(|p| p.is_some()) =>
+                        Shape::Polygon(points.iter().map(|p| p.unwrap()).collect()),
+                    _ => return Err(error("a polygon needs \"points\", at least three [x, y] pairs")),
+                }
+            },
+            _ => return Err(error("needs a \"type\" of rectangle, circle or polygon")),
+        };
+        rois.push(Roi::new(name, shape));
+    }
+    Ok(rois)
+}
+
+
+#[cfg(test)]
+mod test {
+    use buffer::{ ImageBuffer, Gray16Image };
+    use image::other::GrayU16;
+    use mask::PixelMask;
+    use super::{ Roi, Shape, parse_rois, roi_statistics };
+
+    #[test]
+    fn shapes_are_rasterized_by_pixel_center() {
+        let circle = Roi::new("c", Shape::Circle { x: 5.0, y: 5.0, radius: 1.0 });
+        assert_eq!(circle.to_mask(10, 10).masked_pixels(), vec![(4, 4), (5, 4), (4, 5), (5, 5)]);
+
+        let triangle = Roi::new("t", Shape::Polygon(vec![(0.0, 0.0), (4.2, 0.0), (0.0, 4.2)]));
+        assert_eq!(triangle.to_mask(10, 10).count(), 10);
+
+        let rectangle = Roi::new("r", Shape::Rectangle { x: 8, y: 8, width: 5, height: 5 });
+        assert_eq!(rectangle.bounds(10, 10), Some((8, 8, 2, 2)));
+        assert_eq!(rectangle.to_mask(10, 10).count(), 4);
+    }
+
+    #[test]
+    fn text_and_json_files_agree() {
+        let text = "# test\nrectangle a 1 2 3 4\ncircle b 5.5 5 2\npolygon c 0,0 4,0 0,4\n";
+        let json = r#"[{"name": "a", "type": "rectangle", "x": 1, "y": 2, "width": 3, "height": 4},
+                       {"name": "b", "type": "circle", "x": 5.5, "y": 5, "radius": 2},
+                       {"name": "c", "type": "polygon", "points": [[0, 0], [4, 0], [0, 4]]}]"#;
+        let rois = parse_rois(text).unwrap();
+        assert_eq!(rois.len(), 3);
+        assert_eq!(rois, parse_rois(json).unwrap());
+        assert!(parse_rois("rectangle a 1 2 3\n").is_err());
+        assert!(parse_rois(r#"[{"name": "a", "type": "square"}]"#).is_err());
+    }
+
+    #[test]
+    fn statistics_per_roi() {
+        let image: Gray16Image = ImageBuffer::from_fn(4, 4, |x, y| GrayU16((y * 4 + x) as u16));
+        let rois = vec![Roi::new("top", Shape::Rectangle { x: 0, y: 0, width: 4, height: 1 }),
+                        Roi::new("outside", Shape::Circle { x: 20.0, y: 20.0, radius: 1.0 })];
+        let mask = PixelMask::from_fn(4, 4, |x, y| (x, y) == (3, 0));
+        let table = roi_statistics(&image, &rois, Some(&mask));
+        assert_eq!((table[0].pixels, table[0].masked), (4, 1));
+        let s = table[0].statistics.as_ref().unwrap();
+        assert_eq!((s.count, s.mean, s.max), (3, 1.0, 2.0));
+        assert_eq!((table[1].pixels, table[1].statistics.is_none()), (0, true));
+    }
+}
@@ -0,0 +1,367 @@
+//! Stacks of frames of the same scene and their per-pixel temporal statistics
+
+use std::io::{ Read, Seek };
+use std::path::Path;
+
+use num::ToPrimitive;
+
+use buffer::{ ImageBuffer, GrayFloatImage };
+use decoder::IDPDecoder;
+use dynimage::DynamicIdpImage;
+use format;
+use image::error::{ ImageError, ImageResult };
+use image::other::{ GrayF32, DecodingResult };
+use stats::{ sort, percentile };
+use traits::{ Pixel, GenericImageView };
+
+
+/// Returns an error unless `image` is `width` x `height`
+fn check_dimensions<I: GenericImageView>(width: u32, height: u32, image: &I) -> ImageResult<()> {
+    if image.dimensions() == (width, height) {
+        Ok(())
+    } else {
+        Err(ImageError::DimensionMismatch((width, height), image.dimensions()))
+    }
+}
+
+
+/// Per-pixel mean, variance, min and max of a sequence of frames, updated one frame at a time.
+///
+/// Only the running sums are kept, so arbitrarily long sequences fit in memory.
+/// NaN samples are ignored; a pixel that never had a valid sample is NaN in every output.
+#[derive(Clone, Debug)]
+pub struct RunningStatistics {
+    width: u32,
+    height: u32,
+    frames: usize,
+    count: Vec<u32>,
+    mean: Vec<f64>,
+    m2: Vec<f64>,
+    min: Vec<f64>,
+    max: Vec<f64>,
+}
+
+impl RunningStatistics {
+    /// Creates an accumulator for frames of the given size
+    pub fn new(width: u32, height: u32) -> RunningStatistics {
+        let len = width as usize * height as usize;
+        RunningStatistics {
+            width: width,
+            height: height,
+            frames: 0,
+            count: vec![0; len],
+            mean: vec![0.0; len],
+            m2: vec![0.0; len],
+            min: vec![::std::f64::INFINITY; len],
+            max: vec![::std::f64::NEG_INFINITY; len],
+        }
+    }
+
+    /// The width and height of the frames.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Number of frames added so far
+    pub fn len(&self) -> usize {
+        self.frames
+    }
+
+    /// Returns true if no frame has been added
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0
+    }
+
+    /// Adds a frame. Returns an error if it differs in size from the accumulator.
+    pub fn add<I: GenericImageView>(&mut self, image: &I) -> ImageResult<()> {
+        try!(check_dimensions(self.width, self.height, image));
+        for (x, y, p) in image.pixels() {
+            let v = p.value().to_f64().unwrap();
+            if v.is_nan() {
+                continue
+            }
+            let i = y as usize * self.width as usize + x as usize;
+            // Welford's online algorithm, one accumulator per pixel
+            self.count[i] += 1;
+            let delta = v - self.mean[i];
+            self.mean[i] += delta / self.count[i] as f64;
+            self.m2[i] += delta * (v - self.mean[i]);
+            self.min[i] = self.min[i].min(v);
+            self.max[i] = self.max[i].max(v);
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Adds a frame of either pixel type, see `add`
+    pub fn add_dynamic(&mut self, image: &DynamicIdpImage) -> ImageResult<()> {
+        match *image {
+            DynamicIdpImage::U16(ref image) => self.add(image),
+            DynamicIdpImage::F32(ref image) => self.add(image),
+        }
+    }
+
+    /// Builds an image from the per-pixel accumulators with `f(index)`
+    fn image<F: Fn(usize) -> f64>(&self, f: F) -> GrayFloatImage {
+        let width = self.width as usize;
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let i = y as usize * width + x as usize;
+            GrayF32(if self.count[i] == 0 { ::std::f64::NAN } else { f(i) } as f32)
+        })
+    }
+
+    /// The per-pixel mean
+    pub fn mean(&self) -> GrayFloatImage {
+        self.image(|i| self.mean[i])
+    }
+
+    /// The per-pixel population variance
+    pub fn variance(&self) -> GrayFloatImage {
+        self.image(|i| self.m2[i] / self.count[i] as f64)
+    }
+
+    /// The per-pixel population standard deviation
+    pub fn std_dev(&self) -> GrayFloatImage {
+        self.image(|i| (self.m2[i] / self.count[i] as f64).sqrt())
+    }
+
+    /// The per-pixel minimum
+    pub fn min(&self) -> GrayFloatImage {
+        self.image(|i| self.min[i])
+    }
+
+    /// The per-pixel maximum
+    pub fn max(&self) -> GrayFloatImage {
+        self.image(|i| self.max[i])
+    }
+}
+
+
+/// A sequence of equally sized frames, e.g. repeated captures of the same scene.
+///
+/// Frames are stored as f32, which is lossless for both IDP pixel types.
+/// Use `RunningStatistics` instead if the frames do not fit in memory and the median is not needed.
+#[derive(Clone)]
+pub struct ImageStack {
+    width: u32,
+    height: u32,
+    frames: Vec<GrayFloatImage>,
+}
+
+impl ImageStack {
+    /// Creates an empty stack for frames of the given size
+    pub fn new(width: u32, height: u32) -> ImageStack {
+        ImageStack {
+            width: width,
+            height: height,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Builds a stack from `images`, which must all have the same size.
+    /// Returns an error if `images` is empty.
+    pub fn from_images<I: GenericImageView>(images: &[I]) -> ImageResult<ImageStack> {
+        let (width, height) = match images.first() {
+            Some(first) => first.dimensions(),
+            None => return Err(ImageError::FormatError("no images to stack".to_string())),
+        };
+        let mut stack = ImageStack::new(width, height);
+        for image in images {
+            try!(stack.push(image));
+        }
+        Ok(stack)
+    }
+
+    /// Reads every frame from the current one to the end of the stream
+    pub fn from_decoder<R: Read + Seek>(decoder: IDPDecoder<R>) -> ImageResult<ImageStack> {
+        let mut frames = decoder.frames();
+        // A decoder always holds at least one frame
+        let first = try!(frames.next().unwrap());
+        let (width, height) = first.dimensions();
+        let mut stack = ImageStack::new(width, height);
+        try!(stack.push_dynamic(&first));
+        for frame in frames {
+            try!(stack.push_dynamic(&try!(frame)));
+        }
+        Ok(stack)
+    }
+
+    /// Opens a multi-frame image file in any readable format and reads all of its frames,
+    /// see `format::open_frames`
+    pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<ImageStack> {
+        let frames = try!(format::open_frames(path));
+        // A decoder always holds at least one frame
+        let (width, height) = frames[0].dimensions();
+        let mut stack = ImageStack::new(width, height);
+        for frame in &frames {
+            try!(stack.push_dynamic(frame));
+        }
+        Ok(stack)
+    }
+
+    /// Saves all frames as f32 to a file at `path` in the format given by its extension,
+    /// see `format::save_frames`. A NumPy file gets an array of shape (frames, height, width),
+    /// a stack of one frame included.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> ImageResult<()> {
+        let frames: Vec<DecodingResult> = self.frames.iter().map(|frame| DecodingResult::F32(frame.to_vec())).collect();
+        format::save_frames(path, self.width, self.height, &frames)
+    }
+
+    /// Appends a frame. Returns an error if it differs in size from the stack.
+    pub fn push<I: GenericImageView>(&mut self, image: &I) -> ImageResult<()> {
+        try!(check_dimensions(self.width, self.height, image));
+        self.frames.push(ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            GrayF32(image.get_pixel(x, y).value().to_f32().unwrap())
+        }));
+        Ok(())
+    }
+
+    /// Appends a frame of either pixel type, see `push`
+    pub fn push_dynamic(&mut self, image: &DynamicIdpImage) -> ImageResult<()> {
+        let frame = image.to_f32();
+        try!(check_dimensions(self.width, self.height, &frame));
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    /// The width and height of the frames.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Number of frames in the stack
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns true if the stack holds no frame
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// The frames in the order they were added
+    pub fn frames(&self) -> &[GrayFloatImage] {
+        &self.frames
+    }
+
+    /// Accumulates the running statistics of all frames
+    pub fn running_statistics(&self) -> RunningStatistics {
+        let mut running = RunningStatistics::new(self.width, self.height);
+        for frame in &self.frames {
+            running.add(frame).unwrap();
+        }
+        running
+    }
+
+    /// The per-pixel mean
+    pub fn mean(&self) -> GrayFloatImage {
+        self.running_statistics().mean()
+    }
+
+    /// The per-pixel population variance
+    pub fn variance(&self) -> GrayFloatImage {
+        self.running_statistics().variance()
+    }
+
+    /// The per-pixel population standard deviation
+    pub fn std_dev(&self) -> GrayFloatImage {
+        self.running_statistics().std_dev()
+    }
+
+    /// The per-pixel minimum
+    pub fn min(&self) -> GrayFloatImage {
+        self.running_statistics().min()
+    }
+
+    /// The per-pixel maximum
+    pub fn max(&self) -> GrayFloatImage {
+        self.running_statistics().max()
+    }
+
+    /// The per-pixel median, see `percentile`
+    pub fn median(&self) -> GrayFloatImage {
+        self.percentile(50.0)
+    }
+
+    /// The per-pixel `p`th percentile (0 to 100), interpolating linearly between frames.
+    /// NaN samples are ignored; a pixel without a valid sample is NaN.
+    pub fn percentile(&self, p: f64) -> GrayFloatImage {
+        let mut values = Vec::with_capacity(self.frames.len());
+        let mut data = Vec::with_capacity(self.width as usize * self.height as usize);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                values.clear();
+                values.extend(self.frames.iter()
+                                         .map(|frame| frame.get_pixel(x, y).data as f64)
+                                         .filter(|v| !v.is_nan()));
+                data.push(if values.is_empty() {
+                    ::std::f32::NAN
+                } else {
+                    sort(&mut values);
+                    percentile(&values, p) as f32
+                });
+            }
+        }
+        ImageBuffer::from_raw(self.width, self.height, data).unwrap()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use buffer::{ ImageBuffer, Gray16Image };
+    use image::error::ImageError;
+    use image::other::GrayU16;
+    use super::{ ImageStack, RunningStatistics };
+
+    fn frames() -> Vec<Gray16Image> {
+        [3u16, 1, 2, 10].iter().map(|&v| ImageBuffer::from_fn(2, 1, |x, _| GrayU16(v * (x as u16 + 1)))).collect()
+    }
+
+    #[test]
+    fn temporal_statistics() {
+        let stack = ImageStack::from_images(&frames()).unwrap();
+        assert_eq!(stack.len(), 4);
+        assert!(ImageStack::from_images::<Gray16Image>(&[]).is_err());
+        assert_eq!(stack.mean().get_pixel(0, 0).data, 4.0);
+        assert_eq!(stack.median().get_pixel(0, 0).data, 2.5);
+        assert_eq!(stack.variance().get_pixel(0, 0).data, 12.5);
+        assert_eq!(stack.min().get_pixel(1, 0).data, 2.0);
+        assert_eq!(stack.max().get_pixel(1, 0).data, 20.0);
+    }
+
+    #[test]
+    fn streaming_matches_stack() {
+        let mut running = RunningStatistics::new(2, 1);
+        assert!(running.is_empty());
+        for frame in &frames() {
+            running.add(frame).unwrap();
+        }
+        let stack = ImageStack::from_images(&frames()).unwrap();
+        assert_eq!(running.len(), 4);
+        assert!(!running.is_empty());
+        assert_eq!(&*running.mean(), &*stack.mean());
+        assert_eq!(&*running.std_dev(), &*stack.std_dev());
+
+        let small: Gray16Image = ImageBuffer::new(1, 1);
+        match running.add(&small) {
+            Err(ImageError::DimensionMismatch((2, 1), (1, 1))) => {},
+            _ => panic!("expected a dimension mismatch"),
+        }
+    }
+
+    #[test]
+    fn save_and_open_as_numpy_stack() {
+        let path = env::temp_dir().join(format!("idp_stack_{}.npy", ::std::process::id()));
+        let stack = ImageStack::from_images(&frames()).unwrap();
+        stack.save(&path).unwrap();
+        let opened = ImageStack::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(opened.len(), 4);
+        assert_eq!(opened.dimensions(), (2, 1));
+        assert_eq!(&*opened.frames()[3], &*stack.frames()[3]);
+    }
+}
@@ -0,0 +1,183 @@
+//! Whole frame, per row and per column statistics
+
+use num::ToPrimitive;
+
+use mask::{ PixelMask, is_masked, assert_fits, unmasked_values };
+use traits::{ Pixel, GenericImageView };
+
+
+/// Summary statistics of a set of pixel values.
+///
+/// All values are computed in f64, so sums of u16 pixels cannot overflow.
+/// `std_dev` is the population standard deviation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statistics {
+    /// Number of pixels that contributed
+    pub count: usize,
+    pub mean: f64,
+    pub median: f64,
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Statistics {
+    /// Computes the statistics of `values`, reordering the slice in the process.
+    /// NaN values are ignored. Returns None if no value is left.
+    pub fn from_values(values: &mut Vec<f64>) -> Option<Statistics> {
+        values.retain(|v| !v.is_nan());
+        if values.is_empty() {
+            return None
+        }
+
+        // Welford's online algorithm, stable even for large frames
+        let mut mean = 0.0;
+        let mut m2 = 0.0;
+        for (i, &v) in values.iter().enumerate() {
+            let delta = v - mean;
+            mean += delta / (i + 1) as f64;
+            m2 += delta * (v - mean);
+        }
+
+        sort(values);
+        Some(Statistics {
+            count: values.len(),
+            mean: mean,
+            median: percentile(values, 50.0),
+            std_dev: (m2 / values.len() as f64).sqrt(),
+            min: values[0],
+            max: values[values.len() - 1],
+        })
+    }
+}
+
+
+/// Sorts `values` in ascending order. The slice must not contain NaN.
+pub fn sort(values: &mut [f64]) {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+}
+
+/// Returns the `p`th percentile (0 to 100) of the ascending `sorted` values,
+/// interpolating linearly between the closest ranks.
+///
+/// # Panics
+///
+/// Panics if `sorted` is empty.
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p.max(0.0).min(100.0) / 100.0 * (sorted.len() - 1) as f64;
+    let lo = rank.floor() as usize;
+    let hi = rank.ceil() as usize;
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64)
+}
+
+
+/// Returns the value of the pixel at (x, y) widened to f64
+#[inline(always)]
+fn value_at<I: GenericImageView>(image: &I, x: u32, y: u32) -> f64 {
+    image.get_pixel(x, y).value().to_f64().unwrap()
+}
+
+
+/// Computes the statistics of the whole image, skipping pixels masked by `mask`
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size.
+pub fn frame_statistics<I: GenericImageView>(image: &I, mask: Option<&PixelMask>) -> Option<Statistics> {
+    Statistics::from_values(&mut unmasked_values(image, mask))
+}
+
+/// Computes the statistics of every row, top to bottom, skipping pixels masked by `mask`.
+/// Rows without a single valid pixel yield None.
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size.
+pub fn row_statistics<I: GenericImageView>(image: &I, mask: Option<&PixelMask>) -> Vec<Option<Statistics>> {
+    assert_fits(mask, image);
+    let (width, height) = image.dimensions();
+    (0..height).map(|y| {
+        let mut row = (0..width).filter(|&x| !is_masked(mask, x, y))
+                                .map(|x| value_at(image, x, y))
+                                .collect();
+        Statistics::from_values(&mut row)
+    }).collect()
+}
+
+/// Computes the statistics of every column, left to right, skipping pixels masked by `mask`.
+/// Columns without a single valid pixel yield None.
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size.
+pub fn column_statistics<I: GenericImageView>(image: &I, mask: Option<&PixelMask>) -> Vec<Option<Statistics>> {
+    assert_fits(mask, image);
+    let (width, height) = image.dimensions();
+    (0..width).map(|x| {
+        let mut column = (0..height).filter(|&y| !is_masked(mask, x, y))
+                                    .map(|y| value_at(image, x, y))
+                                    .collect();
+        Statistics::from_values(&mut column)
+    }).collect()
+}
+
+/// Returns the requested percentiles (0 to 100) of the pixels not masked by `mask`.
+/// The result is empty if no valid value other than NaN is left.
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size.
+pub fn frame_percentiles<I: GenericImageView>(image: &I, mask: Option<&PixelMask>, percentiles: &[f64]) -> Vec<f64> {
+    let mut values = unmasked_values(image, mask);
+    values.retain(|v| !v.is_nan());
+    if values.is_empty() {
+        return Vec::new()
+    }
+    sort(&mut values);
+    percentiles.iter().map(|&p| percentile(&values, p)).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{ frame_statistics, row_statistics, column_statistics, frame_percentiles };
+    use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+    use mask::PixelMask;
+    use image::other::{ GrayU16, GrayF32 };
+
+    #[test]
+    fn u16_sums_do_not_overflow() {
+        let image: Gray16Image = ImageBuffer::from_pixel(300, 300, GrayU16(65535));
+        let stats = frame_statistics(&image, None).unwrap();
+        assert_eq!(stats.count, 90000);
+        assert_eq!(stats.mean, 65535.0);
+        assert_eq!(stats.std_dev, 0.0);
+    }
+
+    #[test]
+    fn rows_and_columns() {
+        let image: GrayFloatImage = ImageBuffer::from_fn(3, 2, |x, y| GrayF32((x + 10 * y) as f32));
+        let rows = row_statistics(&image, None);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].as_ref().unwrap().mean, 11.0);
+        let columns = column_statistics(&image, None);
+        assert_eq!(columns.len(), 3);
+        assert_eq!(columns[2].as_ref().unwrap().median, 7.0);
+        assert_eq!(columns[2].as_ref().unwrap().max, 12.0);
+        assert_eq!(frame_percentiles(&image, None, &[0.0, 100.0]), vec![0.0, 12.0]);
+    }
+
+    #[test]
+    fn masked_pixels_are_skipped() {
+        let image: GrayFloatImage = ImageBuffer::from_fn(3, 2, |x, y| GrayF32((x + 10 * y) as f32));
+        let mask = PixelMask::from_fn(3, 2, |x, _| x == 2);
+        let stats = frame_statistics(&image, Some(&mask)).unwrap();
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.max, 11.0);
+        let columns = column_statistics(&image, Some(&mask));
+        assert!(columns[2].is_none());
+        assert_eq!(mask.invert().count(), 4);
+        assert_eq!(mask.union(&mask.invert()).unwrap().count(), 6);
+        assert_eq!(mask.intersection(&mask.invert()).unwrap().count(), 0);
+    }
+}
@@ -141,6 +141,11 @@ impl<W> SmartWriter<W> where W: Write + Seek {
             byte_order: byte_order
         }
     }
+
+    /// Returns the wrapped writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
 }
 
 impl<W> EndianWriter for SmartWriter<W> where W: Write + Seek {
@@ -0,0 +1,139 @@
+//! Threshold counting and binary maps
+
+use num::ToPrimitive;
+
+use mask::{ PixelMask, is_masked, assert_fits };
+use traits::{ Pixel, GenericImageView };
+
+
+/// Selects pixels by comparing their value against fixed limits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Threshold {
+    /// Values strictly below the limit
+    Below(f64),
+    /// Values strictly above the limit
+    Above(f64),
+    /// Values between the two limits, both included
+    Within(f64, f64),
+    /// Values strictly below the first or strictly above the second limit
+    Outside(f64, f64),
+}
+
+impl Threshold {
+    /// Returns true if `value` is selected. NaN is never selected.
+    #[inline(always)]
+    pub fn matches(&self, value: f64) -> bool {
+        match *self {
+            Threshold::Below(limit) => value < limit,
+            Threshold::Above(limit) => value > limit,
+            Threshold::Within(low, high) => value >= low && value <= high,
+            Threshold::Outside(low, high) => value < low || value > high,
+        }
+    }
+}
+
+
+/// Returns true if the pixel at (x, y) is not masked and is selected by `threshold`
+#[inline(always)]
+fn selected<I: GenericImageView>(image: &I, x: u32, y: u32, threshold: Threshold, mask: Option<&PixelMask>) -> bool {
+    !is_masked(mask, x, y) && threshold.matches(image.get_pixel(x, y).value().to_f64().unwrap())
+}
+
+/// Counts the pixels selected by `threshold`, skipping pixels masked by `mask`
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size.
+pub fn count<I: GenericImageView>(image: &I, threshold: Threshold, mask: Option<&PixelMask>) -> usize {
+    count_per_row(image, threshold, mask).iter().fold(0, |acc, &n| acc + n)
+}
+
+/// Counts the pixels selected by `threshold` in every row, top to bottom
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size.
+pub fn count_per_row<I: GenericImageView>(image: &I, threshold: Threshold, mask: Option<&PixelMask>) -> Vec<usize> {
+    assert_fits(mask, image);
+    let (width, height) = image.dimensions();
+    (0..height).map(|y| {
+        (0..width).filter(|&x| selected(image, x, y, threshold, mask)).count()
+    }).collect()
+}
+
+/// Counts the pixels selected by `threshold` in every column, left to right
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size.
+pub fn count_per_column<I: GenericImageView>(image: &I, threshold: Threshold, mask: Option<&PixelMask>) -> Vec<usize> {
+    assert_fits(mask, image);
+    let (width, height) = image.dimensions();
+    let mut counts = vec![0; width as usize];
+    for y in 0..height {
+        for x in 0..width {
+            if selected(image, x, y, threshold, mask) {
+                counts[x as usize] += 1;
+            }
+        }
+    }
+    counts
+}
+
+/// Returns a mask with every pixel selected by `threshold` set.
+/// Pixels masked by `mask` are never set.
+///
+/// # Panics
+///
+/// Panics if the mask and the image differ in size.
+pub fn binary_map<I: GenericImageView>(image: &I, threshold: Threshold, mask: Option<&PixelMask>) -> PixelMask {
+    assert_fits(mask, image);
+    let (width, height) = image.dimensions();
+    PixelMask::from_fn(width, height, |x, y| selected(image, x, y, threshold, mask))
+}
+
+
+#[cfg(test)]
+mod test {
+    use buffer::{ ImageBuffer, GrayFloatImage };
+    use image::other::GrayF32;
+    use mask::PixelMask;
+    use super::{ Threshold, count, count_per_row, count_per_column, binary_map };
+
+    /// 0 1 2 on the first row, 3 NaN 5 on the second
+    fn image() -> GrayFloatImage {
+        ImageBuffer::from_fn(3, 2, |x, y| GrayF32(if (x, y) == (1, 1) { ::std::f32::NAN } else { (x + 3 * y) as f32 }))
+    }
+
+    #[test]
+    fn limits_and_boundaries() {
+        assert!(Threshold::Within(1.0, 2.0).matches(1.0) && Threshold::Within(1.0, 2.0).matches(2.0));
+        assert!(!Threshold::Outside(1.0, 2.0).matches(1.0) && !Threshold::Outside(1.0, 2.0).matches(2.0));
+        assert!(!Threshold::Below(1.0).matches(1.0) && !Threshold::Above(1.0).matches(1.0));
+        for &t in &[Threshold::Below(10.0), Threshold::Above(-10.0), Threshold::Within(-10.0, 10.0), Threshold::Outside(0.0, 0.0)] {
+            assert!(!t.matches(::std::f64::NAN));
+        }
+    }
+
+    #[test]
+    fn counts_by_frame_row_and_column() {
+        let image = image();
+        assert_eq!(count(&image, Threshold::Within(1.0, 3.0), None), 3);
+        assert_eq!(count(&image, Threshold::Outside(1.0, 3.0), None), 2);
+        assert_eq!(count_per_row(&image, Threshold::Above(0.5), None), vec![2, 2]);
+        assert_eq!(count_per_column(&image, Threshold::Above(0.5), None), vec![1, 1, 2]);
+    }
+
+    #[test]
+    fn masked_pixels_are_excluded() {
+        let image = image();
+        let mask = PixelMask::from_fn(3, 2, |x, _| x == 2);
+        assert_eq!(count(&image, Threshold::Above(0.5), Some(&mask)), 2);
+        assert_eq!(count_per_column(&image, Threshold::Above(0.5), Some(&mask)), vec![1, 1, 0]);
+
+        let map = binary_map(&image, Threshold::Below(4.0), Some(&mask));
+        assert_eq!(map.count(), 3);
+        assert!(map.is_masked(0, 0) && map.is_masked(1, 0) && map.is_masked(0, 1));
+        assert!(!map.is_masked(2, 0) && !map.is_masked(1, 1));
+    }
+}
@@ -0,0 +1,589 @@
+//! Baseline TIFF: uncompressed grayscale images with u16 or f32 samples, stored in strips,
+//! in either byte order.
+//!
+//! The decoder reads every image file directory (IFD) of a file as one frame, the encoder
+//! writes a single frame.
+
+use std::io::{ Cursor, Read, Write, Seek, SeekFrom };
+use std::ops::Deref;
+
+use num::NumCast;
+
+use buffer::ImageBuffer;
+use decoder::{ ImageDecoder, bytes_per_pixel };
+use encoder::ImageEncoder;
+use image::error::{ ImageError, ImageResult };
+use image::other::{ PixelType, DecodingResult };
+use stream::{ ByteOrder, EndianReader, EndianWriter, SmartReader, SmartWriter };
+use traits::{ Pixel, GenericImageView };
+
+
+/// Tags of the baseline fields this module reads or writes
+mod tag {
+    pub const IMAGE_WIDTH: u16 = 256;
+    pub const IMAGE_LENGTH: u16 = 257;
+    pub const BITS_PER_SAMPLE: u16 = 258;
+    pub const COMPRESSION: u16 = 259;
+    pub const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+    pub const STRIP_OFFSETS: u16 = 273;
+    pub const SAMPLES_PER_PIXEL: u16 = 277;
+    pub const ROWS_PER_STRIP: u16 = 278;
+    pub const STRIP_BYTE_COUNTS: u16 = 279;
+    pub const PLANAR_CONFIGURATION: u16 = 284;
+    pub const TILE_WIDTH: u16 = 322;
+    pub const SAMPLE_FORMAT: u16 = 339;
+}
+
+/// Field types
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+/// SampleFormat values
+const UNSIGNED: u32 = 1;
+const IEEE_FLOAT: u32 = 3;
+
+/// PhotometricInterpretation BlackIsZero
+const BLACK_IS_ZERO: u32 = 1;
+
+/// Largest number of bytes the encoder puts into one strip
+const STRIP_BYTES: u64 = 8192;
+
+
+fn format_error<T>(msg: String) -> ImageResult<T> {
+    Err(ImageError::FormatError(format!("TIFF: {}", msg)))
+}
+
+
+/// Reads a SHORT (`size` 2) or LONG (`size` 4) value
+fn read_value<E: EndianReader>(r: &mut E, size: u64) -> ImageResult<u32> {
+    Ok(if size == 2 { try!(r.read_u16()) as u32 } else { try!(r.read_u32()) })
+}
+
+
+/// An entry of an image file directory
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    /// The value field, which holds the values themselves if they fit into 4 bytes
+    value: [u8; 4],
+}
+
+
+/// Decodes TIFF streams.
+///
+/// The decoder is always positioned on one frame, starting with the first.
+#[derive(Debug)]
+pub struct TiffDecoder<R> where R: Read + Seek {
+    reader: SmartReader<R>,
+    width: u32,
+    height: u32,
+    pixel_type: PixelType,
+    rows_per_strip: u32,
+    strip_offsets: Vec<u32>,
+    strip_byte_counts: Vec<u32>,
+    /// Offset of the IFD after the current one, 0 if there is none
+    next_ifd: u32,
+    /// Offsets of the IFDs read so far, to detect a chain that loops back
+    visited: Vec<u32>,
+    /// Length of the stream in bytes
+    stream_len: u64,
+}
+
+impl<R: Read + Seek> TiffDecoder<R> {
+    /// Create a new decoder that decodes from the stream ```r```
+    ///
+    /// Offsets in the file count from the start of ```r```, so the file has to start there.
+    /// The decoder is positioned on the first frame.
+    pub fn new(r: R) -> ImageResult<TiffDecoder<R>> {
+        let mut reader = SmartReader::wrap(r, ByteOrder::LittleEndian);
+        let mut order = [0u8; 2];
+        try!(reader.read_exact(&mut order));
+        reader.byte_order = match &order {
+            b"II" => ByteOrder::LittleEndian,
+            b"MM" => ByteOrder::BigEndian,
+            _ => return format_error("not a TIFF file".to_string()),
+        };
+        let magic = try!(reader.read_u16());
+        if magic != 42 {
+            return format_error(format!("expected 42 after the byte order, found {}", magic))
+        }
+        let first_ifd = try!(reader.read_u32());
+        let stream_len = try!(reader.seek(SeekFrom::End(0)));
+        let mut decoder = TiffDecoder {
+            reader: reader,
+            width: 0,
+            height: 0,
+            pixel_type: PixelType::Short16,
+            rows_per_strip: 0,
+            strip_offsets: Vec::new(),
+            strip_byte_counts: Vec::new(),
+            next_ifd: first_ifd,
+            visited: Vec::new(),
+            stream_len: stream_len,
+        };
+        try!(decoder.read_ifd(first_ifd));
+        Ok(decoder)
+    }
+
+    /// The byte order of the file
+    pub fn byte_order(&self) -> ByteOrder {
+        self.reader.byte_order
+    }
+
+    /// Returns true if the file holds another frame after the current one.
+    pub fn more_images(&self) -> bool {
+        self.next_ifd != 0
+    }
+
+    /// Moves to the next frame.
+    /// Returns `ImageError::ImageEnd` if the current frame is the last one,
+    /// and an error if the next IFD is one that was read before.
+    pub fn next_image(mut self) -> ImageResult<TiffDecoder<R>> {
+        if !self.more_images() {
+            return Err(ImageError::ImageEnd)
+        }
+        let next = self.next_ifd;
+        try!(self.read_ifd(next));
+        Ok(self)
+    }
+
+    /// Returns an error if `len` bytes starting at `offset` reach past the end of the stream
+    fn check_range(&self, what: &str, offset: u64, len: u64) -> ImageResult<()> {
+        if offset > self.stream_len || len > self.stream_len - offset {
+            return format_error(format!("{} needs {} bytes at offset {}, the stream has {}", what, len, offset, self.stream_len))
+        }
+        Ok(())
+    }
+
+    /// Reads the values of a SHORT or LONG entry
+    fn values(&mut self, entry: &Entry) -> ImageResult<Vec<u32>> {
+        let size = match entry.kind {
+            SHORT => 2,
+            LONG => 4,
+            kind => return format_error(format!("tag {} has field type {}, expected SHORT or LONG", entry.tag, kind)),
+        };
+        let order = self.reader.byte_order;
+        if entry.count as u64 * size <= 4 {
+            let mut values = Vec::with_capacity(entry.count as usize);
+            let mut inline = SmartReader::wrap(Cursor::new(&entry.value[..]), order);
+            for _ in 0..entry.count {
+                values.push(try!(read_value(&mut inline, size)));
+            }
+            Ok(values)
+        } else {
+            let offset = try!(SmartReader::wrap(Cursor::new(&entry.value[..]), order).read_u32());
+            try!(self.check_range(&format!("tag {}", entry.tag), offset as u64, entry.count as u64 * size));
+            let mut values = Vec::with_capacity(entry.count as usize);
+            try!(self.reader.seek(SeekFrom::Start(offset as u64)));
+            for _ in 0..entry.count {
+                values.push(try!(read_value(&mut self.reader, size)));
+            }
+            Ok(values)
+        }
+    }
+
+    /// Reads the single value of an entry
+    fn value(&mut self, entry: &Entry) -> ImageResult<u32> {
+        let values = try!(self.values(entry));
+        if values.len() != 1 {
+            return format_error(format!("tag {} holds {} values, expected 1", entry.tag, values.len()))
+        }
+        Ok(values[0])
+    }
+
+    /// Reads the IFD at `offset` and checks it describes an image this decoder supports
+    fn read_ifd(&mut self, offset: u32) -> ImageResult<()> {
+        if self.visited.contains(&offset) {
+            return format_error(format!("the IFD chain loops back to offset {}", offset))
+        }
+        self.visited.push(offset);
+        try!(self.reader.seek(SeekFrom::Start(offset as u64)));
+        let n = try!(self.reader.read_u16());
+        try!(self.check_range("IFD", offset as u64 + 2, n as u64 * 12 + 4));
+        let mut entries = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            let tag = try!(self.reader.read_u16());
+            let kind = try!(self.reader.read_u16());
+            let count = try!(self.reader.read_u32());
+            let mut value = [0u8; 4];
+            try!(self.reader.read_exact(&mut value));
+            entries.push(Entry { tag: tag, kind: kind, count: count, value: value });
+        }
+        let next_ifd = try!(self.reader.read_u32());
+
+        let (mut width, mut height, mut bits, mut format) = (None, None, 1, UNSIGNED);
+        let mut rows_per_strip = ::std::u32::MAX;
+        let (mut strip_offsets, mut strip_byte_counts) = (None, None);
+        for entry in &entries {
+            match entry.tag {
+                tag::IMAGE_WIDTH => width = Some(try!(self.value(entry))),
+                tag::IMAGE_LENGTH => height = Some(try!(self.value(entry))),
+                tag::BITS_PER_SAMPLE => bits = try!(self.value(entry)),
+                tag::SAMPLE_FORMAT => format = try!(self.value(entry)),
+                tag::ROWS_PER_STRIP => rows_per_strip = try!(self.value(entry)),
+                tag::STRIP_OFFSETS => strip_offsets = Some(try!(self.values(entry))),
+                tag::STRIP_BYTE_COUNTS => strip_byte_counts = Some(try!(self.values(entry))),
+                tag::COMPRESSION => match try!(self.value(entry)) {
+                    1 => {},
+                    c => return format_error(format!("compression {} is not supported", c)),
+                },
+                tag::SAMPLES_PER_PIXEL => match try!(self.value(entry)) {
+                    1 => {},
+                    n => return format_error(format!("{} samples per pixel, only grayscale is supported", n)),
+                },
+                tag::PHOTOMETRIC_INTERPRETATION => match try!(self.value(entry)) {
+                    BLACK_IS_ZERO => {},
+                    p => return format_error(format!("photometric interpretation {} is not supported", p)),
+                },
+                tag::PLANAR_CONFIGURATION => {},
+                tag::TILE_WIDTH => return format_error("tiled images are not supported".to_string()),
+                _ => {},
+            }
+        }
+
+        let (width, height) = match (width, height) {
+            (Some(w), Some(h)) if w > 0 && h > 0 => (w, h),
+            _ => return format_error("missing or zero image width or length".to_string()),
+        };
+        self.pixel_type = match (bits, format) {
+            (16, UNSIGNED) => PixelType::Short16,
+            (32, IEEE_FLOAT) => PixelType::Float32,
+            (bits, format) => return format_error(
+                format!("{} bit samples of format {} are not supported, only u16 and f32", bits, format)
+            ),
+        };
+        let (strip_offsets, strip_byte_counts) = match (strip_offsets, strip_byte_counts) {
+            (Some(o), Some(c)) => (o, c),
+            _ => return format_error("missing strip offsets or byte counts".to_string()),
+        };
+        if rows_per_strip == 0 {
+            return format_error("zero rows per strip".to_string())
+        }
+        let rows_per_strip = ::std::cmp::min(rows_per_strip, height);
+        let strips = (height as usize + rows_per_strip as usize - 1) / rows_per_strip as usize;
+        if strip_offsets.len() != strips || strip_byte_counts.len() != strips {
+            return format_error(format!("expected {} strips, found {} offsets and {} byte counts",
+                                        strips, strip_offsets.len(), strip_byte_counts.len()))
+        }
+
+        self.width = width;
+        self.height = height;
+        self.rows_per_strip = rows_per_strip;
+        self.strip_offsets = strip_offsets;
+        self.strip_byte_counts = strip_byte_counts;
+        self.next_ifd = next_ifd;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> ImageDecoder for TiffDecoder<R> {
+    fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
+        Ok((self.width, self.height))
+    }
+
+    fn pixel_type(&mut self) -> ImageResult<PixelType> {
+        Ok(self.pixel_type)
+    }
+
+    fn read_image(&mut self) -> ImageResult<DecodingResult> {
+        let row_len = self.width as usize;
+        let (width, height, rows_per_strip) = (self.width, self.height, self.rows_per_strip);
+        let strip_pixels = |i: usize| {
+            let first_row = i as u32 * rows_per_strip;
+            ::std::cmp::min(rows_per_strip, height - first_row) as u64 * width as u64
+        };
+        // Check every strip lies inside the stream before anything gets allocated for the image.
+        // Strips may overlap, so the image as a whole has to fit into the stream as well.
+        let image_bytes = width as u64 * height as u64 * bytes_per_pixel(self.pixel_type);
+        if image_bytes > self.stream_len {
+            return format_error(format!("{}x{} image needs {} bytes, the stream has {}", width, height, image_bytes, self.stream_len))
+        }
+        for i in 0..self.strip_offsets.len() {
+            let needed = strip_pixels(i) * bytes_per_pixel(self.pixel_type);
+            if (self.strip_byte_counts[i] as u64) < needed {
+                return format_error(format!("strip {} holds {} bytes, needs {}", i, self.strip_byte_counts[i], needed))
+            }
+            try!(self.check_range(&format!("strip {}", i), self.strip_offsets[i] as u64, needed));
+        }
+
+        let number_of_pixels = row_len * self.height as usize;
+        let mut result = match self.pixel_type {
+            PixelType::Short16 => DecodingResult::U16(Vec::with_capacity(number_of_pixels)),
+            PixelType::Float32 => DecodingResult::F32(Vec::with_capacity(number_of_pixels)),
+        };
+        for i in 0..self.strip_offsets.len() {
+            let pixels = strip_pixels(i) as usize;
+            try!(self.reader.seek(SeekFrom::Start(self.strip_offsets[i] as u64)));
+            match result {
+                DecodingResult::U16(ref mut buffer) => for _ in 0..pixels {
+                    buffer.push(try!(self.reader.read_u16()));
+                },
+                DecodingResult::F32(ref mut buffer) => for _ in 0..pixels {
+                    buffer.push(try!(self.reader.read_f32()));
+                },
+            }
+        }
+        Ok(result)
+    }
+}
+
+
+/// Writes images as single frame TIFF files
+#[derive(Debug)]
+pub struct TiffEncoder<W> where W: Write + Seek {
+    writer: SmartWriter<W>,
+}
+
+impl<W: Write + Seek> TiffEncoder<W> {
+    /// Create a new encoder that writes little endian TIFF to the stream ```w```
+    pub fn new(w: W) -> TiffEncoder<W> {
+        TiffEncoder::with_byte_order(w, ByteOrder::LittleEndian)
+    }
+
+    /// Create a new encoder that writes TIFF in `byte_order` to the stream ```w```
+    pub fn with_byte_order(w: W, byte_order: ByteOrder) -> TiffEncoder<W> {
+        TiffEncoder {
+            writer: SmartWriter::wrap(w, byte_order),
+        }
+    }
+
+    /// Returns the wrapped writer
+    pub fn into_inner(self) -> SmartWriter<W> {
+        self.writer
+    }
+
+    fn write_short_entry(&mut self, tag: u16, value: u16) -> ImageResult<()> {
+        try!(self.writer.write_u16(tag));
+        try!(self.writer.write_u16(SHORT));
+        try!(self.writer.write_u32(1));
+        try!(self.writer.write_u16(value));
+        try!(self.writer.write_u16(0));
+        Ok(())
+    }
+
+    fn write_long_entry(&mut self, tag: u16, count: u32, value: u32) -> ImageResult<()> {
+        try!(self.writer.write_u16(tag));
+        try!(self.writer.write_u16(LONG));
+        try!(self.writer.write_u32(count));
+        try!(self.writer.write_u32(value));
+        Ok(())
+    }
+
+    /// Writes the header, the pixels in strips and an IFD describing them
+    fn write_tiff(&mut self, width: u32, height: u32, image: &DecodingResult) -> ImageResult<()> {
+        let (pixel_type, len) = match *image {
+            DecodingResult::U16(ref buffer) => (PixelType::Short16, buffer.len()),
+            DecodingResult::F32(ref buffer) => (PixelType::Float32, buffer.len()),
+        };
+        let number_of_pixels = width as usize * height as usize;
+        if width == 0 || height == 0 || len != number_of_pixels {
+            return format_error(format!("{}x{} image needs {} pixels, got {}", width, height, number_of_pixels, len))
+        }
+        let bytes = bytes_per_pixel(pixel_type);
+        let row_bytes = width as u64 * bytes;
+        let rows_per_strip = ::std::cmp::max(1, ::std::cmp::min(STRIP_BYTES / row_bytes, height as u64)) as u32;
+        let strips = (height + rows_per_strip - 1) / rows_per_strip;
+        let entries = 11u16;
+        // The IFD and the strip arrays follow the pixels, the whole file has to fit into u32 offsets
+        let file_bytes = row_bytes.checked_mul(height as u64)
+                                  .and_then(|n| n.checked_add(8 + 2 + 12 * entries as u64 + 4 + 8 * strips as u64));
+        let data_bytes = match file_bytes {
+            Some(n) if n <= ::std::u32::MAX as u64 => row_bytes * height as u64,
+            _ => return format_error(format!("{}x{} image is too large for TIFF", width, height)),
+        };
+        let strip_bytes = |i: u32| ::std::cmp::min(rows_per_strip, height - i * rows_per_strip) as u64 * row_bytes;
+
+        let order = match self.writer.byte_order {
+            ByteOrder::LittleEndian => b"II",
+            ByteOrder::BigEndian => b"MM",
+        };
+        let data_start = 8u32;
+        let ifd_offset = data_start + data_bytes as u32;
+        let arrays_offset = ifd_offset + 2 + entries as u32 * 12 + 4;
+
+        try!(self.writer.write_all(order));
+        try!(self.writer.write_u16(42));
+        try!(self.writer.write_u32(ifd_offset));
+        match *image {
+            DecodingResult::U16(ref buffer) => for &datum in buffer {
+                try!(self.writer.write_u16(datum));
+            },
+            DecodingResult::F32(ref buffer) => for &datum in buffer {
+                try!(self.writer.write_f32(datum));
+            },
+        }
+
+        // Entries in ascending tag order; with one strip its offset and byte count are stored inline
+        let (offsets, counts) = if strips == 1 {
+            (data_start, data_bytes as u32)
+        } else {
+            (arrays_offset, arrays_offset + 4 * strips)
+        };
+        let (bits, format) = match pixel_type {
+            PixelType::Short16 => (16, UNSIGNED),
+            PixelType::Float32 => (32, IEEE_FLOAT),
+        };
+        try!(self.writer.write_u16(entries));
+        try!(self.write_long_entry(tag::IMAGE_WIDTH, 1, width));
+        try!(self.write_long_entry(tag::IMAGE_LENGTH, 1, height));
+        try!(self.write_short_entry(tag::BITS_PER_SAMPLE, bits));
+        try!(self.write_short_entry(tag::COMPRESSION, 1));
+        try!(self.write_short_entry(tag::PHOTOMETRIC_INTERPRETATION, BLACK_IS_ZERO as u16));
+        try!(self.write_long_entry(tag::STRIP_OFFSETS, strips, offsets));
+        try!(self.write_short_entry(tag::SAMPLES_PER_PIXEL, 1));
+        try!(self.write_long_entry(tag::ROWS_PER_STRIP, 1, rows_per_strip));
+        try!(self.write_long_entry(tag::STRIP_BYTE_COUNTS, strips, counts));
+        try!(self.write_short_entry(tag::PLANAR_CONFIGURATION, 1));
+        try!(self.write_short_entry(tag::SAMPLE_FORMAT, format as u16));
+        try!(self.writer.write_u32(0));
+
+        if strips > 1 {
+            for i in 0..strips {
+                try!(self.writer.write_u32(data_start + (i as u64 * rows_per_strip as u64 * row_bytes) as u32));
+            }
+            for i in 0..strips {
+                try!(self.writer.write_u32(strip_bytes(i) as u32));
+            }
+        }
+        try!(self.writer.flush());
+        Ok(())
+    }
+
+    /// Collects the pixels of any image, in row major order, and writes them
+    fn write_pixels<P, It>(&mut self, width: u32, height: u32, pixels: It) -> ImageResult<()>
+    where P: Pixel, It: Iterator<Item=(u32, u32, P)> {
+        let data = match <P as Pixel>::pixel_type() {
+            PixelType::Short16 => DecodingResult::U16(pixels.map(|(_, _, p)| NumCast::from(*p.value()).unwrap()).collect()),
+            PixelType::Float32 => DecodingResult::F32(pixels.map(|(_, _, p)| NumCast::from(*p.value()).unwrap()).collect()),
+        };
+        self.write_tiff(width, height, &data)
+    }
+}
+
+impl<W: Write + Seek> ImageEncoder for TiffEncoder<W> {
+    fn encode(&mut self, width: u32, height: u32, image: &DecodingResult) -> ImageResult<()> {
+        self.write_tiff(width, height, image)
+    }
+
+    fn encode_image<I: GenericImageView>(&mut self, image: &I) -> ImageResult<()> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.pixels())
+    }
+
+    fn encode_buffer<P, C>(&mut self, image: &ImageBuffer<P, C>) -> ImageResult<()>
+    where P: Pixel + 'static, P::Subpixel: 'static, C: Deref<Target=[P::Subpixel]> {
+        let (width, height) = image.dimensions();
+        self.write_pixels(width, height, image.enumerate_pixels().map(|(x, y, p)| (x, y, *p)))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use buffer::{ ImageBuffer, Gray16Image, GrayFloatImage };
+    use decoder::ImageDecoder;
+    use dynimage::DynamicIdpImage;
+    use encoder::ImageEncoder;
+    use image::other::{ DecodingResult, GrayU16, GrayF32 };
+    use stream::ByteOrder;
+    use super::{ TiffDecoder, TiffEncoder };
+
+    fn encode<F: Fn(Cursor<Vec<u8>>) -> TiffEncoder<Cursor<Vec<u8>>>>(new: F, image: &DynamicIdpImage) -> Vec<u8> {
+        let mut encoder = new(Cursor::new(Vec::new()));
+        match *image {
+            DynamicIdpImage::U16(ref image) => encoder.encode_image(image).unwrap(),
+            DynamicIdpImage::F32(ref image) => encoder.encode_image(image).unwrap(),
+        }
+        encoder.into_inner().into_inner().into_inner()
+    }
+
+    #[test]
+    fn roundtrip_in_both_byte_orders() {
+        // 3000 pixels per row, so u16 images take 2 rows and f32 images 1 row per strip
+        let u16s: Gray16Image = ImageBuffer::from_fn(3000, 5, |x, y| GrayU16((x * 20 + y) as u16));
+        let f32s: GrayFloatImage = ImageBuffer::from_fn(3000, 3, |x, y| GrayF32(x as f32 * -0.25 + y as f32));
+        for image in vec![DynamicIdpImage::U16(u16s), DynamicIdpImage::F32(f32s)] {
+            for &order in &[ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+                let bytes = encode(|w| TiffEncoder::with_byte_order(w, order), &image);
+                let mut decoder = TiffDecoder::new(Cursor::new(bytes)).unwrap();
+                assert_eq!(decoder.dimensions().unwrap(), image.dimensions());
+                assert_eq!(decoder.pixel_type().unwrap(), image.pixel_type());
+                assert!(!decoder.more_images());
+                let decoded = DynamicIdpImage::from_decoder(&mut decoder).unwrap();
+                assert_eq!(&*decoded.to_f32(), &*image.to_f32());
+            }
+        }
+    }
+
+    #[test]
+    fn reads_a_hand_written_big_endian_file() {
+        // 2x2 u16, one strip, BitsPerSample and friends stored inline, no SampleFormat
+        let mut file = vec![b'M', b'M', 0, 42, 0, 0, 0, 16, 0, 1, 0, 2, 0xff, 0xff, 0x12, 0x34];
+        let entries: [(u16, u16, u32); 7] = [(256, 3, 2), (257, 4, 2), (258, 3, 16), (259, 3, 1),
+                                             (262, 3, 1), (273, 4, 8), (279, 4, 8)];
+        file.extend_from_slice(&[0, entries.len() as u8]);
+        for &(tag, kind, value) in &entries {
+            file.extend_from_slice(&[(tag >> 8) as u8, tag as u8, 0, kind as u8, 0, 0, 0, 1]);
+            if kind == 3 {
+                file.extend_from_slice(&[(value >> 8) as u8, value as u8, 0, 0]);
+            } else {
+                file.extend_from_slice(&[0, 0, 0, value as u8]);
+            }
+        }
+        file.extend_from_slice(&[0, 0, 0, 0]);
+
+        let mut decoder = TiffDecoder::new(Cursor::new(file.clone())).unwrap();
+        match decoder.read_image().unwrap() {
+            DecodingResult::U16(pixels) => assert_eq!(pixels, vec![1, 2, 0xffff, 0x1234]),
+            _ => panic!("expected u16 pixels"),
+        }
+
+        // An IFD that names itself as the next one
+        let mut looped = file.clone();
+        looped[16 + 2 + 7 * 12 + 3] = 16;
+        let decoder = TiffDecoder::new(Cursor::new(looped)).unwrap();
+        assert!(decoder.more_images());
+        assert!(decoder.next_image().is_err());
+
+        // Counts and sizes beyond the end of the stream
+        let mut counts = file.clone();
+        counts[16 + 2 + 5 * 12 + 4..16 + 2 + 5 * 12 + 8].copy_from_slice(&[0x7f, 0xff, 0xff, 0xff]);
+        assert!(TiffDecoder::new(Cursor::new(counts)).is_err());
+        let mut size = file.clone();
+        size[16 + 2 + 8..16 + 2 + 10].copy_from_slice(&[0xff, 0xff]);
+        size[16 + 2 + 6 * 12 + 8..16 + 2 + 6 * 12 + 12].copy_from_slice(&[0x7f, 0xff, 0xff, 0xff]);
+        assert!(TiffDecoder::new(Cursor::new(size)).unwrap().read_image().is_err());
+
+        // Compression 5 (LZW) is rejected
+        file[16 + 2 + 3 * 12 + 9] = 5;
+        assert!(TiffDecoder::new(Cursor::new(file)).is_err());
+        assert!(TiffDecoder::new(Cursor::new(b"P5 not a tiff".to_vec())).is_err());
+    }
+
+    #[test]
+    fn overlapping_strips_cannot_claim_more_than_the_stream() {
+        // 64x64 u16, 64 strips of one row that all point at the same 128 bytes
+        let (data, ifd) = (8u32, 8 + 128u32);
+        let entries: [(u16, u16, u32, u32); 8] = [(256, 3, 1, 64), (257, 3, 1, 64), (258, 3, 1, 16), (259, 3, 1, 1),
+                                                  (262, 3, 1, 1), (273, 4, 64, ifd + 102), (278, 3, 1, 1),
+                                                  (279, 4, 64, ifd + 102 + 256)];
+        let mut file = vec![b'I', b'I', 42, 0, ifd as u8, 0, 0, 0];
+        file.resize(ifd as usize, 0);
+        file.extend_from_slice(&[entries.len() as u8, 0]);
+        for &(tag, kind, count, value) in &entries {
+            file.extend_from_slice(&[tag as u8, (tag >> 8) as u8, kind as u8, 0, count as u8, 0, 0, 0]);
+            file.extend_from_slice(&[value as u8, (value >> 8) as u8, 0, 0]);
+        }
+        file.extend_from_slice(&[0, 0, 0, 0]);
+        for &value in &[data, 128] {
+            for _ in 0..64 {
+                file.extend_from_slice(&[value as u8, 0, 0, 0]);
+            }
+        }
+        assert!(TiffDecoder::new(Cursor::new(file)).unwrap().read_image().is_err());
+    }
+}
@@ -4,7 +4,8 @@ use num::{ Bounded, Num, NumCast };
 use image::other::{
     PixelType,
     Pixels,
-    SubImage
+    SubImage,
+    SubImageRef
 };
 
 
@@ -79,21 +80,24 @@ pub trait Pixel: Copy + Clone {
     /// Apply the function ```f``` to each channel of this pixel.
     fn apply<F>(&mut self, f: F) where F: Fn(Self::Subpixel) -> Self::Subpixel;
 
-//    /// Apply the function ```f``` to each channel of this pixel and
-//    /// ```other``` pairwise.
-//    fn map2<F>(&self, other: &Self, f: F) -> Self
-//        where F: Fn(Self::Subpixel, Self::Subpixel) -> Self::Subpixel;
-//
-//    /// Apply the function ```f``` to each channel of this pixel and
-//    /// ```other``` pairwise. Works in-place.
-//    fn apply2<F>(&mut self, other: &Self, f: F)
-//        where F: Fn(Self::Subpixel, Self::Subpixel) -> Self::Subpixel;
+    /// Apply the function ```f``` to each channel of this pixel and
+    /// ```other``` pairwise.
+    fn map2<F>(&self, other: &Self, f: F) -> Self
+        where F: Fn(Self::Subpixel, Self::Subpixel) -> Self::Subpixel;
+
+    /// Apply the function ```f``` to each channel of this pixel and
+    /// ```other``` pairwise. Works in-place.
+    fn apply2<F>(&mut self, other: &Self, f: F)
+        where F: Fn(Self::Subpixel, Self::Subpixel) -> Self::Subpixel;
 }
 
 
 
-/// A trait for manipulating images.
-pub trait GenericImage: Sized {
+/// The read side of an image: its size and the values of its pixels.
+///
+/// Implemented by images and by views into them, including shared views
+/// that only borrow the image they look into.
+pub trait GenericImageView: Sized {
     /// The type of pixel.
     type Pixel: Pixel;
 
@@ -135,13 +139,6 @@ pub trait GenericImage: Sized {
     /// TODO: change this signature to &P
     fn get_pixel(&self, x: u32, y: u32) -> Self::Pixel;
 
-    /// Puts a pixel at location (x, y)
-    ///
-    /// # Panics
-    ///
-    /// Panics if `(x, y)` is out of bounds.
-    fn get_pixel_mut(&mut self, x: u32, y: u32) -> &mut Self::Pixel;
-
     /// Returns the pixel located at (x, y)
     ///
     /// This function can be implemented in a way that ignores bounds checking.
@@ -149,6 +146,35 @@ pub trait GenericImage: Sized {
         self.get_pixel(x, y)
     }
 
+    /// Returns an Iterator over the pixels of this image.
+    /// The iterator yields the coordinates of each pixel
+    /// along with their value
+    fn pixels(&self) -> Pixels<Self> {
+        let (width, height) = self.dimensions();
+        Pixels::new(self, 0, 0, width, height)
+    }
+
+    /// Returns a read-only view into this image.
+    /// Any number of views can be taken from the same image at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the rectangle does not fit into the image.
+    fn view<'a>(&'a self, x: u32, y: u32, width: u32, height: u32) -> SubImageRef<'a, Self> {
+        SubImageRef::new(self, x, y, width, height)
+    }
+}
+
+
+/// A trait for manipulating images.
+pub trait GenericImage: GenericImageView {
+    /// Puts a pixel at location (x, y)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `(x, y)` is out of bounds.
+    fn get_pixel_mut(&mut self, x: u32, y: u32) -> &mut Self::Pixel;
+
     /// Put a pixel at location (x, y)
     ///
     /// # Panics
@@ -163,22 +189,6 @@ pub trait GenericImage: Sized {
         self.put_pixel(x, y, pixel);
     }
 
-    /// Returns an Iterator over the pixels of this image.
-    /// The iterator yields the coordinates of each pixel
-    /// along with their value
-    fn pixels(&self) -> Pixels<Self>;
-    // {
-    //    let (width, height) = self.dimensions();
-
-    //    Pixels {
-    //        image:  self,
-    //        x:      0,
-    //        y:      0,
-    //        width:  width,
-    //        height: height,
-    //    }
-    // }
-
     /// Copies all of the pixels from another image into this image.
     ///
     /// The other image is copied with the top-left corner of the
@@ -190,7 +200,7 @@ pub trait GenericImage: Sized {
     /// `true` if the copy was successful, `false` if the image could not
     /// be copied due to size constraints.
     fn copy_from<O>(&mut self, other: &O, x: u32, y:u32) -> bool
-    where O: GenericImage<Pixel=Self::Pixel> {
+    where O: GenericImageView<Pixel=Self::Pixel> {
         // Do bounds checking here so we can use the non-bounds-checking
         // functions to copy pixels.
         if self.width() < other.width() + x {
@@ -212,9 +222,7 @@ pub trait GenericImage: Sized {
 
     /// Returns a subimage that is a view into this image.
     fn sub_image<'a>(&'a mut self, x: u32, y: u32, width: u32, height: u32)
-    -> SubImage<'a, Self>
-    where Self: 'static, <Self::Pixel as Pixel>::Subpixel: 'static,
-    Self::Pixel: 'static {
+    -> SubImage<'a, Self> {
         SubImage::new(self, x, y, width, height)
     }
 }